@@ -0,0 +1,417 @@
+use crate::ex;
+use crate::options;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use unicode_width::UnicodeWidthStr;
+
+// The line typed at the `:` and `/` prompts: its editing, its history and
+// the completion of its last word.
+
+// Entries kept in each history, like Vim's 'history'.
+const HISTORY_SIZE: usize = 50;
+
+// The text being edited and the cursor in it, counted in chars.
+#[derive(Default)]
+pub struct Line {
+    text: String,
+    cursor: usize,
+}
+
+impl Line {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            cursor: text.chars().count(),
+        }
+    }
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    pub fn before_cursor(&self) -> &str {
+        &self.text[..self.byte_index(self.cursor)]
+    }
+    // Screen columns up to the cursor.
+    pub fn cursor_width(&self) -> usize {
+        self.before_cursor().width()
+    }
+    fn byte_index(&self, cursor: usize) -> usize {
+        self.text
+            .char_indices()
+            .nth(cursor)
+            .map_or(self.text.len(), |(index, _)| index)
+    }
+    pub fn set(&mut self, text: &str) {
+        *self = Self::new(text);
+    }
+    pub fn insert(&mut self, c: char) {
+        let index = self.byte_index(self.cursor);
+        self.text.insert(index, c);
+        self.cursor = self.cursor.saturating_add(1);
+    }
+    // Replaces the text from byte `start` up to the cursor.
+    pub fn replace_before_cursor(&mut self, start: usize, replacement: &str) {
+        let end = self.byte_index(self.cursor);
+        self.text.replace_range(start..end, replacement);
+        self.cursor = self.text[..start.saturating_add(replacement.len())]
+            .chars()
+            .count();
+    }
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor = self.cursor.saturating_sub(1);
+            self.text.remove(self.byte_index(self.cursor));
+        }
+    }
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            self.text.remove(self.byte_index(self.cursor));
+        }
+    }
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+    pub fn right(&mut self) {
+        self.cursor = self.cursor.saturating_add(1).min(self.text.chars().count());
+    }
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+    pub fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+    // Ctrl-w: the spaces before the cursor and the word or run of
+    // punctuation before them.
+    pub fn delete_word(&mut self) {
+        let before: Vec<char> = self.before_cursor().chars().collect();
+        let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+        let spaces = before
+            .iter()
+            .rev()
+            .take_while(|c| c.is_whitespace())
+            .count();
+        let rest = &before[..before.len().saturating_sub(spaces)];
+        let word = match rest.last() {
+            Some(last) if is_word(last) => rest.iter().rev().take_while(|c| is_word(c)).count(),
+            Some(_) => rest
+                .iter()
+                .rev()
+                .take_while(|c| !is_word(c) && !c.is_whitespace())
+                .count(),
+            None => 0,
+        };
+        let start = self.cursor.saturating_sub(spaces.saturating_add(word));
+        let (from, to) = (self.byte_index(start), self.byte_index(self.cursor));
+        self.text.replace_range(from..to, "");
+        self.cursor = start;
+    }
+    // Ctrl-u: everything before the cursor.
+    pub fn delete_to_start(&mut self) {
+        let end = self.byte_index(self.cursor);
+        self.text.replace_range(..end, "");
+        self.cursor = 0;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Command,
+    Search,
+    // Files that were opened, for the picker of recent files.
+    File,
+}
+
+impl Kind {
+    fn prefix(self) -> char {
+        match self {
+            Self::Command => ':',
+            Self::Search => '/',
+            Self::File => '>',
+        }
+    }
+}
+
+// The histories, oldest entry first. They are kept in one file with each
+// line starting with the prompt it belongs to, or `>` for files.
+#[derive(Default)]
+pub struct History {
+    commands: Vec<String>,
+    searches: Vec<String>,
+    files: Vec<String>,
+}
+
+impl History {
+    pub fn file() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".phantom_history"))
+    }
+    pub fn load(file: &Path) -> Self {
+        let mut history = Self::default();
+        for line in fs::read_to_string(file).unwrap_or_default().lines() {
+            for kind in [Kind::Command, Kind::Search, Kind::File] {
+                if let Some(entry) = line.strip_prefix(kind.prefix()) {
+                    history.add(kind, entry);
+                }
+            }
+        }
+        history
+    }
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        for kind in [Kind::Command, Kind::Search, Kind::File] {
+            for entry in self.entries(kind) {
+                contents.push(kind.prefix());
+                contents.push_str(entry);
+                contents.push('\n');
+            }
+        }
+        fs::write(file, contents)
+    }
+    pub fn entries(&self, kind: Kind) -> &Vec<String> {
+        match kind {
+            Kind::Command => &self.commands,
+            Kind::Search => &self.searches,
+            Kind::File => &self.files,
+        }
+    }
+    // An entry used again moves to the end instead of being kept twice.
+    pub fn add(&mut self, kind: Kind, entry: &str) {
+        let entries = match kind {
+            Kind::Command => &mut self.commands,
+            Kind::Search => &mut self.searches,
+            Kind::File => &mut self.files,
+        };
+        entries.retain(|old| old != entry);
+        entries.push(entry.to_string());
+        if entries.len() > HISTORY_SIZE {
+            entries.remove(0);
+        }
+    }
+    // The closest entry before `index` (or the newest one when None) that
+    // starts with `prefix`.
+    pub fn older(&self, kind: Kind, index: Option<usize>, prefix: &str) -> Option<usize> {
+        let entries = self.entries(kind);
+        let end = index.unwrap_or(entries.len()).min(entries.len());
+        entries[..end]
+            .iter()
+            .rposition(|entry| entry.starts_with(prefix))
+    }
+    // The closest entry after `index` that starts with `prefix`.
+    pub fn newer(&self, kind: Kind, index: usize, prefix: &str) -> Option<usize> {
+        let start = index.saturating_add(1);
+        self.entries(kind)
+            .iter()
+            .skip(start)
+            .position(|entry| entry.starts_with(prefix))
+            .map(|position| position.saturating_add(start))
+    }
+}
+
+// Completions for the end of a command line: the byte where the completed
+// word starts and what it can be replaced with.
+pub fn complete(line: &str, buffers: &[String]) -> Option<(usize, Vec<String>)> {
+    let name_start = ex::name_start(line)?;
+    let rest = &line[name_start..];
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, after_name) = rest.split_at(name_len);
+    if after_name.is_empty() {
+        let names = ex::COMMANDS
+            .iter()
+            .map(|(full, _, _)| *full)
+            .filter(|full| full.starts_with(name))
+            .map(str::to_string)
+            .collect();
+        return Some((name_start, names));
+    }
+    let argument = after_name.strip_prefix('!').unwrap_or(after_name);
+    if !argument.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let word_start = line
+        .rfind(char::is_whitespace)
+        .map_or(0, |index| index.saturating_add(1));
+    let word = &line[word_start..];
+    let candidates = match ex::full_name(name)? {
+        "edit" | "read" | "saveas" | "write" | "wq" | "xit" => files(word, false),
+        "cd" | "chdir" | "lcd" | "lchdir" => files(word, true),
+        "buffer" => buffers
+            .iter()
+            .filter(|buffer| buffer.contains(word))
+            .cloned()
+            .collect(),
+        "set" => set_options(word),
+        "help" => ex::COMMANDS
+            .iter()
+            .map(|(full, _, _)| *full)
+            .filter(|full| full.starts_with(word))
+            .map(str::to_string)
+            .collect(),
+        _ => return None,
+    };
+    Some((word_start, candidates))
+}
+
+fn set_options(word: &str) -> Vec<String> {
+    let mut candidates = Vec::new();
+    for prefix in ["", "no", "inv"] {
+        if let Some(name) = word.strip_prefix(prefix) {
+            candidates.extend(
+                options::NAMES
+                    .iter()
+                    .filter(|(full, _)| full.starts_with(name))
+                    .map(|(full, _)| format!("{}{}", prefix, full)),
+            );
+        }
+    }
+    candidates
+}
+
+// Files and directories starting with `word`, directories ending in `/`.
+// Hidden ones are only offered once `word` asks for them.
+fn files(word: &str, directories_only: bool) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(index) => word.split_at(index.saturating_add(1)),
+        None => ("", word),
+    };
+    let entries = match fs::read_dir(if directory.is_empty() { "." } else { directory }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let is_directory = entry.path().is_dir();
+            if !name.starts_with(prefix)
+                || (name.starts_with('.') && !prefix.starts_with('.'))
+                || (directories_only && !is_directory)
+            {
+                return None;
+            }
+            let slash = if is_directory { "/" } else { "" };
+            Some(format!("{}{}{}", directory, name, slash))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+// The completions Tab cycles through, shown in place of the status bar.
+pub struct Wildmenu {
+    pub candidates: Vec<String>,
+    pub selected: usize,
+    // Byte where the current completion starts in the line.
+    pub start: usize,
+}
+
+impl Wildmenu {
+    // The candidates that fit into `width` columns, starting far enough
+    // along to show the selected one, which is reversed.
+    pub fn render(&self, width: usize) -> String {
+        let mut first = self.selected;
+        let mut used = self
+            .candidates
+            .get(first)
+            .map_or(0, |candidate| candidate.width());
+        while first > 0 {
+            let previous = self
+                .candidates
+                .get(first.saturating_sub(1))
+                .map_or(0, |candidate| candidate.width().saturating_add(2));
+            if used.saturating_add(previous) > width {
+                break;
+            }
+            used = used.saturating_add(previous);
+            first = first.saturating_sub(1);
+        }
+        let mut result = String::new();
+        let mut used: usize = 0;
+        for (index, candidate) in self.candidates.iter().enumerate().skip(first) {
+            let needed = candidate
+                .width()
+                .saturating_add(if used == 0 { 0 } else { 2 });
+            if used.saturating_add(needed) > width {
+                break;
+            }
+            if used > 0 {
+                result.push_str("  ");
+            }
+            if index == self.selected {
+                result.push_str(&format!(
+                    "{}{}{}",
+                    termion::style::Invert,
+                    candidate,
+                    termion::style::NoInvert
+                ));
+            } else {
+                result.push_str(candidate);
+            }
+            used = used.saturating_add(needed);
+        }
+        result.push_str(&" ".repeat(width.saturating_sub(used)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let mut line = Line::new("s/foo bar");
+        line.left();
+        line.left();
+        line.insert('x');
+        assert_eq!(
+            (line.text(), line.before_cursor()),
+            ("s/foo bxar", "s/foo bx")
+        );
+        line.delete_word();
+        assert_eq!(line.text(), "s/foo ar");
+        line.delete_word();
+        assert_eq!(line.text(), "s/ar");
+        line.delete_word();
+        assert_eq!(line.text(), "sar");
+        line.end();
+        line.backspace();
+        line.home();
+        line.delete();
+        assert_eq!(line.text(), "a");
+        line.right();
+        line.delete_to_start();
+        assert_eq!(line.text(), "");
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        for entry in ["s/a/b/", "w", "set ts=4", "w"] {
+            history.add(Kind::Command, entry);
+        }
+        assert_eq!(history.entries(Kind::Command), &["s/a/b/", "set ts=4", "w"]);
+        assert!(history.entries(Kind::Search).is_empty());
+        assert_eq!(history.older(Kind::Command, None, ""), Some(2));
+        assert_eq!(history.older(Kind::Command, None, "s"), Some(1));
+        assert_eq!(history.older(Kind::Command, Some(1), "s"), Some(0));
+        assert_eq!(history.older(Kind::Command, Some(0), "s"), None);
+        assert_eq!(history.newer(Kind::Command, 0, "s"), Some(1));
+        assert_eq!(history.newer(Kind::Command, 1, "s"), None);
+    }
+
+    #[test]
+    fn test_complete() {
+        let buffers = ["src/main.rs".to_string(), "README.md".to_string()];
+        let names = |line| complete(line, &buffers);
+        assert_eq!(names("'<,'>nor"), Some((5, vec!["normal".to_string()])));
+        assert_eq!(
+            names("se ts=4 noex"),
+            Some((8, vec!["noexpandtab".to_string()]))
+        );
+        assert_eq!(names("b main"), Some((2, vec!["src/main.rs".to_string()])));
+        assert_eq!(names("h subs"), Some((2, vec!["substitute".to_string()])));
+        assert_eq!(names("d x"), None);
+        assert_eq!(names("w!"), None);
+    }
+}
@@ -1,9 +1,11 @@
 use crate::FileType;
+use crate::IsKeyword;
 use crate::Position;
 use crate::Row;
 use crate::SearchDirection;
+use regex::Regex;
 use std::fs;
-use std::io::{Error, Write};
+use std::io::{BufRead, BufReader, Error, Write};
 
 #[derive(Clone)]
 struct DocumentState {
@@ -16,8 +18,16 @@ pub struct Document {
     pub file_name: Option<String>,
     dirty: bool,
     file_type: FileType,
+    // The file name when `:set filetype=` was used, which saving the file
+    // then keeps until it is saved under another name.
+    file_type_set_for: Option<String>,
+    // Set by `:set iskeyword=`, over the filetype's until that changes.
+    iskeyword: Option<IsKeyword>,
     undo_stack: Vec<DocumentState>,
     redo_stack: Vec<DocumentState>,
+    // Nesting depth of undo groups; while above zero, changes are not
+    // recorded individually.
+    undo_group: usize,
 }
 
 impl Default for Document {
@@ -27,8 +37,11 @@ impl Default for Document {
             file_name: None,
             dirty: false,
             file_type: FileType::default(),
+            file_type_set_for: None,
+            iskeyword: None,
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            undo_group: 0,
         }
     }
 }
@@ -36,7 +49,8 @@ impl Default for Document {
 impl Document {
     pub fn open(filename: &str) -> Result<Self, std::io::Error> {
         let contents = fs::read_to_string(filename)?;
-        let file_type = FileType::from(filename);
+        let lines: Vec<&str> = contents.lines().collect();
+        let file_type = FileType::detect(filename, &lines);
         let mut rows = Vec::new();
         for value in contents.lines() {
             rows.push(Row::from(value));
@@ -46,16 +60,59 @@ impl Document {
             file_name: Some(filename.to_string()),
             dirty: false,
             file_type,
+            file_type_set_for: None,
+            iskeyword: None,
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            undo_group: 0,
         })
     }
+    // The first `count` lines of a file, for a preview that should not wait
+    // for all of a big one.
+    pub fn open_head(filename: &str, count: usize) -> Result<Self, std::io::Error> {
+        let lines: Vec<String> = BufReader::new(fs::File::open(filename)?)
+            .lines()
+            .take(count)
+            .collect::<Result<_, _>>()?;
+        let text: Vec<&str> = lines.iter().map(String::as_str).collect();
+        Ok(Self {
+            file_name: Some(filename.to_string()),
+            file_type: FileType::detect(filename, &text),
+            ..Self::scratch(&lines)
+        })
+    }
+    // A document not read from a file, like the quickfix list.
+    pub fn scratch(lines: &[String]) -> Self {
+        Self {
+            rows: lines.iter().map(|line| Row::from(&line[..])).collect(),
+            ..Self::default()
+        }
+    }
     pub fn lines(&self) -> Vec<String> {
         self.rows.iter().map(|r| r.to_string()).collect()
     }
     pub fn file_type(&self) -> String {
         self.file_type.name()
     }
+    pub fn file_type_id(&self) -> &str {
+        self.file_type.id()
+    }
+    pub fn set_file_type(&mut self, id: &str) -> Result<(), String> {
+        self.file_type = FileType::named(id).ok_or_else(|| format!("Unknown filetype: {}", id))?;
+        self.file_type_set_for = self.file_name.clone();
+        self.iskeyword = None;
+        self.unhighlight_rows(0);
+        Ok(())
+    }
+    pub fn iskeyword(&self) -> &IsKeyword {
+        self.iskeyword
+            .as_ref()
+            .unwrap_or_else(|| self.file_type.iskeyword())
+    }
+    pub fn set_iskeyword(&mut self, spec: &str) -> Result<(), String> {
+        self.iskeyword = Some(IsKeyword::parse(spec)?);
+        Ok(())
+    }
     pub fn row(&self, index: usize) -> Option<&Row> {
         self.rows.get(index)
     }
@@ -77,6 +134,76 @@ impl Document {
         Some(removed.to_string())
     }
     
+    // Whole line versions of the edits below, each recorded as one undo
+    // step.
+    pub fn delete_lines(&mut self, first: usize, last: usize) -> Vec<String> {
+        if first >= self.rows.len() || first > last {
+            return Vec::new();
+        }
+        self.save_state(&Position { x: 0, y: first });
+        self.dirty = true;
+        let last = last.min(self.rows.len().saturating_sub(1));
+        let removed = self
+            .rows
+            .drain(first..=last)
+            .map(|row| row.to_string())
+            .collect();
+        self.unhighlight_rows(first.saturating_sub(1));
+        removed
+    }
+    pub fn insert_lines(&mut self, at: usize, lines: &[String]) {
+        if at > self.rows.len() || lines.is_empty() {
+            return;
+        }
+        self.save_state(&Position { x: 0, y: at });
+        self.dirty = true;
+        self.rows
+            .splice(at..at, lines.iter().map(|line| Row::from(&line[..])));
+        self.unhighlight_rows(at.saturating_sub(1));
+    }
+    pub fn replace_lines(&mut self, first: usize, last: usize, lines: &[String]) {
+        if first >= self.rows.len() || first > last {
+            return;
+        }
+        self.save_state(&Position { x: 0, y: first });
+        self.dirty = true;
+        let last = last.min(self.rows.len().saturating_sub(1));
+        self.rows
+            .splice(first..=last, lines.iter().map(|line| Row::from(&line[..])));
+        self.unhighlight_rows(first.saturating_sub(1));
+    }
+    // `:g` marks the matching lines before running its command on them, so
+    // the marks move along with the lines that command inserts or deletes.
+    // Marks left from before, say by an undo, are cleared first.
+    pub fn mark_lines(&mut self, lines: &[usize]) {
+        self.unmark_lines();
+        for y in lines {
+            if let Some(row) = self.rows.get_mut(*y) {
+                row.is_marked = true;
+            }
+        }
+    }
+    // Unmarks and returns the first marked line at or after `from`, or
+    // failing that before it.
+    #[allow(clippy::indexing_slicing)]
+    pub fn take_marked_line(&mut self, from: usize) -> Option<usize> {
+        let from = from.min(self.rows.len());
+        let y = self.rows[from..]
+            .iter()
+            .position(|row| row.is_marked)
+            .map(|index| index.saturating_add(from))
+            .or_else(|| self.rows[..from].iter().position(|row| row.is_marked))?;
+        if let Some(row) = self.rows.get_mut(y) {
+            row.is_marked = false;
+        }
+        Some(y)
+    }
+    pub fn unmark_lines(&mut self) {
+        for row in &mut self.rows {
+            row.is_marked = false;
+        }
+    }
+
     pub fn get_line(&self, y: usize) -> Option<String> {
         self.rows.get(y).map(|r| r.to_string())
     }
@@ -120,6 +247,9 @@ impl Document {
         self.rows.insert(at.y + 1, new_row);
     }
     fn save_state(&mut self, cursor_position: &Position) {
+        if self.undo_group > 0 {
+            return;
+        }
         let state = DocumentState {
             rows: self.rows.clone(),
             cursor_position: cursor_position.clone(),
@@ -133,6 +263,125 @@ impl Document {
         }
     }
     
+    // Everything changed until the matching end_undo_group() is undone in
+    // one step.
+    pub fn begin_undo_group(&mut self, cursor_position: &Position) {
+        self.save_state(cursor_position);
+        self.undo_group = self.undo_group.saturating_add(1);
+    }
+    pub fn end_undo_group(&mut self) {
+        self.undo_group = self.undo_group.saturating_sub(1);
+    }
+    // The text from `start` up to, but not including, `end`, one string per
+    // line.
+    pub fn text(&self, start: &Position, end: &Position) -> Vec<String> {
+        let mut lines = Vec::new();
+        for y in start.y..=end.y {
+            if let Some(row) = self.rows.get(y) {
+                let from = if y == start.y { start.x } else { 0 };
+                let to = if y == end.y { end.x } else { row.len() };
+                lines.push(row.slice(from, to));
+            }
+        }
+        lines
+    }
+    #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+    pub fn delete_text(&mut self, start: &Position, end: &Position) -> Vec<String> {
+        if start.y >= self.rows.len() {
+            return Vec::new();
+        }
+        let end = if end.y >= self.rows.len() {
+            let y = self.rows.len() - 1;
+            Position {
+                x: self.rows[y].len(),
+                y,
+            }
+        } else {
+            end.clone()
+        };
+        let deleted = self.text(start, &end);
+        self.save_state(start);
+        self.dirty = true;
+        let tail = self.rows[end.y].split(end.x);
+        self.rows.drain(start.y.saturating_add(1)..=end.y);
+        let row = &mut self.rows[start.y];
+        row.split(start.x);
+        row.append(&tail);
+        self.unhighlight_rows(start.y);
+        deleted
+    }
+    // Inserts possibly multi-line text and returns the position right
+    // after it.
+    #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+    pub fn insert_text(&mut self, at: &Position, lines: &[String]) -> Position {
+        if at.y > self.rows.len() || lines.is_empty() {
+            return at.clone();
+        }
+        self.save_state(at);
+        self.dirty = true;
+        if at.y == self.rows.len() {
+            self.rows.push(Row::default());
+        }
+        let tail = self.rows[at.y].split(at.x);
+        let mut end = at.clone();
+        for (index, line) in lines.iter().enumerate() {
+            let new = Row::from(&line[..]);
+            if index == 0 {
+                end.x = at.x + new.len();
+                self.rows[at.y].append(&new);
+            } else {
+                end = Position {
+                    x: new.len(),
+                    y: at.y + index,
+                };
+                self.rows.insert(end.y, new);
+            }
+        }
+        self.rows[end.y].append(&tail);
+        self.unhighlight_rows(at.y);
+        end
+    }
+    // Replaces the text from `start` to `end` (exclusive) with what `f`
+    // makes of it, line by line.
+    #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+    pub fn map_text<F>(&mut self, start: &Position, end: &Position, f: F)
+    where
+        F: Fn(&str) -> String,
+    {
+        if start.y >= self.rows.len() {
+            return;
+        }
+        self.save_state(start);
+        self.dirty = true;
+        let last = end.y.min(self.rows.len() - 1);
+        for y in start.y..=last {
+            let row = &mut self.rows[y];
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            let mut middle = row.split(from);
+            let tail = middle.split(to.saturating_sub(from));
+            row.append(&Row::from(&f(&middle.to_string())[..]));
+            row.append(&tail);
+        }
+        self.unhighlight_rows(start.y);
+    }
+    // Overwrites the grapheme at `at` and returns the one it replaced, or
+    // None when `at` is past the end of the line.
+    pub fn replace(&mut self, at: &Position, with: &str) -> Option<String> {
+        let len = self.rows.get(at.y)?.len();
+        if at.x >= len {
+            return None;
+        }
+        self.save_state(at);
+        self.dirty = true;
+        let row = self.rows.get_mut(at.y)?;
+        let mut rest = row.split(at.x);
+        let after = rest.split(1);
+        row.append(&Row::from(with));
+        row.append(&after);
+        self.unhighlight_rows(at.y);
+        Some(rest.to_string())
+    }
     pub fn insert(&mut self, at: &Position, c: char) {
         if at.y > self.rows.len() {
             return;
@@ -212,7 +461,17 @@ impl Document {
     pub fn save(&mut self) -> Result<(), Error> {
         if let Some(file_name) = &self.file_name {
             let mut file = fs::File::create(file_name)?;
-            self.file_type = FileType::from(file_name);
+            if self.file_type_set_for.as_ref() != Some(file_name) {
+                let lines = self.lines();
+                let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+                let file_type = FileType::detect(file_name, &lines);
+                if file_type.id() != self.file_type.id() {
+                    self.file_type = file_type;
+                    self.iskeyword = None;
+                    self.unhighlight_rows(0);
+                }
+                self.file_type_set_for = None;
+            }
             for row in &mut self.rows {
                 file.write_all(row.as_bytes())?;
                 file.write_all(b"\n")?;
@@ -221,11 +480,44 @@ impl Document {
         }
         Ok(())
     }
+    // Writes the lines `first..=last` to another file, or appends them to
+    // it, without changing which file the document belongs to.
+    pub fn write_to(
+        &self,
+        file_name: &str,
+        first: usize,
+        last: usize,
+        append: bool,
+    ) -> Result<usize, Error> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(file_name)?;
+        let mut written: usize = 0;
+        for row in self
+            .rows
+            .iter()
+            .skip(first)
+            .take(last.saturating_sub(first).saturating_add(1))
+        {
+            file.write_all(row.as_bytes())?;
+            file.write_all(b"\n")?;
+            written = written.saturating_add(1);
+        }
+        Ok(written)
+    }
     pub fn is_dirty(&self) -> bool {
         self.dirty
     }
     #[allow(clippy::indexing_slicing)]
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
+    pub fn find(
+        &self,
+        regex: &Regex,
+        at: &Position,
+        direction: SearchDirection,
+    ) -> Option<Position> {
         if at.y >= self.rows.len() {
             return None;
         }
@@ -243,7 +535,7 @@ impl Document {
         };
         for _ in start..end {
             if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(&query, position.x, direction) {
+                if let Some(x) = row.find(regex, position.x, direction) {
                     position.x = x;
                     return Some(position);
                 }
@@ -260,8 +552,28 @@ impl Document {
         }
         None
     }
-    pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
-        let mut start_with_comment = false;
+    // Which of the matches of `regex` starts at `at`, counting from 1, and
+    // how many there are, counting no further than `limit`.
+    pub fn count_matches(&self, regex: &Regex, at: &Position, limit: usize) -> (usize, usize) {
+        let mut current = 0;
+        let mut total: usize = 0;
+        for (y, row) in self.rows.iter().enumerate() {
+            for (x, _) in row.matches(regex) {
+                total = total.saturating_add(1);
+                if (y, x) <= (at.y, at.x) {
+                    current = total;
+                }
+                if total >= limit {
+                    return (current, total);
+                }
+            }
+        }
+        (current, total)
+    }
+    // Search matches are only looked for from row `start` on, as the rows
+    // above it are not on screen.
+    pub fn highlight(&mut self, pattern: Option<&Regex>, start: usize, until: Option<usize>) {
+        let mut open_comment = None;
         let until = if let Some(until) = until {
             if until.saturating_add(1) < self.rows.len() {
                 until.saturating_add(1)
@@ -272,12 +584,52 @@ impl Document {
             self.rows.len()
         };
         #[allow(clippy::indexing_slicing)]
-        for row in &mut self.rows[..until] {
-            start_with_comment = row.highlight(
+        for (y, row) in self.rows[..until].iter_mut().enumerate() {
+            open_comment = row.highlight(
                 &self.file_type.highlighting_options(),
-                word,
-                start_with_comment,
+                pattern.filter(|_| y >= start),
+                open_comment,
             );
         }
     }
 }
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    // What `:g/<c>/d` does to the document.
+    fn delete_matching(document: &mut Document, c: char) {
+        let lines: Vec<usize> = document
+            .lines()
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.contains(c))
+            .map(|(y, _)| y)
+            .collect();
+        document.begin_undo_group(&Position::default());
+        document.mark_lines(&lines);
+        let mut y = 0;
+        while let Some(marked) = document.take_marked_line(y) {
+            y = marked;
+            document.delete_line(&Position { x: 0, y });
+        }
+        document.unmark_lines();
+        document.end_undo_group();
+    }
+
+    #[test]
+    fn test_global_undo() {
+        let mut document = Document::default();
+        for (y, line) in ["x1", "y1", "x2", "y2", "z"].iter().enumerate() {
+            document.insert_line(&Position { x: 0, y }, line);
+        }
+        delete_matching(&mut document, 'x');
+        assert_eq!(document.lines(), ["y1", "y2", "z"]);
+        assert!(document.undo().is_some());
+        assert_eq!(document.lines(), ["x1", "y1", "x2", "y2", "z"]);
+        assert!(document.rows.iter().all(|row| !row.is_marked));
+        delete_matching(&mut document, 'y');
+        assert_eq!(document.lines(), ["x1", "x2", "z"]);
+    }
+}
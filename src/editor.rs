@@ -1,17 +1,45 @@
-use crate::movement::Movement;
+use crate::cmdline::{self, History, Kind, Line, Wildmenu};
+use crate::errorformat;
+use crate::ex;
+use crate::explorer;
+use crate::files;
+use crate::filetype;
+use crate::highlighting;
+use crate::iskeyword::CharClass;
+use crate::movement::{Context, Movement};
+use crate::normal::{self, Action, Command, Operator, Parsed, Target};
+use crate::options::Options;
+use crate::picker::{self, Item, Picker, Source};
+use crate::quickfix::{self, Quickfix};
+use crate::registers::{Register, Registers};
+use crate::search::{self, Settings};
+use crate::shell;
+use crate::sort::Sort;
+use crate::substitute::{self, Substitute};
 use crate::Document;
 use crate::Row;
 use crate::Terminal;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::time::Instant;
 use termion::color;
 use termion::event::Key;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
 const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const QUIT_TIMES: u8 = 3;
+const ERROR_COLOR: color::Rgb = color::Rgb(220, 50, 47);
+const WARNING_COLOR: color::Rgb = color::Rgb(181, 137, 0);
+const NOTE_COLOR: color::Rgb = color::Rgb(38, 139, 210);
+// Matches counted for the `[3/17]` in the status bar.
+const SEARCH_COUNT_LIMIT: usize = 1000;
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -23,14 +51,48 @@ pub enum SearchDirection {
 pub enum Mode {
     Normal,
     Insert,
+    Replace,
+    Visual,
+    VisualLine,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
 }
 
+// The screen column vertical motions try to return to, like Vim's
+// 'curswant'. `End` is set by `$` and sticks to the end of every line.
+#[derive(Clone, Copy)]
+enum DesiredColumn {
+    Column(usize),
+    End,
+}
+
+// A key Insert mode waits for after Ctrl-r or Ctrl-v.
+#[derive(Clone, Copy)]
+enum InsertPending {
+    Register,
+    Literal,
+}
+
+// What Replace mode did at each step, so that Backspace can undo it.
+enum Replaced {
+    Original(String),
+    Inserted,
+    LineBreak,
+}
+
+// A file kept open in the background, with where its cursor was left.
+struct Buffer {
+    number: usize,
+    document: Document,
+    cursor_position: Position,
+    offset: Position,
+    marks: HashMap<char, Position>,
+}
+
 struct StatusMessage {
     text: String,
     time: Instant,
@@ -52,10 +114,59 @@ pub struct Editor {
     document: Document,
     status_message: StatusMessage,
     quit_times: u8,
-    highlighted_word: Option<String>,
+    highlighted_pattern: Option<Regex>,
     mode: Mode,
     pending_keys: String,
-    clipboard: Vec<String>,
+    registers: Registers,
+    insert_pending: Option<InsertPending>,
+    // Set by Ctrl-o in Insert mode: go back to Insert mode after one
+    // Normal mode command.
+    insert_after_command: bool,
+    replaced: Vec<Replaced>,
+    options: Options,
+    // Only valid while the cursor is still at the position stored with it.
+    desired_column: Option<(DesiredColumn, Position)>,
+    marks: HashMap<char, Position>,
+    // The end of the Visual mode selection that does not move.
+    visual_start: Position,
+    last_search: Option<String>,
+    search_direction: SearchDirection,
+    // Off after `*` and `#`, whose pattern was not typed, like in Vim.
+    search_smart_case: bool,
+    // Set by `:nohlsearch` until the next search.
+    no_hlsearch: bool,
+    // The last search pattern as 'hlsearch' shows it, compiled with the
+    // settings stored along, so redrawing does not compile it every time.
+    hlsearch_pattern: Option<(String, Settings, Regex)>,
+    // The match the cursor was put on, its number and the number of all.
+    search_count: Option<(Position, usize, usize)>,
+    last_substitute: Option<Substitute>,
+    // The match `:s///c` asks about: its line and graphemes.
+    substitute_match: Option<(usize, usize, usize)>,
+    in_global: bool,
+    // Keys given to `:normal`, read before the terminal. While they last,
+    // running out of them ends whatever command is still incomplete.
+    queued_keys: VecDeque<Key>,
+    executing_keys: bool,
+    last_shell_command: Option<String>,
+    // The buffers not shown, ordered by number, and the number of the one
+    // that is.
+    hidden_buffers: Vec<Buffer>,
+    buffer_number: usize,
+    next_buffer_number: usize,
+    previous_directory: Option<PathBuf>,
+    history: History,
+    // Where the terminal cursor goes while a prompt is read, and the
+    // completions shown over the status bar.
+    prompt_cursor: Option<usize>,
+    wildmenu: Option<Wildmenu>,
+    quickfix: Quickfix,
+    // The buffer `:copen` shows the quickfix list in, once it was opened.
+    quickfix_buffer: Option<usize>,
+    // Shown instead of the text while `:Files` and the like are open.
+    picker: Option<Picker>,
+    // The directories explorer buffers list, by buffer number.
+    explorers: HashMap<usize, PathBuf>,
 }
 
 impl Editor {
@@ -64,378 +175,2647 @@ impl Editor {
             if let Err(error) = self.refresh_screen() {
                 die(error);
             }
-            if self.should_quit {
-                break;
+            if self.should_quit {
+                break;
+            }
+            if let Err(error) = self.process_keypress() {
+                die(error);
+            }
+        }
+    }
+    pub fn default() -> Self {
+        let args: Vec<String> = env::args().collect();
+        let mut initial_status =
+            String::from("HELP: i=insert | :w=save | :q=quit | /=search | :help for more");
+        if let Some(error) = filetype::syntaxes().errors.first() {
+            initial_status = format!("ERR: {}", error);
+        }
+
+        let document = if let Some(file_name) = args.get(1) {
+            let doc = Document::open(file_name);
+            if let Ok(doc) = doc {
+                doc
+            } else if Path::new(file_name).is_dir() {
+                Document::default()
+            } else {
+                initial_status = format!("ERR: Could not open file: {}", file_name);
+                Document::default()
+            }
+        } else {
+            Document::default()
+        };
+
+        let mut editor = Self {
+            should_quit: false,
+            terminal: Terminal::default().expect("Failed to initialize terminal"),
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+            status_message: StatusMessage::from(initial_status),
+            quit_times: QUIT_TIMES,
+            highlighted_pattern: None,
+            mode: Mode::Normal,
+            pending_keys: String::new(),
+            registers: Registers::default(),
+            insert_pending: None,
+            insert_after_command: false,
+            replaced: Vec::new(),
+            options: Options::default(),
+            desired_column: None,
+            marks: HashMap::new(),
+            visual_start: Position::default(),
+            last_search: None,
+            search_direction: SearchDirection::Forward,
+            search_smart_case: true,
+            no_hlsearch: false,
+            hlsearch_pattern: None,
+            search_count: None,
+            last_substitute: None,
+            substitute_match: None,
+            in_global: false,
+            queued_keys: VecDeque::new(),
+            executing_keys: false,
+            last_shell_command: None,
+            hidden_buffers: Vec::new(),
+            buffer_number: 1,
+            next_buffer_number: 2,
+            previous_directory: None,
+            history: History::file().map_or_else(History::default, |file| History::load(&file)),
+            prompt_cursor: None,
+            wildmenu: None,
+            quickfix: Quickfix::default(),
+            quickfix_buffer: None,
+            picker: None,
+            explorers: HashMap::new(),
+        };
+        if let Some(file_name) = editor.document.file_name.clone() {
+            editor.remember_file(&file_name);
+        }
+        if let Some(directory) = args.get(1).filter(|name| Path::new(name).is_dir()) {
+            if let Err(message) = editor.explore(Path::new(directory), None) {
+                editor.status_message = StatusMessage::from(message);
+            }
+        }
+        editor
+    }
+
+    fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
+        Terminal::cursor_hide();
+        Terminal::cursor_position(&Position::default());
+        if self.should_quit {
+            Terminal::clear_screen();
+            println!("Goodbye.\r");
+        } else {
+            match self.mode {
+                Mode::Normal | Mode::Visual | Mode::VisualLine => print!("\x1b[2 q"),
+                Mode::Insert => print!("\x1b[5 q"),
+                Mode::Replace => print!("\x1b[4 q"),
+            }
+            // The pattern being typed, or else the last one with 'hlsearch'.
+            let show_hlsearch = self.options.hlsearch && !self.no_hlsearch;
+            let hlsearch = if self.highlighted_pattern.is_none() && show_hlsearch {
+                self.update_hlsearch_pattern();
+                self.hlsearch_pattern.as_ref().map(|(_, _, regex)| regex)
+            } else {
+                None
+            };
+            self.document.highlight(
+                self.highlighted_pattern.as_ref().or(hlsearch),
+                self.offset.y,
+                Some(
+                    self.offset
+                        .y
+                        .saturating_add(self.terminal.size().height as usize),
+                ),
+            );
+            match &self.picker {
+                Some(picker) => self.draw_picker(picker),
+                None => self.draw_rows(),
+            }
+            self.draw_status_bar();
+            self.draw_message_bar();
+            let line_num_width = 5; // 4 digits + 1 space
+            if let Some(column) = self.prompt_cursor {
+                Terminal::cursor_position(&Position {
+                    x: column,
+                    y: (self.terminal.size().height as usize).saturating_add(1),
+                });
+                Terminal::cursor_show();
+                return Terminal::flush();
+            }
+            let column = self.document.row(self.cursor_position.y).map_or(0, |row| {
+                row.display_column(self.cursor_position.x, self.options.tabstop)
+            });
+            Terminal::cursor_position(&Position {
+                x: column
+                    .saturating_sub(self.offset.x)
+                    .saturating_add(line_num_width),
+                y: self.cursor_position.y.saturating_sub(self.offset.y),
+            });
+        }
+        Terminal::cursor_show();
+        Terminal::flush()
+    }
+    fn save(&mut self) -> Result<(), String> {
+        if self.document.file_name.is_none() {
+            let new_name = self
+                .prompt("Save as: ", "", None, |_, _, _| {})
+                .unwrap_or(None);
+            return match new_name {
+                Some(new_name) => self.save_as(&new_name),
+                None => Err("Save aborted.".to_string()),
+            };
+        }
+
+        self.document
+            .save()
+            .map_err(|error| format!("Error writing file: {}", error))?;
+        self.status_message = StatusMessage::from("File saved successfully.".to_string());
+        Ok(())
+    }
+    // Saves the document as `file_name`, keeping its old name when that
+    // fails.
+    fn save_as(&mut self, file_name: &str) -> Result<(), String> {
+        let old_name = self.document.file_name.replace(file_name.to_string());
+        if let Err(error) = self.save() {
+            self.document.file_name = old_name;
+            return Err(error);
+        }
+        Ok(())
+    }
+    // `:w [>>] [file]`: without a file name this saves the document, with
+    // one it writes the range (the whole document by default) there.
+    fn write(
+        &mut self,
+        range: Option<(usize, usize)>,
+        argument: &str,
+        force: bool,
+    ) -> Result<(), String> {
+        let (append, file_name) = match argument.strip_prefix(">>") {
+            Some(rest) => (true, rest.trim()),
+            None => (false, argument),
+        };
+        let own_file = self.document.file_name.as_deref();
+        let is_own_file = |name: &str| own_file.map_or(false, |own| files::same_file(own, name));
+        if file_name.is_empty() || is_own_file(file_name) {
+            if range.is_none() && !append {
+                return self.save();
+            }
+            if range.is_some() && !append && !force {
+                return Err("Use ! to write partial buffer".to_string());
+            }
+        } else if own_file.is_none() && range.is_none() && !append {
+            // Like Vim, writing an unnamed document names it.
+            return self.save_as(file_name);
+        }
+        let file_name = match file_name {
+            "" => own_file.ok_or_else(|| "No file name".to_string())?,
+            file_name => file_name,
+        }
+        .to_string();
+        if !append && !force && !is_own_file(&file_name) && Path::new(&file_name).exists() {
+            return Err("File exists (add ! to override)".to_string());
+        }
+        let (first, last) = range.unwrap_or((0, self.document.len().saturating_sub(1)));
+        let written = self
+            .document
+            .write_to(&file_name, first, last, append)
+            .map_err(|error| format!("Can't write {}: {}", file_name, error))?;
+        self.status_message = StatusMessage::from(format!(
+            "\"{}\" {} lines {}",
+            file_name,
+            written,
+            if append { "appended" } else { "written" }
+        ));
+        Ok(())
+    }
+    // Writes every document that has changes.
+    fn save_all(&mut self) -> Result<(), String> {
+        if self.document.is_dirty() {
+            self.save()?;
+        }
+        for buffer in &mut self.hidden_buffers {
+            if !buffer.document.is_dirty() {
+                continue;
+            }
+            if buffer.document.file_name.is_none() {
+                return Err(format!("No file name for buffer {}", buffer.number));
+            }
+            buffer
+                .document
+                .save()
+                .map_err(|error| format!("Error writing buffer {}: {}", buffer.number, error))?;
+        }
+        Ok(())
+    }
+    fn quit(&mut self, force: bool) -> Result<(), String> {
+        if !force {
+            if self.document.is_dirty() {
+                return Err("No write since last change (use :q! to override)".to_string());
+            }
+            if let Some(buffer) = self
+                .hidden_buffers
+                .iter()
+                .find(|buffer| buffer.document.is_dirty())
+            {
+                return Err(format!(
+                    "No write since last change for buffer {} (add ! to override)",
+                    buffer.number
+                ));
+            }
+        }
+        self.should_quit = true;
+        Ok(())
+    }
+    // Shows buffer `number`, keeping the current one in the background.
+    fn switch_to_buffer(&mut self, number: usize) -> Result<(), String> {
+        if number == self.buffer_number {
+            return Ok(());
+        }
+        let index = self
+            .hidden_buffers
+            .iter()
+            .position(|buffer| buffer.number == number)
+            .ok_or_else(|| format!("Buffer {} does not exist", number))?;
+        let target = self.hidden_buffers.remove(index);
+        let current = Buffer {
+            number: self.buffer_number,
+            document: std::mem::take(&mut self.document),
+            cursor_position: self.cursor_position.clone(),
+            offset: self.offset.clone(),
+            marks: std::mem::take(&mut self.marks),
+        };
+        self.hidden_buffers.push(current);
+        self.hidden_buffers.sort_by_key(|buffer| buffer.number);
+        self.load_buffer(target);
+        Ok(())
+    }
+    fn load_buffer(&mut self, buffer: Buffer) {
+        self.buffer_number = buffer.number;
+        self.document = buffer.document;
+        self.cursor_position = buffer.cursor_position;
+        self.offset = buffer.offset;
+        self.marks = buffer.marks;
+        self.desired_column = None;
+    }
+    // `:e file`: shows the buffer of `file_name`, opening it first if needed.
+    // An empty, unchanged, unnamed document is replaced instead of kept.
+    fn edit(&mut self, file_name: &str) -> Result<(), String> {
+        if Path::new(file_name).is_dir() {
+            return self.explore(Path::new(file_name), None);
+        }
+        if let Some(number) = self.buffer_of(file_name) {
+            return self.switch_to_buffer(number);
+        }
+        let document =
+            Document::open(file_name).map_err(|_| format!("Could not open file: {}", file_name))?;
+        let buffer = Buffer {
+            number: self.next_buffer_number,
+            document,
+            cursor_position: Position::default(),
+            offset: Position::default(),
+            marks: HashMap::new(),
+        };
+        self.next_buffer_number = self.next_buffer_number.saturating_add(1);
+        if self.document.file_name.is_none()
+            && self.document.is_empty()
+            && !self.document.is_dirty()
+            && self.quickfix_buffer != Some(self.buffer_number)
+        {
+            self.load_buffer(buffer);
+        } else {
+            self.hidden_buffers.push(buffer);
+            self.switch_to_buffer(self.next_buffer_number.saturating_sub(1))?;
+        }
+        self.remember_file(file_name);
+        self.status_message = StatusMessage::from(format!("Opened: {}", file_name));
+        Ok(())
+    }
+    // `:e!` and `:e`: reads the file again, dropping any changes.
+    fn revert(&mut self) -> Result<(), String> {
+        let file_name = self
+            .document
+            .file_name
+            .clone()
+            .ok_or_else(|| "No file name".to_string())?;
+        self.document = Document::open(&file_name)
+            .map_err(|_| format!("Could not open file: {}", file_name))?;
+        self.cursor_position.y = self
+            .cursor_position
+            .y
+            .min(self.document.len().saturating_sub(1));
+        let len = self
+            .document
+            .row(self.cursor_position.y)
+            .map_or(0, Row::len);
+        self.cursor_position.x = self.cursor_position.x.min(len.saturating_sub(1));
+        Ok(())
+    }
+    // The buffer, current or hidden, that has `file_name` open under this or
+    // any other name.
+    fn buffer_of(&self, file_name: &str) -> Option<usize> {
+        self.documents()
+            .find(|(_, document)| {
+                document
+                    .file_name
+                    .as_deref()
+                    .map_or(false, |name| files::same_file(name, file_name))
+            })
+            .map(|(number, _)| number)
+    }
+    fn documents(&self) -> impl Iterator<Item = (usize, &Document)> {
+        std::iter::once((self.buffer_number, &self.document)).chain(
+            self.hidden_buffers
+                .iter()
+                .map(|buffer| (buffer.number, &buffer.document)),
+        )
+    }
+    // `:b`: a buffer number, the name of a file or a unique part of one.
+    fn find_buffer(&self, argument: &str) -> Result<usize, String> {
+        if let Ok(number) = argument.parse() {
+            return Ok(number);
+        }
+        if let Some(number) = self.buffer_of(argument) {
+            return Ok(number);
+        }
+        let matching: Vec<usize> = self
+            .documents()
+            .filter(|(_, document)| {
+                document
+                    .file_name
+                    .as_deref()
+                    .map_or(false, |file_name| file_name.contains(argument))
+            })
+            .map(|(number, _)| number)
+            .collect();
+        match matching.as_slice() {
+            [number] => Ok(*number),
+            [] => Err(format!("No matching buffer for {}", argument)),
+            _ => Err(format!("More than one match for {}", argument)),
+        }
+    }
+    // `:bn` / `:bp`: the next or previous buffer by number, wrapping around.
+    fn cycle_buffer(&mut self, forward: bool) -> Result<(), String> {
+        let numbers: Vec<usize> = self
+            .hidden_buffers
+            .iter()
+            .map(|buffer| buffer.number)
+            .collect();
+        let next = if forward {
+            numbers
+                .iter()
+                .find(|number| **number > self.buffer_number)
+                .or_else(|| numbers.first())
+        } else {
+            numbers
+                .iter()
+                .rev()
+                .find(|number| **number < self.buffer_number)
+                .or_else(|| numbers.last())
+        };
+        match next {
+            Some(number) => self.switch_to_buffer(*number),
+            None => Ok(()),
+        }
+    }
+    fn buffer_name(&self, number: usize, document: &Document) -> String {
+        match &document.file_name {
+            Some(file_name) => file_name.clone(),
+            None if self.quickfix_buffer == Some(number) => "[Quickfix List]".to_string(),
+            None => self.explorers.get(&number).map_or_else(
+                || "[No Name]".to_string(),
+                |directory| directory.join("").display().to_string(),
+            ),
+        }
+    }
+    fn list_buffers(&self) -> String {
+        let describe = |number: usize, document: &Document, current: bool| {
+            format!(
+                "{}{}{} \"{}\"",
+                number,
+                if current { " %a" } else { " h" },
+                if document.is_dirty() { " +" } else { "" },
+                self.buffer_name(number, document)
+            )
+        };
+        let mut buffers: Vec<(usize, String)> = self
+            .hidden_buffers
+            .iter()
+            .map(|buffer| {
+                (
+                    buffer.number,
+                    describe(buffer.number, &buffer.document, false),
+                )
+            })
+            .collect();
+        buffers.push((
+            self.buffer_number,
+            describe(self.buffer_number, &self.document, true),
+        ));
+        buffers.sort_by_key(|(number, _)| *number);
+        buffers
+            .into_iter()
+            .map(|(_, description)| description)
+            .collect::<Vec<String>>()
+            .join("  ")
+    }
+    // `:cd`: relative file names would point elsewhere afterwards, so every
+    // open document is given its full path first.
+    fn change_directory(&mut self, argument: &str) -> Result<(), String> {
+        let current = env::current_dir().map_err(|error| error.to_string())?;
+        let target = match argument {
+            "" => env::var("HOME")
+                .map(PathBuf::from)
+                .map_err(|_| "No home directory".to_string())?,
+            "-" => self
+                .previous_directory
+                .clone()
+                .ok_or_else(|| "No previous directory".to_string())?,
+            directory => PathBuf::from(directory),
+        };
+        env::set_current_dir(&target)
+            .map_err(|error| format!("Can't find directory {}: {}", target.display(), error))?;
+        let documents = std::iter::once(&mut self.document).chain(
+            self.hidden_buffers
+                .iter_mut()
+                .map(|buffer| &mut buffer.document),
+        );
+        for document in documents {
+            if let Some(file_name) = &document.file_name {
+                if Path::new(file_name).is_relative() {
+                    document.file_name = Some(current.join(file_name).display().to_string());
+                }
+            }
+        }
+        self.quickfix.rebase(&current);
+        self.previous_directory = Some(current);
+        self.status_message = StatusMessage::from(env::current_dir().map_or_else(
+            |error| error.to_string(),
+            |directory| directory.display().to_string(),
+        ));
+        Ok(())
+    }
+    // `:grep` and `:vimgrep`: fills the quickfix list and goes to its first
+    // entry, unless told not to.
+    fn grep(&mut self, argument: &str, vim: bool, bang: bool) -> Result<(), String> {
+        let grep = quickfix::parse_grep(argument, vim)?;
+        let regex = search::compile(&grep.pattern, self.options.search())?;
+        let entries = quickfix::grep(&regex, &grep.paths, grep.all);
+        self.last_search = Some(grep.pattern.clone());
+        self.search_smart_case = true;
+        self.no_hlsearch = false;
+        if entries.is_empty() {
+            return Err(format!("No match: {}", grep.pattern));
+        }
+        self.quickfix = Quickfix::new(entries);
+        self.refresh_quickfix_buffer();
+        if grep.no_jump || (bang && !vim) {
+            self.status_message = StatusMessage::from(format!("{} matches", self.quickfix.len()));
+            return Ok(());
+        }
+        self.jump_to_entry(0)
+    }
+    // `:make`: runs 'makeprg' and reads the errors in its output into the
+    // quickfix list, going to the first one unless told not to.
+    fn make(&mut self, argument: &str, bang: bool) -> Result<(), String> {
+        let line = shell::expand_file_name(
+            format!("{} {}", self.options.makeprg, argument).trim(),
+            self.document.file_name.as_deref(),
+        )?;
+        let output = shell::capture(&line)?;
+        let entries = errorformat::parse(&output, &self.options.errorformat)?;
+        self.quickfix = Quickfix::new(entries);
+        self.refresh_quickfix_buffer();
+        if self.quickfix.is_empty() {
+            self.status_message = StatusMessage::from(format!("{}: no errors", line));
+            return Ok(());
+        }
+        if bang {
+            self.status_message = StatusMessage::from(format!("{} errors", self.quickfix.len()));
+            return Ok(());
+        }
+        self.jump_to_entry(0)
+    }
+    // Opens the file of quickfix entry `index` at its line and column.
+    fn jump_to_entry(&mut self, index: usize) -> Result<(), String> {
+        let entry = self.quickfix.select(index)?.clone();
+        self.edit(&entry.file_name)?;
+        let y = entry.line.min(self.document.len().saturating_sub(1));
+        let len = self.document.row(y).map_or(0, Row::len);
+        self.cursor_position = Position {
+            x: entry.column.min(len.saturating_sub(1)),
+            y,
+        };
+        self.desired_column = None;
+        self.status_message = StatusMessage::from(format!(
+            "({} of {}): {}",
+            self.quickfix.current().saturating_add(1),
+            self.quickfix.len(),
+            entry.text.trim()
+        ));
+        Ok(())
+    }
+    // Puts the quickfix list in its buffer, if `:copen` made one.
+    fn refresh_quickfix_buffer(&mut self) {
+        let number = match self.quickfix_buffer {
+            Some(number) => number,
+            None => return,
+        };
+        let lines: Vec<String> = self.quickfix.entries().iter().map(Quickfix::line).collect();
+        let document = Document::scratch(&lines);
+        let cursor_position = Position {
+            x: 0,
+            y: self.quickfix.current(),
+        };
+        if number == self.buffer_number {
+            self.document = document;
+            self.cursor_position = cursor_position;
+        } else if let Some(buffer) = self
+            .hidden_buffers
+            .iter_mut()
+            .find(|buffer| buffer.number == number)
+        {
+            buffer.document = document;
+            buffer.cursor_position = cursor_position;
+            buffer.offset = Position::default();
+        }
+    }
+    // `:copen`: there is only one window, so the list is shown in a buffer of
+    // its own. Enter there goes to the entry under the cursor.
+    fn open_quickfix(&mut self) -> Result<(), String> {
+        let number = match self.quickfix_buffer {
+            Some(number) => number,
+            None => {
+                let number = self.next_buffer_number;
+                self.next_buffer_number = number.saturating_add(1);
+                self.hidden_buffers.push(Buffer {
+                    number,
+                    document: Document::default(),
+                    cursor_position: Position::default(),
+                    offset: Position::default(),
+                    marks: HashMap::new(),
+                });
+                self.quickfix_buffer = Some(number);
+                number
+            }
+        };
+        self.refresh_quickfix_buffer();
+        self.switch_to_buffer(number)
+    }
+    // `:cdo`: runs `command` at every quickfix entry, stopping at the first
+    // error.
+    fn quickfix_do(&mut self, command: &str) -> Result<(), String> {
+        if self.quickfix.is_empty() {
+            return Err("No Errors".to_string());
+        }
+        for index in 0..self.quickfix.len() {
+            self.jump_to_entry(index)?;
+            self.execute_ex(command)?;
+        }
+        Ok(())
+    }
+    // The items `:Files`, `:Buffers`, `:History` and `:BLines` pick from.
+    // Files are looked for under `directory`.
+    fn picker_items(&self, source: Source, directory: &str) -> Vec<Item> {
+        match source {
+            Source::Files => picker::files(directory)
+                .into_iter()
+                .map(|file_name| Item {
+                    prefix: String::new(),
+                    text: file_name.clone(),
+                    target: picker::Target::File(file_name),
+                })
+                .collect(),
+            Source::Buffers => {
+                let mut buffers: Vec<(usize, &Document)> = self
+                    .hidden_buffers
+                    .iter()
+                    .map(|buffer| (buffer.number, &buffer.document))
+                    .collect();
+                buffers.push((self.buffer_number, &self.document));
+                buffers.sort_by_key(|(number, _)| *number);
+                buffers
+                    .into_iter()
+                    .map(|(number, document)| Item {
+                        prefix: format!("{:>3} ", number),
+                        text: self.buffer_name(number, document),
+                        target: picker::Target::Buffer(number),
+                    })
+                    .collect()
+            }
+            // Newest first, and relative to the working directory when
+            // inside it.
+            Source::Recent => self
+                .history
+                .entries(Kind::File)
+                .iter()
+                .rev()
+                .map(Path::new)
+                .filter(|path| path.is_file())
+                .map(|path| {
+                    let file_name = relative_path(path);
+                    Item {
+                        prefix: String::new(),
+                        text: file_name.clone(),
+                        target: picker::Target::File(file_name),
+                    }
+                })
+                .collect(),
+            Source::Lines => {
+                let width = self.document.len().to_string().len();
+                (0..self.document.len())
+                    .filter_map(|y| {
+                        Some(Item {
+                            prefix: format!("{:>width$} ", y.saturating_add(1), width = width),
+                            text: self.document.get_line(y)?,
+                            target: picker::Target::Line(y),
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+    // Shows the picker until Enter goes to the selected item or Esc leaves.
+    // Typing narrows the list down; Up and Down (or Ctrl-p and Ctrl-n) and
+    // PageUp and PageDown move the selection.
+    fn pick(&mut self, source: Source, directory: &str) -> Result<(), String> {
+        let items = self.picker_items(source, directory);
+        if items.is_empty() {
+            return Err(format!("{}: nothing to pick", source.title()));
+        }
+        self.picker = Some(Picker::new(source, items));
+        let page = (self.terminal.size().height as usize)
+            .saturating_sub(1)
+            .max(1);
+        let target = loop {
+            let picker = match &mut self.picker {
+                Some(picker) => picker,
+                None => break None,
+            };
+            let prompt = format!(
+                "{} {}/{}> ",
+                picker.source.title(),
+                picker.match_count(),
+                picker.len()
+            );
+            self.prompt_cursor = Some(prompt.width().saturating_add(picker.query.cursor_width()));
+            self.status_message = StatusMessage::from(format!("{}{}", prompt, picker.query.text()));
+            if let Err(error) = self.refresh_screen() {
+                self.picker = None;
+                return Err(error.to_string());
+            }
+            let key = self.read_key().map_err(|error| error.to_string())?;
+            let picker = match &mut self.picker {
+                Some(picker) => picker,
+                None => break None,
+            };
+            let query = picker.query.text().to_string();
+            match key {
+                Key::Char('\n') => break picker.selected_item().map(|item| item.target.clone()),
+                Key::Esc | Key::Ctrl('c') => break None,
+                Key::Backspace => picker.query.backspace(),
+                Key::Delete => picker.query.delete(),
+                Key::Left => picker.query.left(),
+                Key::Right => picker.query.right(),
+                Key::Home | Key::Ctrl('a') => picker.query.home(),
+                Key::End | Key::Ctrl('e') => picker.query.end(),
+                Key::Ctrl('w') => picker.query.delete_word(),
+                Key::Ctrl('u') => picker.query.delete_to_start(),
+                Key::Up | Key::Ctrl('p') => picker.select(false, 1),
+                Key::Down | Key::Ctrl('n') => picker.select(true, 1),
+                Key::PageUp => picker.select(false, page),
+                Key::PageDown => picker.select(true, page),
+                Key::Char(c) if !c.is_control() => picker.query.insert(c),
+                _ => (),
+            }
+            if picker.query.text() != query {
+                picker.rank();
+            }
+        };
+        self.picker = None;
+        self.prompt_cursor = None;
+        self.status_message = StatusMessage::from(String::new());
+        match target {
+            Some(picker::Target::File(file_name)) => self.edit(&file_name),
+            Some(picker::Target::Buffer(number)) => self.switch_to_buffer(number),
+            Some(picker::Target::Line(y)) => {
+                self.cursor_position = Position { x: 0, y };
+                self.move_cursor(Movement::FirstNonWhitespace);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+    // Adds a file that was opened to the history of the picker of recent
+    // files, by its full path.
+    fn remember_file(&mut self, file_name: &str) {
+        if let Ok(path) = fs::canonicalize(file_name) {
+            self.remember(Kind::File, &path.display().to_string());
+        }
+    }
+    // Lists `directory` in an explorer buffer: the current one when it is
+    // already an explorer, or else a new one. The cursor goes to the line of
+    // `select` when there is one, or stays where it was when the same
+    // directory is listed again.
+    fn explore(&mut self, directory: &Path, select: Option<&str>) -> Result<(), String> {
+        let directory = fs::canonicalize(directory)
+            .map_err(|error| format!("{}: {}", directory.display(), error))?;
+        let lines = explorer::list(&directory)
+            .map_err(|error| format!("{}: {}", directory.display(), error))?;
+        let document = Document::scratch(&lines);
+        let current = self.explorers.get(&self.buffer_number);
+        let y = match current {
+            Some(current) if *current == directory => self.cursor_position.y,
+            _ => 0,
+        };
+        if current.is_some()
+            || (self.document.file_name.is_none()
+                && self.document.is_empty()
+                && !self.document.is_dirty()
+                && self.quickfix_buffer != Some(self.buffer_number))
+        {
+            self.document = document;
+            self.offset = Position::default();
+        } else {
+            let number = self.next_buffer_number;
+            self.next_buffer_number = number.saturating_add(1);
+            self.hidden_buffers.push(Buffer {
+                number,
+                document,
+                cursor_position: Position::default(),
+                offset: Position::default(),
+                marks: HashMap::new(),
+            });
+            self.switch_to_buffer(number)?;
+        }
+        let y = select
+            .and_then(|select| lines.iter().position(|line| line == select))
+            .unwrap_or(y);
+        self.cursor_position = Position {
+            x: 0,
+            y: y.min(lines.len().saturating_sub(1)),
+        };
+        self.desired_column = None;
+        self.explorers.insert(self.buffer_number, directory);
+        self.status_message = StatusMessage::from(
+            "Enter: open | -: up | %: new file | d: new directory | R: rename | D: delete"
+                .to_string(),
+        );
+        Ok(())
+    }
+    // A key in Normal mode in an explorer buffer: Enter opens the file or
+    // directory under the cursor and `-` goes up, `%` and `d` make a new file
+    // or directory, and `R` and `D` rename and delete the entry under the
+    // cursor.
+    fn explorer_key(&mut self, key: char) -> Result<(), String> {
+        let directory = match self.explorers.get(&self.buffer_number) {
+            Some(directory) => directory.clone(),
+            None => return Ok(()),
+        };
+        let entry = self
+            .document
+            .get_line(self.cursor_position.y)
+            .unwrap_or_default();
+        let name = entry.trim_end_matches('/');
+        let path = match explorer::entry_path(&directory, &entry) {
+            Some(path) if key == '\n' && path.is_dir() => return self.explore(&path, None),
+            Some(path) if key == '\n' => return self.edit(&relative_path(&path)),
+            _ if key == '-' => {
+                let select = directory
+                    .file_name()
+                    .map(|name| format!("{}/", name.to_string_lossy()));
+                return match directory.parent() {
+                    Some(parent) => self.explore(parent, select.as_deref()),
+                    None => Ok(()),
+                };
+            }
+            Some(path) if name != ".." => Some(path),
+            _ => None,
+        };
+        match (key, path) {
+            ('%' | 'd', _) => {
+                let prompt = if key == 'd' {
+                    "New directory: "
+                } else {
+                    "New file: "
+                };
+                let name = match self
+                    .prompt(prompt, "", None, |_, _, _| {})
+                    .map_err(|error| error.to_string())?
+                {
+                    Some(name) => name,
+                    None => return Ok(()),
+                };
+                explorer::create(&directory.join(&name), key == 'd')
+                    .map_err(|error| format!("{}: {}", name, error))?;
+                let select = if key == 'd' {
+                    format!("{}/", name)
+                } else {
+                    name
+                };
+                self.explore(&directory, Some(&select))
+            }
+            ('R', Some(path)) => {
+                let new_name = match self
+                    .prompt("Rename to: ", name, None, |_, _, _| {})
+                    .map_err(|error| error.to_string())?
+                {
+                    Some(new_name) if new_name != name => new_name,
+                    _ => return Ok(()),
+                };
+                explorer::rename(&path, &directory.join(&new_name))
+                    .map_err(|error| format!("{}: {}", new_name, error))?;
+                let select = if entry.ends_with('/') {
+                    format!("{}/", new_name)
+                } else {
+                    new_name
+                };
+                self.explore(&directory, Some(&select))
+            }
+            ('D', Some(path)) => {
+                if self.confirm(&format!("Delete {}?", entry))? {
+                    explorer::delete(&path).map_err(|error| format!("{}: {}", entry, error))?;
+                    self.explore(&directory, None)?;
+                    self.status_message = StatusMessage::from(format!("Deleted {}", entry));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+    // Asks a question on the message bar, answered by `y` or anything else.
+    fn confirm(&mut self, question: &str) -> Result<bool, String> {
+        self.status_message = StatusMessage::from(format!("{} (y/n)", question));
+        self.refresh_screen().map_err(|error| error.to_string())?;
+        let key = self.read_key().map_err(|error| error.to_string())?;
+        self.status_message = StatusMessage::from(String::new());
+        Ok(key == Key::Char('y'))
+    }
+    // `/` and `?`. With 'incsearch' the cursor shows where the pattern
+    // would go while it is typed; the view is put back afterwards.
+    fn search(&mut self, direction: SearchDirection, count: Option<usize>) {
+        let old_position = self.cursor_position.clone();
+        let old_offset = self.offset.clone();
+        let prompt = match direction {
+            SearchDirection::Forward => "/",
+            SearchDirection::Backward => "?",
+        };
+        let query = self
+            .prompt(prompt, "", Some(Kind::Search), |editor, _, query| {
+                if !editor.options.incsearch {
+                    return;
+                }
+                // A pattern still being typed may not be valid yet.
+                editor.highlighted_pattern = if query.is_empty() {
+                    None
+                } else {
+                    search::compile(query, editor.options.search()).ok()
+                };
+                let found = editor
+                    .highlighted_pattern
+                    .as_ref()
+                    .and_then(|pattern| editor.find_match(pattern, &old_position, direction));
+                match found {
+                    Some((found, _)) => {
+                        editor.cursor_position = found;
+                        editor.scroll();
+                    }
+                    None => {
+                        editor.cursor_position = old_position.clone();
+                        editor.offset = old_offset.clone();
+                    }
+                }
+            })
+            .unwrap_or(None);
+        self.highlighted_pattern = None;
+        self.cursor_position = old_position;
+        self.offset = old_offset;
+        let query = match query {
+            Some(query) => query,
+            None => return,
+        };
+        if let Err(message) = search::compile(&query, self.options.search()) {
+            self.status_message = StatusMessage::from(message);
+            return;
+        }
+        self.last_search = Some(query);
+        self.search_direction = direction;
+        self.search_smart_case = true;
+        self.search_next(count, false);
+    }
+    // The match after (or before) `from`, and whether the search had to
+    // continue at the other end of the file to find it.
+    fn find_match(
+        &self,
+        regex: &Regex,
+        from: &Position,
+        direction: SearchDirection,
+    ) -> Option<(Position, bool)> {
+        let start = match direction {
+            SearchDirection::Forward => Position {
+                x: from.x.saturating_add(1),
+                y: from.y,
+            },
+            SearchDirection::Backward => from.clone(),
+        };
+        if let Some(found) = self.document.find(regex, &start, direction) {
+            return Some((found, false));
+        }
+        if !self.options.wrapscan {
+            return None;
+        }
+        let restart = match direction {
+            SearchDirection::Forward => Position::default(),
+            SearchDirection::Backward => {
+                let y = self.document.len().saturating_sub(1);
+                let x = self.document.row(y).map_or(0, Row::len);
+                Position { x, y }
+            }
+        };
+        self.document
+            .find(regex, &restart, direction)
+            .map(|found| (found, true))
+    }
+    // The settings for the last search pattern.
+    fn search_settings(&self) -> Settings {
+        let mut settings = self.options.search();
+        settings.smart_case = settings.smart_case && self.search_smart_case;
+        settings
+    }
+    // Compiles the last search pattern again only when it or the settings
+    // for it have changed.
+    fn update_hlsearch_pattern(&mut self) {
+        let settings = self.search_settings();
+        let is_current = match (&self.hlsearch_pattern, &self.last_search) {
+            (Some((compiled, compiled_settings, _)), Some(pattern)) => {
+                compiled == pattern && *compiled_settings == settings
+            }
+            _ => false,
+        };
+        if !is_current {
+            self.hlsearch_pattern = self.last_search.as_ref().and_then(|pattern| {
+                let regex = search::compile(pattern, settings).ok()?;
+                Some((pattern.clone(), settings, regex))
+            });
+        }
+    }
+    // `n` and `N`: the last search again, `N` in the other direction.
+    fn search_next(&mut self, count: Option<usize>, reverse: bool) {
+        self.no_hlsearch = false;
+        if let Err(message) = self.repeat_search(count.unwrap_or(1), reverse) {
+            self.status_message = StatusMessage::from(message);
+        }
+        self.scroll();
+    }
+    fn repeat_search(&mut self, count: usize, reverse: bool) -> Result<(), String> {
+        let pattern = self
+            .last_search
+            .clone()
+            .ok_or_else(|| "No previous regular expression".to_string())?;
+        let regex = search::compile(&pattern, self.search_settings())?;
+        let direction = match (self.search_direction, reverse) {
+            (direction, false) => direction,
+            (SearchDirection::Forward, true) => SearchDirection::Backward,
+            (SearchDirection::Backward, true) => SearchDirection::Forward,
+        };
+        let (end, other_end) = match direction {
+            SearchDirection::Forward => ("BOTTOM", "TOP"),
+            SearchDirection::Backward => ("TOP", "BOTTOM"),
+        };
+        let mut position = self.cursor_position.clone();
+        let mut wrapped = false;
+        for _ in 0..count {
+            let (found, wrap) = self
+                .find_match(&regex, &position, direction)
+                .ok_or_else(|| {
+                    if self.options.wrapscan {
+                        format!("Pattern not found: {}", pattern)
+                    } else {
+                        format!("search hit {} without match for: {}", end, pattern)
+                    }
+                })?;
+            position = found;
+            wrapped = wrapped || wrap;
+        }
+        self.cursor_position = position;
+        self.desired_column = None;
+        let (current, total) =
+            self.document
+                .count_matches(&regex, &self.cursor_position, SEARCH_COUNT_LIMIT);
+        self.search_count = Some((self.cursor_position.clone(), current, total));
+        self.status_message = StatusMessage::from(if wrapped {
+            format!("search hit {}, continuing at {}", end, other_end)
+        } else {
+            let prefix = match self.search_direction {
+                SearchDirection::Forward => '/',
+                SearchDirection::Backward => '?',
+            };
+            format!("{}{}", prefix, pattern)
+        });
+        Ok(())
+    }
+    // `*`, `#`, `g*` and `g#`.
+    fn search_word(&mut self, forward: bool, whole_word: bool, count: Option<usize>) {
+        let (start, word) = match self.word_under_cursor() {
+            Some(word) => word,
+            None => {
+                self.status_message = StatusMessage::from("No string under cursor".to_string());
+                return;
+            }
+        };
+        let is_word = |c: Option<char>| c.map_or(false, |c| c.is_alphanumeric() || c == '_');
+        let mut pattern = search::escape(&word, self.options.search().syntax);
+        if whole_word && is_word(word.chars().next()) {
+            pattern.insert_str(0, "\\<");
+        }
+        if whole_word && is_word(word.chars().last()) {
+            pattern.push_str("\\>");
+        }
+        self.remember(Kind::Search, &pattern);
+        self.last_search = Some(pattern);
+        self.search_direction = if forward {
+            SearchDirection::Forward
+        } else {
+            SearchDirection::Backward
+        };
+        self.search_smart_case = false;
+        // From the start of the word, so that `#` skips the word itself.
+        self.cursor_position.x = start;
+        self.search_next(count, false);
+    }
+    // The keyword under or after the cursor on its line, and where it
+    // starts.
+    fn word_under_cursor(&self) -> Option<(usize, String)> {
+        let row = self.document.row(self.cursor_position.y)?;
+        let keywords = self.document.iskeyword();
+        let chars: Vec<char> = row
+            .to_string()
+            .graphemes(true)
+            .map(|grapheme| grapheme.chars().next().unwrap_or(' '))
+            .collect();
+        let is_keyword = |index: usize| chars.get(index).map_or(false, |c| keywords.is_keyword(*c));
+        let mut start = (self.cursor_position.x..chars.len()).find(|index| is_keyword(*index))?;
+        while start > 0 && is_keyword(start.saturating_sub(1)) {
+            start = start.saturating_sub(1);
+        }
+        let end = (start..chars.len())
+            .find(|index| !is_keyword(*index))
+            .unwrap_or(chars.len());
+        Some((start, row.slice(start, end)))
+    }
+    fn process_normal_key(&mut self, pressed_key: Key) -> Result<(), std::io::Error> {
+        match pressed_key {
+            Key::Char('\n')
+                if self.pending_keys.is_empty()
+                    && self.quickfix_buffer == Some(self.buffer_number) =>
+            {
+                if let Err(message) = self.jump_to_entry(self.cursor_position.y) {
+                    self.status_message = StatusMessage::from(message);
+                }
+                return Ok(());
+            }
+            Key::Char(c @ ('\n' | '-' | '%' | 'd' | 'R' | 'D'))
+                if self.pending_keys.is_empty()
+                    && self.explorers.contains_key(&self.buffer_number) =>
+            {
+                if let Err(message) = self.explorer_key(c) {
+                    self.status_message = StatusMessage::from(message);
+                }
+                return Ok(());
+            }
+            Key::Char(c) => {
+                self.pending_keys.push(c);
+                match normal::parse(&self.pending_keys) {
+                    Parsed::Pending => (),
+                    Parsed::Invalid => self.pending_keys.clear(),
+                    Parsed::Complete(command) => {
+                        self.pending_keys.clear();
+                        self.execute_normal(command)?;
+                    }
+                }
+                return Ok(());
+            }
+            _ => self.pending_keys.clear(),
+        }
+        match pressed_key {
+            Key::Ctrl('d') => self.move_cursor(Movement::HalfPageDown),
+            Key::Ctrl('u') => self.move_cursor(Movement::HalfPageUp),
+            Key::Ctrl('f') | Key::PageDown => self.move_cursor(Movement::PageDown),
+            Key::Ctrl('b') | Key::PageUp => self.move_cursor(Movement::PageUp),
+            Key::Ctrl('e') => self.scroll_view(SearchDirection::Forward),
+            Key::Ctrl('y') => self.scroll_view(SearchDirection::Backward),
+            Key::Ctrl('s') => {
+                if let Err(message) = self.save() {
+                    self.status_message = StatusMessage::from(message);
+                }
+            }
+            Key::Ctrl('r') => {
+                if let Some(pos) = self.document.redo() {
+                    self.cursor_position = pos;
+                    self.status_message = StatusMessage::from("Redo".to_string());
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    fn execute_normal(&mut self, command: Command) -> Result<(), std::io::Error> {
+        let count = command.count;
+        match command.action {
+            Action::Move(movement) => self.move_cursor_times(movement, count),
+            Action::Operate(operator, target) => {
+                self.operate(operator, target, count, command.register);
+            }
+            Action::ReplaceChar(c) => self.replace_chars(c, count.unwrap_or(1)),
+            Action::Join(normalize) => self.join_lines(count, normalize),
+            Action::ToggleCase => {
+                let Position { x, y } = self.cursor_position;
+                let len = self.document.row(y).map_or(0, Row::len);
+                if x < len {
+                    let end = x.saturating_add(count.unwrap_or(1)).min(len);
+                    self.document.map_text(
+                        &self.cursor_position,
+                        &Position { x: end, y },
+                        toggle_case,
+                    );
+                    self.cursor_position.x = end.min(len.saturating_sub(1));
+                }
+            }
+            Action::Key('i') => self.mode = Mode::Insert,
+            Action::Key('a') => {
+                // Insert after cursor
+                self.move_cursor(Movement::Right);
+                self.mode = Mode::Insert;
+            }
+            Action::Key('A') => {
+                // Insert at end of line
+                self.move_cursor(Movement::EndOfLine);
+                self.move_cursor(Movement::Right);
+                self.mode = Mode::Insert;
+            }
+            Action::Key('o') => {
+                // Insert new line below
+                let y = self.cursor_position.y;
+                self.open_line(y.saturating_add(1).min(self.document.len()), y);
+                self.mode = Mode::Insert;
+            }
+            Action::Key('O') => {
+                // Insert new line above
+                let y = self.cursor_position.y;
+                self.open_line(y, y);
+                self.mode = Mode::Insert;
+            }
+            Action::Key('R') => {
+                // Everything replaced until Esc is undone in one step.
+                self.document.begin_undo_group(&self.cursor_position);
+                self.replaced.clear();
+                self.mode = Mode::Replace;
+            }
+            Action::Key('/') => self.search(SearchDirection::Forward, count),
+            Action::Key('?') => self.search(SearchDirection::Backward, count),
+            Action::Key('n') => self.search_next(count, false),
+            Action::Key('N') => self.search_next(count, true),
+            Action::SearchWord(forward, whole_word) => self.search_word(forward, whole_word, count),
+            Action::Pick(source) => {
+                if let Err(message) = self.pick(source, ".") {
+                    self.status_message = StatusMessage::from(message);
+                }
+            }
+            Action::Key('u') => {
+                if let Some(pos) = self.document.undo() {
+                    self.cursor_position = pos;
+                    self.status_message = StatusMessage::from("Undo".to_string());
+                }
+            }
+            Action::Key('x') => {
+                // Delete characters under the cursor
+                let Position { x, y } = self.cursor_position;
+                let len = self.document.row(y).map_or(0, Row::len);
+                if x < len {
+                    let end = Position {
+                        x: x.saturating_add(count.unwrap_or(1)).min(len),
+                        y,
+                    };
+                    let deleted = self.document.delete_text(&self.cursor_position, &end);
+                    let register = Register {
+                        lines: deleted,
+                        linewise: false,
+                    };
+                    self.registers.store(command.register, register, false);
+                }
+            }
+            Action::Key('D') => {
+                let target = Target::Motion(Movement::EndOfLine);
+                self.operate(Operator::Delete, target, count, command.register);
+            }
+            Action::Key('p') => self.paste(true, command.register),
+            Action::Key('P') => self.paste(false, command.register),
+            Action::SetMark(mark) => {
+                if mark.is_ascii_alphabetic() {
+                    self.marks.insert(mark, self.cursor_position.clone());
+                }
+            }
+            Action::JumpToMark(mark, exact) => self.jump_to_mark(mark, exact),
+            Action::RepeatSubstitute(false) => self.run_command("s"),
+            Action::RepeatSubstitute(true) => self.run_command("%s//~/&"),
+            Action::Key('v') => self.enter_visual(Mode::Visual),
+            Action::Key('V') => self.enter_visual(Mode::VisualLine),
+            // Like Vim, a count before `:` turns into a range of that many
+            // lines.
+            Action::Key(':') => match count {
+                Some(1) => self.command_line("."),
+                Some(count) => {
+                    self.command_line(&format!(".,.+{}", count.saturating_sub(1)));
+                }
+                None => self.command_line(""),
+            },
+            Action::Key(_) => (),
+        }
+        Ok(())
+    }
+    // Applies an operator to the text between the cursor and the end of
+    // the motion, or to `count` whole lines.
+    fn operate(
+        &mut self,
+        operator: Operator,
+        target: Target,
+        count: Option<usize>,
+        register: Option<char>,
+    ) {
+        let Position { x, y } = self.cursor_position;
+        let (start, end, linewise) = match target {
+            Target::Lines => {
+                let last = y
+                    .saturating_add(count.unwrap_or(1).saturating_sub(1))
+                    .min(self.document.len().saturating_sub(1));
+                (Position { x: 0, y }, Position { x: 0, y: last }, true)
+            }
+            Target::Motion(movement) => {
+                let target = self.motion_target(movement, count);
+                let (start, mut end) = if (target.y, target.x) < (y, x) {
+                    (target, self.cursor_position.clone())
+                } else {
+                    (self.cursor_position.clone(), target)
+                };
+                // Like Vim, `dw` on the last word of a line stops at the end
+                // of that line instead of joining the next one.
+                if movement == Movement::NextWord && end.y > start.y {
+                    let indent = self
+                        .document
+                        .row(end.y)
+                        .map_or(0, |row| row.indentation().len());
+                    if end.x <= indent {
+                        end.y = end.y.saturating_sub(1);
+                        end.x = self.document.row(end.y).map_or(0, Row::len);
+                    }
+                }
+                if movement.is_inclusive() {
+                    end.x = end.x.saturating_add(1);
+                }
+                let linewise = movement.is_linewise()
+                    || matches!(operator, Operator::Indent | Operator::Dedent);
+                (start, end, linewise)
+            }
+        };
+        self.apply_operator(operator, start, end, linewise, register);
+    }
+    // Applies an operator to the text from `start` up to `end`, or to all
+    // the lines between them when `linewise` is set.
+    fn apply_operator(
+        &mut self,
+        operator: Operator,
+        start: Position,
+        end: Position,
+        linewise: bool,
+        register: Option<char>,
+    ) {
+        let (start, end) = if linewise {
+            let len = self.document.row(end.y).map_or(0, Row::len);
+            (Position { x: 0, y: start.y }, Position { x: len, y: end.y })
+        } else {
+            (start, end)
+        };
+        match operator {
+            Operator::Delete => {
+                let lines = if linewise {
+                    self.document.delete_lines(start.y, end.y)
+                } else {
+                    self.document.delete_text(&start, &end)
+                };
+                self.registers
+                    .store(register, Register { lines, linewise }, false);
+                self.cursor_position = start;
+                if linewise {
+                    self.cursor_position.y = self
+                        .cursor_position
+                        .y
+                        .min(self.document.len().saturating_sub(1));
+                    self.move_cursor(Movement::FirstNonWhitespace);
+                }
+            }
+            Operator::Yank => {
+                let yanked = Register {
+                    lines: self.document.text(&start, &end),
+                    linewise,
+                };
+                self.registers.store(register, yanked, true);
+                if !linewise {
+                    self.cursor_position = start;
+                }
+            }
+            Operator::Lowercase | Operator::Uppercase | Operator::ToggleCase => {
+                let f = match operator {
+                    Operator::Lowercase => str::to_lowercase,
+                    Operator::Uppercase => str::to_uppercase,
+                    _ => toggle_case,
+                };
+                self.document.map_text(&start, &end, f);
+                self.cursor_position = start;
+            }
+            Operator::Indent | Operator::Dedent => {
+                self.shift_lines(start.y, end.y, operator == Operator::Indent);
+            }
+        }
+    }
+    // `>>` and `<<`: change the indentation of non-empty lines by
+    // 'shiftwidth' columns.
+    fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        let tabstop = self.options.tabstop;
+        let shiftwidth = self.options.shiftwidth();
+        self.document.begin_undo_group(&self.cursor_position);
+        for y in first..=last {
+            let width = match self.document.row(y) {
+                Some(row) if !row.is_empty() => {
+                    row.display_column(row.indentation().len(), tabstop)
+                }
+                _ => continue,
+            };
+            let width = if right {
+                width.saturating_add(shiftwidth)
+            } else {
+                width.saturating_sub(shiftwidth)
+            };
+            self.set_indentation(y, width);
+        }
+        self.document.end_undo_group();
+        self.cursor_position.y = first;
+        self.move_cursor(Movement::FirstNonWhitespace);
+    }
+    // `J` / `gJ`: join `count` lines (at least two). `J` drops the leading
+    // whitespace of the joined line and separates the parts with one space,
+    // unless the first part already ends in whitespace or the second starts
+    // with ')'.
+    fn join_lines(&mut self, count: Option<usize>, normalize: bool) {
+        let y = self.cursor_position.y;
+        let joins = count.unwrap_or(2).max(2).saturating_sub(1);
+        if y.saturating_add(1) >= self.document.len() {
+            return;
+        }
+        self.document.begin_undo_group(&self.cursor_position);
+        let mut column = 0;
+        for _ in 0..joins {
+            let (current, next) =
+                match (self.document.row(y), self.document.row(y.saturating_add(1))) {
+                    (Some(current), Some(next)) => (current.to_string(), next.to_string()),
+                    _ => break,
+                };
+            let len = current.graphemes(true).count();
+            let join_point = Position { x: len, y };
+            if normalize {
+                let indent = next
+                    .len()
+                    .saturating_sub(next.trim_start_matches(|c| c == ' ' || c == '\t').len());
+                let rest = &next[indent..];
+                self.document.delete_text(
+                    &join_point,
+                    &Position {
+                        x: indent,
+                        y: y.saturating_add(1),
+                    },
+                );
+                if !current.is_empty()
+                    && !rest.is_empty()
+                    && !current.ends_with(|c| c == ' ' || c == '\t')
+                    && !rest.starts_with(')')
+                {
+                    self.document.insert(&join_point, ' ');
+                }
+            } else {
+                self.document.delete_text(
+                    &join_point,
+                    &Position {
+                        x: 0,
+                        y: y.saturating_add(1),
+                    },
+                );
+            }
+            column = len;
+        }
+        self.document.end_undo_group();
+        let len = self.document.row(y).map_or(0, Row::len);
+        self.cursor_position = Position {
+            x: column.min(len.saturating_sub(1)),
+            y,
+        };
+    }
+    // The indentation a line opened next to line `y` starts with.
+    fn auto_indentation(&self, y: usize) -> String {
+        if self.options.autoindent {
+            self.document
+                .row(y)
+                .map_or(String::new(), |row| row.indentation().to_string())
+        } else {
+            String::new()
+        }
+    }
+    // `o` / `O`: insert a new line at index `at`, indented like line `y`.
+    fn open_line(&mut self, at: usize, y: usize) {
+        let indentation = self.auto_indentation(y);
+        self.document
+            .insert_line(&Position { x: 0, y: at }, &indentation);
+        self.cursor_position = Position {
+            x: indentation.len(),
+            y: at,
+        };
+    }
+    // Enter in Insert mode: split the line at the cursor and indent the new
+    // line like the current one.
+    fn split_line(&mut self) {
+        let at = self.cursor_position.clone();
+        let indentation = self.auto_indentation(at.y);
+        self.document.begin_undo_group(&at);
+        self.document.insert(&at, '\n');
+        let line_start = Position {
+            x: 0,
+            y: at.y.saturating_add(1),
+        };
+        self.cursor_position = self.document.insert_text(&line_start, &[indentation]);
+        self.document.end_undo_group();
+    }
+    fn paste(&mut self, after: bool, register: Option<char>) {
+        let register = match self.registers.get(register) {
+            Some(register) => register.clone(),
+            None => return,
+        };
+        if register.linewise {
+            let y = if after {
+                self.cursor_position
+                    .y
+                    .saturating_add(1)
+                    .min(self.document.len())
+            } else {
+                self.cursor_position.y
+            };
+            self.document.insert_lines(y, &register.lines);
+            self.cursor_position = Position { x: 0, y };
+        } else {
+            let Position { x, y } = self.cursor_position;
+            let len = self.document.row(y).map_or(0, Row::len);
+            let at = Position {
+                x: if after {
+                    x.saturating_add(1).min(len)
+                } else {
+                    x
+                },
+                y,
+            };
+            let end = self.document.insert_text(&at, &register.lines);
+            self.cursor_position = Position {
+                x: end.x.saturating_sub(1),
+                y: end.y,
+            };
+        }
+        self.status_message = StatusMessage::from("Pasted".to_string());
+    }
+    // `r{c}`: replace `count` characters with `c`, or split the line when
+    // `c` is a line break.
+    fn replace_chars(&mut self, c: char, count: usize) {
+        let Position { x, y } = self.cursor_position;
+        let len = self.document.row(y).map_or(0, Row::len);
+        if count == 0 || x.saturating_add(count) > len {
+            return;
+        }
+        let end = Position {
+            x: x.saturating_add(count),
+            y,
+        };
+        if c == '\n' {
+            self.document.begin_undo_group(&self.cursor_position);
+            self.document.delete_text(&self.cursor_position, &end);
+            self.document.insert(&self.cursor_position, '\n');
+            self.document.end_undo_group();
+            self.cursor_position = Position {
+                x: 0,
+                y: y.saturating_add(1),
+            };
+            return;
+        }
+        let replacement = c.to_string().repeat(count);
+        self.document
+            .map_text(&self.cursor_position, &end, |_| replacement.clone());
+        self.cursor_position.x = end.x.saturating_sub(1);
+    }
+    // The key after Ctrl-r names a register to insert, the key after
+    // Ctrl-v is inserted as it is.
+    fn process_insert_pending(&mut self, pending: InsertPending, pressed_key: Key) {
+        match (pending, pressed_key) {
+            (InsertPending::Register, Key::Char(name)) => {
+                if let Some(register) = self.registers.get(Some(name)) {
+                    let mut lines = register.lines.clone();
+                    if register.linewise {
+                        lines.push(String::new());
+                    }
+                    self.cursor_position = self.document.insert_text(&self.cursor_position, &lines);
+                }
+            }
+            (InsertPending::Literal, key) => {
+                let c = match key {
+                    Key::Char('\n') => Some('\r'),
+                    Key::Char(c) => Some(c),
+                    Key::Ctrl(c) => u8::try_from(c).ok().map(|c| char::from(c & 0x1f)),
+                    Key::Esc => Some('\x1b'),
+                    Key::Backspace => Some('\x7f'),
+                    Key::Null => Some('\0'),
+                    _ => None,
+                };
+                if let Some(c) = c {
+                    self.document.insert(&self.cursor_position, c);
+                    self.cursor_position.x = self.cursor_position.x.saturating_add(1);
+                }
+            }
+            _ => (),
+        }
+    }
+    // Tab inserts spaces up to the next tab stop when 'expandtab' is set.
+    fn insert_tab(&mut self) {
+        if !self.options.expandtab {
+            self.document.insert(&self.cursor_position, '\t');
+            self.move_cursor(Movement::Right);
+            return;
+        }
+        let tabstop = self.options.tabstop.max(1);
+        let Position { x, y } = self.cursor_position;
+        let column = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.display_column(x, tabstop));
+        #[allow(clippy::integer_arithmetic)]
+        let spaces = " ".repeat(tabstop - column % tabstop);
+        self.cursor_position = self.document.insert_text(&self.cursor_position, &[spaces]);
+    }
+    // Backspace; at the start of a line it joins the line with the one
+    // above.
+    fn delete_before_cursor(&mut self) {
+        let Position { x, y } = self.cursor_position;
+        if x > 0 {
+            self.cursor_position.x = x.saturating_sub(1);
+        } else if y > 0 {
+            let y = y.saturating_sub(1);
+            self.cursor_position = Position {
+                x: self.document.row(y).map_or(0, Row::len),
+                y,
+            };
+        } else {
+            return;
+        }
+        self.document.delete(&self.cursor_position);
+    }
+    // Ctrl-w: delete the whitespace and then the word before the cursor.
+    fn delete_word_before_cursor(&mut self) {
+        let Position { x, y } = self.cursor_position;
+        if x == 0 {
+            self.delete_before_cursor();
+            return;
+        }
+        let chars: Vec<char> = self.document.row(y).map_or(Vec::new(), |row| {
+            row.slice(0, x)
+                .graphemes(true)
+                .map(|grapheme| grapheme.chars().next().unwrap_or(' '))
+                .collect()
+        });
+        let keywords = self.document.iskeyword();
+        let class_before = |start: usize| {
+            chars
+                .get(start.saturating_sub(1))
+                .map(|c| keywords.class(*c))
+        };
+        let mut start = chars.len();
+        while start > 0 && class_before(start) == Some(CharClass::Whitespace) {
+            start = start.saturating_sub(1);
+        }
+        let class = class_before(start);
+        while start > 0 && class_before(start) == class {
+            start = start.saturating_sub(1);
+        }
+        let from = Position { x: start, y };
+        self.document.delete_text(&from, &self.cursor_position);
+        self.cursor_position = from;
+    }
+    // Ctrl-u: delete the text before the cursor, keeping the indentation
+    // unless the cursor is inside it.
+    fn delete_line_before_cursor(&mut self) {
+        let Position { x, y } = self.cursor_position;
+        let indent = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.indentation().len());
+        let start = if x > indent { indent } else { 0 };
+        if x == 0 {
+            self.delete_before_cursor();
+            return;
+        }
+        let from = Position { x: start, y };
+        self.document.delete_text(&from, &self.cursor_position);
+        self.cursor_position = from;
+    }
+    // Ctrl-t / Ctrl-d: change the indentation of the cursor line to the next
+    // or previous multiple of 'shiftwidth', keeping the cursor on the same
+    // character.
+    #[allow(clippy::integer_arithmetic, clippy::integer_division)]
+    fn shift_cursor_line(&mut self, right: bool) {
+        let Position { x, y } = self.cursor_position;
+        let shiftwidth = self.options.shiftwidth().max(1);
+        let (old_len, width) = self.document.row(y).map_or((0, 0), |row| {
+            let len = row.indentation().len();
+            (len, row.display_column(len, self.options.tabstop))
+        });
+        let width = if right {
+            (width / shiftwidth + 1) * shiftwidth
+        } else if width % shiftwidth != 0 {
+            width - width % shiftwidth
+        } else {
+            width.saturating_sub(shiftwidth)
+        };
+        let new_len = self.set_indentation(y, width);
+        self.cursor_position.x = if x >= old_len {
+            x - old_len + new_len
+        } else {
+            new_len
+        };
+    }
+    // Replaces the indentation of line `y` with one `width` columns wide and
+    // returns its length.
+    fn set_indentation(&mut self, y: usize, width: usize) -> usize {
+        let indent_len = self
+            .document
+            .row(y)
+            .map_or(0, |row| row.indentation().len());
+        let indentation = self.options.indentation(width);
+        self.document.map_text(
+            &Position { x: 0, y },
+            &Position { x: indent_len, y },
+            |_| indentation.clone(),
+        );
+        indentation.len()
+    }
+    // Replace mode overtypes characters; Backspace puts back what was
+    // there before.
+    fn process_replace_key(&mut self, pressed_key: Key) {
+        match pressed_key {
+            Key::Esc => {
+                self.document.end_undo_group();
+                self.mode = Mode::Normal;
+            }
+            Key::Char('\n') => {
+                self.document.insert(&self.cursor_position, '\n');
+                self.cursor_position = Position {
+                    x: 0,
+                    y: self.cursor_position.y.saturating_add(1),
+                };
+                self.replaced.push(Replaced::LineBreak);
+            }
+            Key::Char(c) => {
+                let original = self.document.replace(&self.cursor_position, &c.to_string());
+                if let Some(original) = original {
+                    self.replaced.push(Replaced::Original(original));
+                } else {
+                    self.document.insert(&self.cursor_position, c);
+                    self.replaced.push(Replaced::Inserted);
+                }
+                self.move_cursor(Movement::Right);
+            }
+            Key::Backspace => match self.replaced.pop() {
+                Some(Replaced::Original(original)) => {
+                    self.move_cursor(Movement::Left);
+                    self.document.replace(&self.cursor_position, &original);
+                }
+                Some(Replaced::Inserted) => {
+                    self.move_cursor(Movement::Left);
+                    self.document.delete(&self.cursor_position);
+                }
+                Some(Replaced::LineBreak) => {
+                    let y = self.cursor_position.y.saturating_sub(1);
+                    self.cursor_position = Position {
+                        x: self.document.row(y).map_or(0, Row::len),
+                        y,
+                    };
+                    self.document.delete(&self.cursor_position);
+                }
+                None => self.move_cursor(Movement::Left),
+            },
+            Key::Up | Key::Down | Key::Left | Key::Right => {
+                self.replaced.clear();
+                self.move_cursor(match pressed_key {
+                    Key::Up => Movement::Up,
+                    Key::Down => Movement::Down,
+                    Key::Left => Movement::Left,
+                    _ => Movement::Right,
+                });
+            }
+            _ => (),
+        }
+    }
+    fn jump_to_mark(&mut self, mark: char, exact: bool) {
+        let position = match self.marks.get(&mark) {
+            Some(position) => position.clone(),
+            None => {
+                self.status_message = StatusMessage::from("Mark not set".to_string());
+                return;
+            }
+        };
+        let y = position.y.min(self.document.len().saturating_sub(1));
+        if exact {
+            let len = self.document.row(y).map_or(0, Row::len);
+            self.cursor_position = Position {
+                x: position.x.min(len.saturating_sub(1)),
+                y,
+            };
+        } else {
+            self.cursor_position = Position { x: 0, y };
+            self.move_cursor(Movement::FirstNonWhitespace);
+        }
+    }
+    fn enter_visual(&mut self, mode: Mode) {
+        self.visual_start = self.cursor_position.clone();
+        self.mode = mode;
+    }
+    // Leaving Visual mode sets the `'<` and `'>` marks to the selection.
+    fn leave_visual(&mut self) {
+        let (start, end) = self.visual_range();
+        self.marks.insert('<', start);
+        self.marks.insert('>', end);
+        self.mode = Mode::Normal;
+        let len = self
+            .document
+            .row(self.cursor_position.y)
+            .map_or(0, Row::len);
+        self.cursor_position.x = self.cursor_position.x.min(len.saturating_sub(1));
+    }
+    // The first and last selected position, in document order.
+    fn visual_range(&self) -> (Position, Position) {
+        let (anchor, cursor) = (&self.visual_start, &self.cursor_position);
+        if (cursor.y, cursor.x) < (anchor.y, anchor.x) {
+            (cursor.clone(), anchor.clone())
+        } else {
+            (anchor.clone(), cursor.clone())
+        }
+    }
+    // Graphemes of row `y` inside the selection. An index past the end of the
+    // row stands for its line break.
+    fn selected_in_row(&self, y: usize) -> Option<(usize, usize)> {
+        if let Some((match_y, from, to)) = self.substitute_match {
+            return (y == match_y).then(|| (from, to.max(from.saturating_add(1))));
+        }
+        let (start, end) = self.visual_range();
+        if y < start.y || y > end.y {
+            return None;
+        }
+        let len = self.document.row(y).map_or(0, Row::len);
+        match self.mode {
+            Mode::VisualLine => Some((0, len.max(1))),
+            Mode::Visual => {
+                let from = if y == start.y { start.x } else { 0 };
+                let to = if y == end.y {
+                    end.x.saturating_add(1)
+                } else {
+                    len.saturating_add(1)
+                };
+                Some((from, to))
+            }
+            _ => None,
+        }
+    }
+    fn process_visual_key(&mut self, pressed_key: Key) -> Result<(), std::io::Error> {
+        let c = match pressed_key {
+            Key::Char(c) => c,
+            Key::Esc => {
+                self.pending_keys.clear();
+                self.leave_visual();
+                return Ok(());
+            }
+            _ => {
+                self.pending_keys.clear();
+                return Ok(());
+            }
+        };
+        self.pending_keys.push(c);
+        let command = match normal::parse_visual(&self.pending_keys) {
+            Parsed::Pending => return Ok(()),
+            Parsed::Invalid => {
+                self.pending_keys.clear();
+                return Ok(());
+            }
+            Parsed::Complete(command) => {
+                self.pending_keys.clear();
+                command
+            }
+        };
+        let (start, end) = self.visual_range();
+        let (operator, linewise) = match command.action {
+            Action::Move(movement) => {
+                self.move_cursor_times(movement, command.count);
+                return Ok(());
+            }
+            Action::Join(normalize) => {
+                self.leave_visual();
+                self.cursor_position = start;
+                let lines = end
+                    .y
+                    .saturating_sub(self.cursor_position.y)
+                    .saturating_add(1);
+                self.join_lines(Some(lines), normalize);
+                return Ok(());
+            }
+            Action::Key('o') => {
+                std::mem::swap(&mut self.visual_start, &mut self.cursor_position);
+                return Ok(());
+            }
+            Action::Key(key @ ('v' | 'V')) => {
+                let mode = if key == 'v' {
+                    Mode::Visual
+                } else {
+                    Mode::VisualLine
+                };
+                if self.mode == mode {
+                    self.leave_visual();
+                } else {
+                    self.mode = mode;
+                }
+                return Ok(());
+            }
+            Action::Key(':') => {
+                self.leave_visual();
+                self.command_line("'<,'>");
+                return Ok(());
+            }
+            Action::Key('!') => {
+                self.leave_visual();
+                self.command_line("'<,'>!");
+                return Ok(());
+            }
+            Action::Key('d' | 'x') => (Operator::Delete, false),
+            Action::Key('D' | 'X') => (Operator::Delete, true),
+            Action::Key('y') => (Operator::Yank, false),
+            Action::Key('Y') => (Operator::Yank, true),
+            Action::Key('>') => (Operator::Indent, true),
+            Action::Key('<') => (Operator::Dedent, true),
+            Action::Key('~') => (Operator::ToggleCase, false),
+            Action::Key('u') => (Operator::Lowercase, false),
+            Action::Key('U') => (Operator::Uppercase, false),
+            _ => return Ok(()),
+        };
+        let linewise = linewise || self.mode == Mode::VisualLine;
+        self.leave_visual();
+        self.cursor_position = start.clone();
+        if matches!(operator, Operator::Indent | Operator::Dedent) {
+            for _ in 0..command.count.unwrap_or(1) {
+                self.shift_lines(start.y, end.y, operator == Operator::Indent);
+            }
+            return Ok(());
+        }
+        let end = if linewise {
+            end
+        } else {
+            let len = self.document.row(end.y).map_or(0, Row::len);
+            Position {
+                x: end.x.saturating_add(1).min(len),
+                y: end.y,
+            }
+        };
+        self.apply_operator(operator, start, end, linewise, command.register);
+        Ok(())
+    }
+    // `:s`: replaces matches on the lines `first..=last` as one undo step.
+    #[allow(clippy::integer_arithmetic)]
+    fn substitute(&mut self, (first, last): (usize, usize), argument: &str) -> Result<(), String> {
+        let substitute = substitute::parse(argument, self.last_substitute.as_ref())?;
+        let pattern = if substitute.pattern.is_empty() {
+            self.last_search
+                .clone()
+                .ok_or_else(|| "No previous regular expression".to_string())?
+        } else {
+            substitute.pattern.clone()
+        };
+        let regex = substitute.regex(&pattern, self.options.search())?;
+        self.last_search = Some(pattern.clone());
+        self.search_smart_case = true;
+        self.no_hlsearch = false;
+        self.last_substitute = Some(substitute.clone());
+        let (mut y, mut last) = ex::with_count((first, last), substitute.count);
+        last = last.min(self.document.len().saturating_sub(1));
+        let mut confirm = substitute.flags.confirm;
+        let mut substitutions: usize = 0;
+        let mut lines: usize = 0;
+        let mut grouped = false;
+        let mut stop = false;
+        while y <= last && !stop {
+            let line = match self.document.row(y) {
+                Some(row) => row.to_string(),
+                None => break,
+            };
+            let mut result = String::new();
+            let mut copied = 0;
+            let mut replaced = false;
+            for captures in regex.captures_iter(&line) {
+                let whole = match captures.get(0) {
+                    Some(whole) => whole,
+                    None => continue,
+                };
+                if confirm {
+                    let from = line[..whole.start()].graphemes(true).count();
+                    let to = line[..whole.end()].graphemes(true).count();
+                    let replacement = substitute::expand(&substitute.replacement, &captures);
+                    match self.confirm_substitution(y, from, to, &replacement)? {
+                        'y' => (),
+                        'l' => stop = true,
+                        'a' => confirm = false,
+                        'n' if substitute.flags.global => continue,
+                        'n' => break,
+                        _ => {
+                            stop = true;
+                            break;
+                        }
+                    }
+                }
+                result.push_str(&line[copied..whole.start()]);
+                result.push_str(&substitute::expand(&substitute.replacement, &captures));
+                copied = whole.end();
+                replaced = true;
+                substitutions += 1;
+                if stop || !substitute.flags.global {
+                    break;
+                }
+            }
+            if replaced {
+                result.push_str(&line[copied..]);
+                if !grouped {
+                    self.document.begin_undo_group(&self.cursor_position);
+                    grouped = true;
+                }
+                // A `\r` in the replacement splits the line.
+                let new_lines: Vec<String> = result.split('\n').map(str::to_string).collect();
+                let len = self.document.row(y).map_or(0, Row::len);
+                self.document
+                    .delete_text(&Position { x: 0, y }, &Position { x: len, y });
+                self.document.insert_text(&Position { x: 0, y }, &new_lines);
+                y += new_lines.len() - 1;
+                last += new_lines.len() - 1;
+                lines += 1;
+                self.cursor_position = Position { x: 0, y };
+            }
+            y += 1;
+        }
+        if grouped {
+            self.document.end_undo_group();
+            self.move_cursor(Movement::FirstNonWhitespace);
+        }
+        match (substitutions, lines) {
+            (0, _) if stop => Ok(()),
+            (0, _) => Err(format!("Pattern not found: {}", pattern)),
+            (substitutions, lines) => {
+                self.status_message = StatusMessage::from(format!(
+                    "{} substitution{} on {} line{}",
+                    substitutions,
+                    if substitutions == 1 { "" } else { "s" },
+                    lines,
+                    if lines == 1 { "" } else { "s" }
+                ));
+                Ok(())
+            }
+        }
+    }
+    // `:g/pattern/command` runs an ex command on every line in the range
+    // that matches the pattern, or with `:v` and `:g!` that does not. The
+    // lines are marked first, and the whole run is one undo step.
+    fn global(
+        &mut self,
+        (first, last): (usize, usize),
+        argument: &str,
+        invert: bool,
+    ) -> Result<(), String> {
+        if self.in_global {
+            return Err("Cannot do :global recursively".to_string());
+        }
+        let delimiter = argument
+            .chars()
+            .next()
+            .filter(|c| !c.is_alphanumeric() && !matches!(c, '\\' | '"' | '|'))
+            .ok_or_else(|| "Regular expression missing from :global".to_string())?;
+        let (pattern, command) = ex::split_pattern(&argument[delimiter.len_utf8()..], delimiter);
+        let pattern = if pattern.is_empty() {
+            self.last_search
+                .clone()
+                .ok_or_else(|| "No previous regular expression".to_string())?
+        } else {
+            pattern
+        };
+        let regex = search::compile(&pattern, self.options.search())?;
+        self.last_search = Some(pattern.clone());
+        self.search_smart_case = true;
+        self.no_hlsearch = false;
+        let mut lines = Vec::new();
+        for y in first..=last {
+            let is_match = match self.document.row(y) {
+                Some(row) => regex.is_match(&row.to_string()),
+                None => break,
+            };
+            if is_match != invert {
+                lines.push(y);
+            }
+        }
+        if lines.is_empty() {
+            return Err(if invert {
+                format!("Pattern found in every line: {}", pattern)
+            } else {
+                format!("Pattern not found: {}", pattern)
+            });
+        }
+        if command.trim().is_empty() {
+            self.status_message = StatusMessage::from(format!("{} matching lines", lines.len()));
+            return Ok(());
+        }
+        self.in_global = true;
+        // Marking after the undo state is saved keeps the marks out of it.
+        self.document.begin_undo_group(&self.cursor_position);
+        self.document.mark_lines(&lines);
+        let mut result = Ok(());
+        let mut y = 0;
+        while let Some(marked) = self.document.take_marked_line(y) {
+            y = marked;
+            self.cursor_position = Position { x: 0, y };
+            result = self.execute_ex(command);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.document.unmark_lines();
+        self.document.end_undo_group();
+        self.in_global = false;
+        result
+    }
+    // Shows a match of `:s///c` and asks what to do with it.
+    fn confirm_substitution(
+        &mut self,
+        y: usize,
+        from: usize,
+        to: usize,
+        replacement: &str,
+    ) -> Result<char, String> {
+        self.substitute_match = Some((y, from, to));
+        self.cursor_position = Position { x: from, y };
+        self.scroll();
+        self.status_message =
+            StatusMessage::from(format!("replace with {} (y/n/a/q/l)?", replacement));
+        let answer = loop {
+            self.refresh_screen().map_err(|error| error.to_string())?;
+            match self.read_key().map_err(|error| error.to_string())? {
+                Key::Char(c @ ('y' | 'n' | 'a' | 'q' | 'l')) => break c,
+                Key::Esc => break 'q',
+                _ => (),
+            }
+        };
+        self.substitute_match = None;
+        self.status_message = StatusMessage::from(String::new());
+        Ok(answer)
+    }
+    // The command line of `:!` and friends, with `%` replaced by the file
+    // name and a leading `!` by the previous command.
+    fn shell_command(&mut self, argument: &str) -> Result<String, String> {
+        let line = match argument.strip_prefix('!') {
+            Some(rest) => format!(
+                "{}{}",
+                self.last_shell_command
+                    .as_deref()
+                    .ok_or_else(|| "No previous command".to_string())?,
+                rest
+            ),
+            None => shell::expand_file_name(argument, self.document.file_name.as_deref())?,
+        };
+        if line.trim().is_empty() {
+            return Err("Argument required".to_string());
+        }
+        self.last_shell_command = Some(line.clone());
+        Ok(line)
+    }
+    // Hands the terminal over to a shell command and waits for a key once
+    // it is done.
+    fn run_in_terminal(&mut self, line: &str, input: Option<&str>) -> Result<(), String> {
+        Terminal::clear_screen();
+        Terminal::cursor_position(&Position::default());
+        Terminal::cursor_show();
+        Terminal::flush().map_err(|error| error.to_string())?;
+        self.terminal
+            .suspend_raw_mode()
+            .map_err(|error| error.to_string())?;
+        let result = shell::run(line, input);
+        print!("\nPress any key to continue");
+        Terminal::flush().map_err(|error| error.to_string())?;
+        self.terminal
+            .activate_raw_mode()
+            .map_err(|error| error.to_string())?;
+        self.read_key().map_err(|error| error.to_string())?;
+        result
+    }
+    // `:{range}!`: replaces the lines with what the command makes of them.
+    fn filter_lines(&mut self, first: usize, last: usize, line: &str) -> Result<(), String> {
+        let input: String = (first..=last)
+            .filter_map(|y| self.document.get_line(y))
+            .map(|line| line + "\n")
+            .collect();
+        let output = shell::filter(line, &input)?;
+        let lines: Vec<String> = output.lines().map(str::to_string).collect();
+        self.document.replace_lines(first, last, &lines);
+        self.cursor_position = Position {
+            x: 0,
+            y: first.min(self.document.len().saturating_sub(1)),
+        };
+        self.move_cursor(Movement::FirstNonWhitespace);
+        self.status_message = StatusMessage::from(format!(
+            "{} lines filtered",
+            last.saturating_sub(first).saturating_add(1)
+        ));
+        Ok(())
+    }
+    // Reads an ex command line, starting out with `initial`, and runs it.
+    fn command_line(&mut self, initial: &str) {
+        let command = self
+            .prompt(":", initial, Some(Kind::Command), |_, _, _| {})
+            .unwrap_or(None);
+        if let Some(command) = command {
+            self.run_command(&command);
+        }
+    }
+    fn run_command(&mut self, line: &str) {
+        if let Err(message) = self.execute_ex(line) {
+            self.status_message = StatusMessage::from(message);
+        }
+    }
+    // First and last line of an ex range, counting from 0.
+    fn resolve_range(&self, range: &ex::Range) -> Result<Option<(usize, usize)>, String> {
+        let lines = self.document.lines();
+        let context = ex::Context {
+            current: self.cursor_position.y,
+            lines: &lines,
+            marks: &self.marks,
+            last_search: self.last_search.as_deref(),
+            search: self.options.search(),
+        };
+        range.resolve(&context)
+    }
+    fn resolve_address(&self, address: &ex::Address) -> Result<usize, String> {
+        let lines = self.document.lines();
+        let context = ex::Context {
+            current: self.cursor_position.y,
+            lines: &lines,
+            marks: &self.marks,
+            last_search: self.last_search.as_deref(),
+            search: self.options.search(),
+        };
+        address.line(&context)
+    }
+    fn execute_ex(&mut self, line: &str) -> Result<(), String> {
+        let command = ex::parse(line)?;
+        let range = self.resolve_range(&command.range)?;
+        let argument = command.argument.trim_end();
+        // Commands are matched by their full names from here on.
+        let name = ex::full_name(&command.name).unwrap_or(&command.name);
+        let takes_range = matches!(
+            name,
+            "" | "substitute"
+                | "&"
+                | "global"
+                | "vglobal"
+                | "delete"
+                | "yank"
+                | "move"
+                | "t"
+                | "copy"
+                | "sort"
+                | "normal"
+                | "!"
+                | "read"
+                | "write"
+                | "wq"
+                | "xit"
+        );
+        if !command.range.is_empty() && !takes_range {
+            return Err("No range allowed".to_string());
+        }
+        match (name, command.bang) {
+            // A range without a command jumps to its last line.
+            ("", _) => {
+                if let Some((_, last)) = range {
+                    self.cursor_position = Position {
+                        x: 0,
+                        y: last.min(self.document.len().saturating_sub(1)),
+                    };
+                    self.move_cursor(Movement::FirstNonWhitespace);
+                }
+            }
+            // There is only one window, so quitting it quits everything.
+            ("quit" | "qall" | "quitall", force) => self.quit(force)?,
+            ("write", _) if argument.starts_with('!') => {
+                let line = self.shell_command(&argument[1..])?;
+                let (first, last) = range.unwrap_or((0, self.document.len().saturating_sub(1)));
+                let input: String = (first..=last)
+                    .filter_map(|y| self.document.get_line(y))
+                    .map(|line| line + "\n")
+                    .collect();
+                self.run_in_terminal(&line, Some(&input))?;
+            }
+            ("write", force) => self.write(range, argument, force)?,
+            ("saveas", force) => {
+                if argument.is_empty() {
+                    return Err("Argument required".to_string());
+                }
+                let own_file = self
+                    .document
+                    .file_name
+                    .as_deref()
+                    .map_or(false, |own| files::same_file(own, argument));
+                if !own_file && self.buffer_of(argument).is_some() {
+                    return Err("File is loaded in another buffer".to_string());
+                }
+                if !force && !own_file && Path::new(argument).exists() {
+                    return Err("File exists (add ! to override)".to_string());
+                }
+                self.save_as(argument)?;
+                self.remember_file(argument);
             }
-            if let Err(error) = self.process_keypress() {
-                die(error);
+            ("update", _) => {
+                if self.document.is_dirty() {
+                    self.save()?;
+                }
+            }
+            ("wall", _) => self.save_all()?,
+            ("wqall" | "xall", force) => {
+                self.save_all()?;
+                self.quit(force)?;
+            }
+            ("!", _) => {
+                let line = self.shell_command(argument)?;
+                match range {
+                    Some((first, last)) => self.filter_lines(first, last, &line)?,
+                    None => self.run_in_terminal(&line, None)?,
+                }
+            }
+            // `:r` puts the text below the addressed line; `:0r` above the
+            // first one.
+            ("read", _) => {
+                let below = match command.range.addresses.last() {
+                    Some(address) => self.resolve_address(address)?,
+                    None => self.cursor_position.y.saturating_add(1),
+                };
+                let text = match argument.strip_prefix('!') {
+                    Some(line) => {
+                        let line = self.shell_command(line)?;
+                        shell::filter(&line, "")?
+                    }
+                    None => {
+                        let file_name = match argument {
+                            "" => self
+                                .document
+                                .file_name
+                                .clone()
+                                .ok_or_else(|| "No file name".to_string())?,
+                            file_name => file_name.to_string(),
+                        };
+                        fs::read_to_string(&file_name)
+                            .map_err(|_| format!("Can't open file {}", file_name))?
+                    }
+                };
+                let lines: Vec<String> = text.lines().map(str::to_string).collect();
+                let at = below.min(self.document.len());
+                self.document.insert_lines(at, &lines);
+                if !lines.is_empty() {
+                    self.cursor_position = Position { x: 0, y: at };
+                    self.move_cursor(Movement::FirstNonWhitespace);
+                }
+            }
+            // The quit is skipped when the write fails.
+            ("wq", force) => {
+                self.write(range, argument, force)?;
+                self.quit(force)?;
+            }
+            ("xit", force) => {
+                if self.document.is_dirty() {
+                    self.write(range, argument, force)?;
+                }
+                self.quit(force)?;
+            }
+            ("edit", true) => self.revert()?,
+            ("edit", false) if argument.is_empty() => {
+                if self.document.is_dirty() {
+                    return Err("No write since last change (add ! to override)".to_string());
+                }
+                self.revert()?;
+            }
+            ("edit", false) => self.edit(argument)?,
+            ("Explore", _) if argument.is_empty() => {
+                let file_name = self.document.file_name.clone().unwrap_or_default();
+                let directory = match self.explorers.get(&self.buffer_number) {
+                    Some(directory) => directory.clone(),
+                    None => Path::new(&file_name)
+                        .parent()
+                        .filter(|parent| !parent.as_os_str().is_empty())
+                        .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+                };
+                let select = Path::new(&file_name)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string());
+                self.explore(&directory, select.as_deref())?;
+            }
+            ("Explore", _) => self.explore(Path::new(argument), None)?,
+            ("Files", _) if argument.is_empty() => self.pick(Source::Files, ".")?,
+            ("Files", _) => self.pick(Source::Files, argument)?,
+            ("Buffers", _) => self.pick(Source::Buffers, "")?,
+            ("History", _) => self.pick(Source::Recent, "")?,
+            ("BLines", _) => self.pick(Source::Lines, "")?,
+            ("ls" | "buffers" | "files", _) => {
+                self.status_message = StatusMessage::from(self.list_buffers());
+            }
+            ("buffer", _) => {
+                if !argument.is_empty() {
+                    let number = self.find_buffer(argument)?;
+                    self.switch_to_buffer(number)?;
+                }
+            }
+            ("bnext", _) => self.cycle_buffer(true)?,
+            ("bprevious" | "bNext", _) => self.cycle_buffer(false)?,
+            ("cd" | "chdir" | "lcd" | "lchdir", _) => self.change_directory(argument)?,
+            ("grep", bang) => self.grep(argument, false, bang)?,
+            ("vimgrep", bang) => self.grep(argument, true, bang)?,
+            ("copen", _) => self.open_quickfix()?,
+            ("cnext", _) => {
+                self.quickfix.step(quickfix_count(argument, 1)?, true)?;
+                self.jump_to_entry(self.quickfix.current())?;
+            }
+            ("cprevious" | "cNext", _) => {
+                self.quickfix.step(quickfix_count(argument, 1)?, false)?;
+                self.jump_to_entry(self.quickfix.current())?;
+            }
+            ("cc", _) => {
+                let number = quickfix_count(argument, self.quickfix.current().saturating_add(1))?;
+                self.jump_to_entry(number.saturating_sub(1))?;
+            }
+            ("cfirst" | "crewind", _) => {
+                self.jump_to_entry(quickfix_count(argument, 1)?.saturating_sub(1))?;
+            }
+            ("clast", _) => {
+                let number = quickfix_count(argument, self.quickfix.len())?;
+                self.jump_to_entry(number.saturating_sub(1))?;
+            }
+            ("cdo", _) => self.quickfix_do(argument)?,
+            ("make", bang) => self.make(argument, bang)?,
+            ("pwd", _) => {
+                let directory = env::current_dir().map_err(|error| error.to_string())?;
+                self.status_message = StatusMessage::from(directory.display().to_string());
+            }
+            // `:&` repeats the last substitution without its flags, `:&&`
+            // with them.
+            ("substitute" | "&", _) => {
+                let y = self.cursor_position.y;
+                self.substitute(range.unwrap_or((y, y)), argument)?;
+            }
+            ("global" | "vglobal", bang) => {
+                let whole_file = (0, self.document.len().saturating_sub(1));
+                let invert = bang || name == "vglobal";
+                self.global(range.unwrap_or(whole_file), argument, invert)?;
+            }
+            ("delete", _) => {
+                let (register, count) = ex::register_and_count(argument)?;
+                let y = self.cursor_position.y;
+                let (first, last) = ex::with_count(range.unwrap_or((y, y)), count);
+                let start = Position { x: 0, y: first };
+                let end = Position { x: 0, y: last };
+                self.apply_operator(Operator::Delete, start, end, true, register);
+            }
+            ("yank", _) => {
+                let (register, count) = ex::register_and_count(argument)?;
+                let y = self.cursor_position.y;
+                let (first, last) = ex::with_count(range.unwrap_or((y, y)), count);
+                let start = Position { x: 0, y: first };
+                let end = Position { x: 0, y: last };
+                self.apply_operator(Operator::Yank, start, end, true, register);
+            }
+            ("move" | "t" | "copy", _) => {
+                let target = ex::parse_target(argument)?;
+                let target = self.resolve_address(&target)?;
+                let y = self.cursor_position.y;
+                let copy = name != "move";
+                self.move_lines(range.unwrap_or((y, y)), target, copy)?;
+            }
+            ("sort", reverse) => {
+                let sort = Sort::parse(
+                    argument,
+                    reverse,
+                    self.last_search.as_deref(),
+                    self.options.search(),
+                )?;
+                let (first, last) = range.unwrap_or((0, self.document.len().saturating_sub(1)));
+                let lines = (first..=last)
+                    .filter_map(|y| self.document.get_line(y))
+                    .collect();
+                self.document.replace_lines(first, last, &sort.apply(lines));
+                self.cursor_position = Position { x: 0, y: first };
+            }
+            ("normal", _) => self.execute_keys(range, &command.argument)?,
+            ("set", _) => {
+                let hlsearch = self.options.hlsearch;
+                self.options.filetype = self.document.file_type_id().to_string();
+                let iskeyword = self.document.iskeyword().spec().to_string();
+                self.options.iskeyword = iskeyword.clone();
+                let message = self.options.set(argument);
+                if self.options.filetype != self.document.file_type_id() {
+                    let filetype = self.options.filetype.clone();
+                    self.document.set_file_type(&filetype)?;
+                }
+                if self.options.iskeyword != iskeyword {
+                    let iskeyword = self.options.iskeyword.clone();
+                    self.document.set_iskeyword(&iskeyword)?;
+                }
+                if let Some(message) = message? {
+                    self.status_message = StatusMessage::from(message);
+                }
+                // Switching 'hlsearch' on shows the matches again.
+                if self.options.hlsearch && !hlsearch {
+                    self.no_hlsearch = false;
+                }
+            }
+            ("nohlsearch", _) => self.no_hlsearch = true,
+            ("help", _) if !argument.is_empty() => {
+                let usage = ex::usage(argument)
+                    .ok_or_else(|| format!("Sorry, no help for {}", argument))?;
+                self.status_message = StatusMessage::from(usage.to_string());
+            }
+            ("help", _) => {
+                self.status_message = StatusMessage::from(
+                    "Commands: :w [file] :q :wq :wa :qa :saveas :e[!] <file> :ls :b :cd :pwd :set <option> :[range]{s,g,v,d,y,m,t,sort,normal}"
+                        .to_string(),
+                );
+            }
+            (name, _) => {
+                return Err(format!(
+                    "Unknown command: {} (type :help for commands)",
+                    name
+                ));
             }
         }
+        Ok(())
     }
-    pub fn default() -> Self {
-        let args: Vec<String> = env::args().collect();
-        let mut initial_status =
-            String::from("HELP: i=insert | :w=save | :q=quit | /=search | :help for more");
-
-        let document = if let Some(file_name) = args.get(1) {
-            let doc = Document::open(file_name);
-            if let Ok(doc) = doc {
-                doc
-            } else {
-                initial_status = format!("ERR: Could not open file: {}", file_name);
-                Document::default()
-            }
-        } else {
-            Document::default()
-        };
-
-        Self {
-            should_quit: false,
-            terminal: Terminal::default().expect("Failed to initialize terminal"),
-            document,
-            cursor_position: Position::default(),
-            offset: Position::default(),
-            status_message: StatusMessage::from(initial_status),
-            quit_times: QUIT_TIMES,
-            highlighted_word: None,
-            mode: Mode::Normal,
-            pending_keys: String::new(),
-            clipboard: Vec::new(),
+    fn read_key(&mut self) -> Result<Key, std::io::Error> {
+        match self.queued_keys.pop_front() {
+            Some(key) => Ok(key),
+            None if self.executing_keys => Ok(Key::Esc),
+            None => Terminal::read_key(),
         }
     }
-
-    fn refresh_screen(&mut self) -> Result<(), std::io::Error> {
-        Terminal::cursor_hide();
-        Terminal::cursor_position(&Position::default());
-        if self.should_quit {
-            Terminal::clear_screen();
-            println!("Goodbye.\r");
-        } else {
+    // `:normal`: runs `keys` as Normal mode commands on each line of the
+    // range, ending any command or mode they leave unfinished.
+    fn execute_keys(&mut self, lines: Option<(usize, usize)>, keys: &str) -> Result<(), String> {
+        let (first, last) = lines.unwrap_or((self.cursor_position.y, self.cursor_position.y));
+        let executing_keys = self.executing_keys;
+        self.executing_keys = true;
+        self.document.begin_undo_group(&self.cursor_position);
+        let mut result = Ok(());
+        for y in first..=last {
+            if y >= self.document.len() {
+                break;
+            }
+            if lines.is_some() {
+                self.cursor_position = Position { x: 0, y };
+            }
+            self.queued_keys.extend(keys.chars().map(key_from_char));
+            while !self.queued_keys.is_empty() && result.is_ok() {
+                result = self.process_keypress().map_err(|error| error.to_string());
+            }
+            self.queued_keys.clear();
+            self.pending_keys.clear();
             match self.mode {
-                Mode::Normal => print!("\x1b[2 q"),
-                Mode::Insert => print!("\x1b[5 q"),
+                Mode::Insert | Mode::Replace => {
+                    if self.mode == Mode::Replace {
+                        self.document.end_undo_group();
+                    }
+                    self.mode = Mode::Normal;
+                    self.move_cursor(Movement::Left);
+                }
+                Mode::Visual | Mode::VisualLine => self.leave_visual(),
+                Mode::Normal => (),
             }
-            self.document.highlight(
-                &self.highlighted_word,
-                Some(
-                    self.offset
-                        .y
-                        .saturating_add(self.terminal.size().height as usize),
-                ),
-            );
-            self.draw_rows();
-            self.draw_status_bar();
-            self.draw_message_bar();
-            let line_num_width = 5; // 4 digits + 1 space
-            Terminal::cursor_position(&Position {
-                x: self.cursor_position.x.saturating_sub(self.offset.x).saturating_add(line_num_width),
-                y: self.cursor_position.y.saturating_sub(self.offset.y),
-            });
-        }
-        Terminal::cursor_show();
-        Terminal::flush()
-    }
-    fn save(&mut self) {
-        if self.document.file_name.is_none() {
-            let new_name = self.prompt("Save as: ", |_, _, _| {}).unwrap_or(None);
-            if new_name.is_none() {
-                self.status_message = StatusMessage::from("Save aborted.".to_string());
-                return;
+            if result.is_err() || self.should_quit {
+                break;
             }
-            self.document.file_name = new_name;
-        }
-
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from("File saved successfully.".to_string());
-        } else {
-            self.status_message = StatusMessage::from("Error writing file!".to_string());
         }
+        self.document.end_undo_group();
+        self.executing_keys = executing_keys;
+        result
     }
-    fn search(&mut self) {
-        let old_position = self.cursor_position.clone();
-        let mut direction = SearchDirection::Forward;
-        let query = self
-            .prompt(
-                "Search (ESC to cancel, Arrows to navigate): ",
-                |editor, key, query| {
-                    let mut moved = false;
-                    match key {
-                        Key::Right | Key::Down => {
-                            direction = SearchDirection::Forward;
-                            editor.move_cursor(Movement::Right);
-                            moved = true;
-                        }
-                        Key::Left | Key::Up => {
-                            direction = SearchDirection::Backward;
-                            editor.move_cursor(Movement::Left);
-                            moved = true;
-                        }
-                        _ => direction = SearchDirection::Forward,
-                    }
-                    if let Some(position) =
-                        editor
-                            .document
-                            .find(&query, &editor.cursor_position, direction)
-                    {
-                        editor.cursor_position = position;
-                        editor.scroll();
-                    } else if moved {
-                        editor.move_cursor(Movement::Left);
-                    }
-                    editor.highlighted_word = Some(query.to_string());
-                },
-            )
-            .unwrap_or(None);
-
-        if query.is_none() {
-            self.cursor_position = old_position;
-            self.scroll();
+    // Moves (or with `copy` copies) the lines `first..=last` below line
+    // `target`, counting from 1, where 0 puts them at the top.
+    fn move_lines(
+        &mut self,
+        (first, last): (usize, usize),
+        target: usize,
+        copy: bool,
+    ) -> Result<(), String> {
+        if !copy && target > first && target <= last {
+            return Err("Cannot move a range of lines into itself".to_string());
         }
-        self.highlighted_word = None;
+        let lines: Vec<String> = (first..=last)
+            .filter_map(|y| self.document.get_line(y))
+            .collect();
+        let count = lines.len();
+        self.document.begin_undo_group(&self.cursor_position);
+        let at = if copy {
+            target
+        } else {
+            self.document.delete_lines(first, last);
+            if target > last {
+                target.saturating_sub(count)
+            } else {
+                target
+            }
+        };
+        self.document.insert_lines(at, &lines);
+        self.document.end_undo_group();
+        self.cursor_position = Position {
+            x: 0,
+            y: at.saturating_add(count).saturating_sub(1),
+        };
+        self.move_cursor(Movement::FirstNonWhitespace);
+        Ok(())
     }
     fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
+        let pressed_key = self.read_key()?;
         match self.mode {
             Mode::Normal => {
-                self.pending_keys.push(match pressed_key {
-                    Key::Char(c) => c,
-                    _ => ' ',
-                });
-                match pressed_key {
-                    Key::Char('i') => self.mode = Mode::Insert,
-                    Key::Char('a') => {
-                        // Insert after cursor
-                        self.move_cursor(Movement::Right);
-                        self.mode = Mode::Insert;
-                    }
-                    Key::Char('A') => {
-                        // Insert at end of line
-                        self.move_cursor(Movement::EndOfLine);
-                        self.move_cursor(Movement::Right);
-                        self.mode = Mode::Insert;
-                    }
-                    Key::Char('o') => {
-                        // Insert new line below
-                        self.move_cursor(Movement::EndOfLine);
-                        self.document.insert(&self.cursor_position, '\n');
-                        self.move_cursor(Movement::Down);
-                        self.cursor_position.x = 0;
-                        self.mode = Mode::Insert;
-                    }
-                    Key::Char('O') => {
-                        // Insert new line above
-                        self.cursor_position.x = 0;
-                        self.document.insert(&self.cursor_position, '\n');
+                self.process_normal_key(pressed_key)?;
+                if self.insert_after_command && self.pending_keys.is_empty() {
+                    self.insert_after_command = false;
+                    if self.mode == Mode::Normal {
                         self.mode = Mode::Insert;
                     }
-                    Key::Char('h') => self.move_cursor(Movement::Left),
-                    Key::Char('j') => self.move_cursor(Movement::Down),
-                    Key::Char('k') => self.move_cursor(Movement::Up),
-                    Key::Char('l') => self.move_cursor(Movement::Right),
-                    Key::Char('w') => self.move_cursor(Movement::NextWord),
-                    Key::Char('b') => self.move_cursor(Movement::PrevWord),
-                    Key::Char('e') => self.move_cursor(Movement::EndOfWord),
-                    Key::Char('0') => self.move_cursor(Movement::StartOfLine),
-                    Key::Char('$') => self.move_cursor(Movement::EndOfLine),
-                    Key::Char('^') => self.move_cursor(Movement::FirstNonWhitespace),
-                    Key::Char('G') => self.move_cursor(Movement::EndOfFile),
-                    Key::Ctrl('d') => self.move_cursor(Movement::PageDown),
-                    Key::Ctrl('u') => self.move_cursor(Movement::PageUp),
-                    Key::Ctrl('s') => self.save(),
-                    Key::Char('/') => self.search(),
-                    Key::Char('u') => {
-                        if let Some(pos) = self.document.undo() {
-                            self.cursor_position = pos;
-                            self.status_message = StatusMessage::from("Undo".to_string());
-                        }
-                    }
-                    Key::Ctrl('r') => {
-                        if let Some(pos) = self.document.redo() {
-                            self.cursor_position = pos;
-                            self.status_message = StatusMessage::from("Redo".to_string());
-                        }
-                    }
-                    Key::Char('x') => {
-                        // Delete character under cursor
-                        self.document.delete(&self.cursor_position);
-                    }
-                    Key::Char('p') => {
-                        // Paste below current line
-                        if !self.clipboard.is_empty() {
-                            let mut y = self.cursor_position.y.saturating_add(1);
-                            for line in &self.clipboard {
-                                self.document.insert_line(&Position { x: 0, y }, line);
-                                y = y.saturating_add(1);
-                            }
-                            self.cursor_position.y = self.cursor_position.y.saturating_add(1);
-                            self.cursor_position.x = 0;
-                            self.status_message = StatusMessage::from("Pasted".to_string());
-                        }
-                    }
-                    Key::Char('P') => {
-                        // Paste above current line
-                        if !self.clipboard.is_empty() {
-                            let mut y = self.cursor_position.y;
-                            for line in &self.clipboard {
-                                self.document.insert_line(&Position { x: 0, y }, line);
-                                y = y.saturating_add(1);
-                            }
-                            self.cursor_position.x = 0;
-                            self.status_message = StatusMessage::from("Pasted".to_string());
-                        }
-                    }
-                    Key::Char('y') => {
-                        self.pending_keys.push('y');
-                    }
-                    Key::Char('d') => {
-                        self.pending_keys.push('d');
-                    }
-                    Key::Char(':') => {
-                        let command = self.prompt(":", |_, _, _| {}).unwrap_or(None);
-                        if let Some(command) = command {
-                            let parts: Vec<&str> = command.trim().split_whitespace().collect();
-                            if parts.is_empty() {
-                                return Ok(());
-                            }
-                            
-                            match parts[0] {
-                                "q" => {
-                                    if self.document.is_dirty() {
-                                        self.status_message = StatusMessage::from(
-                                            "No write since last change (use :q! to override)".to_string()
-                                        );
-                                    } else {
-                                        self.should_quit = true;
-                                    }
-                                }
-                                "q!" => self.should_quit = true,
-                                "w" => self.save(),
-                                "wq" | "x" => {
-                                    self.save();
-                                    self.should_quit = true;
-                                }
-                                "wq!" => {
-                                    self.save();
-                                    self.should_quit = true;
-                                }
-                                cmd if cmd.starts_with('e') => {
-                                    if parts.len() > 1 {
-                                        let filename = parts[1..].join(" ");
-                                        match Document::open(&filename) {
-                                            Ok(doc) => {
-                                                self.document = doc;
-                                                self.cursor_position = Position::default();
-                                                self.offset = Position::default();
-                                                self.status_message = StatusMessage::from(
-                                                    format!("Opened: {}", filename)
-                                                );
-                                            }
-                                            Err(_) => {
-                                                self.status_message = StatusMessage::from(
-                                                    format!("Could not open file: {}", filename)
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        self.status_message = StatusMessage::from(
-                                            "Usage: :e <filename>".to_string()
-                                        );
-                                    }
-                                }
-                                cmd if cmd.parse::<usize>().is_ok() => {
-                                    // Jump to line number
-                                    if let Ok(line_num) = cmd.parse::<usize>() {
-                                        let target_line = line_num.saturating_sub(1);
-                                        if target_line < self.document.len() {
-                                            self.cursor_position.y = target_line;
-                                            self.cursor_position.x = 0;
-                                            self.scroll();
-                                        }
-                                    }
-                                }
-                                "help" | "h" => {
-                                    self.status_message = StatusMessage::from(
-                                        "Commands: :w :q :wq :q! :e <file> :<number>".to_string()
-                                    );
-                                }
-                                _ => {
-                                    self.status_message = StatusMessage::from(format!(
-                                        "Unknown command: {} (type :help for commands)",
-                                        command
-                                    ));
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        self.pending_keys.clear();
-                    }
                 }
-                if self.pending_keys.ends_with("gg") {
-                    self.move_cursor(Movement::StartOfFile);
-                    self.pending_keys.clear();
-                } else if self.pending_keys.ends_with("yy") {
-                    // Yank current line
-                    if let Some(line) = self.document.get_line(self.cursor_position.y) {
-                        self.clipboard = vec![line];
-                        self.status_message = StatusMessage::from("Yanked line".to_string());
-                    }
-                    self.pending_keys.clear();
-                } else if self.pending_keys.ends_with("dd") {
-                    // Delete current line
-                    if let Some(line) = self.document.delete_line(&self.cursor_position) {
-                        self.clipboard = vec![line];
-                        if self.cursor_position.y >= self.document.len() && self.cursor_position.y > 0 {
-                            self.cursor_position.y = self.cursor_position.y.saturating_sub(1);
+            }
+            Mode::Insert => match self.insert_pending.take() {
+                Some(pending) => self.process_insert_pending(pending, pressed_key),
+                None => match pressed_key {
+                    Key::Ctrl('q') => {
+                        if self.quit_times > 0 && self.document.is_dirty() {
+                            self.status_message = StatusMessage::from(format!(
+                                "WARNING! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                                self.quit_times
+                            ));
+                            self.quit_times -= 1;
+                            return Ok(());
                         }
-                        self.cursor_position.x = 0;
-                        self.status_message = StatusMessage::from("Deleted line".to_string());
+                        self.should_quit = true
                     }
-                    self.pending_keys.clear();
-                } else if self.pending_keys.ends_with("d$") || self.pending_keys.ends_with("D") {
-                    // Delete to end of line
-                    if let Some(deleted) = self.document.delete_to_end_of_line(&self.cursor_position) {
-                        self.clipboard = vec![deleted];
-                        self.status_message = StatusMessage::from("Deleted to end of line".to_string());
+                    Key::Esc => self.mode = Mode::Normal,
+                    Key::Char('\n') => self.split_line(),
+                    Key::Char('\t') => self.insert_tab(),
+                    Key::Char(c) => {
+                        self.document.insert(&self.cursor_position, c);
+                        self.move_cursor(Movement::Right);
                     }
-                    self.pending_keys.clear();
-                }
-            }
-            Mode::Insert => match pressed_key {
-                Key::Ctrl('q') => {
-                    if self.quit_times > 0 && self.document.is_dirty() {
-                        self.status_message = StatusMessage::from(format!(
-                            "WARNING! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-                            self.quit_times
-                        ));
-                        self.quit_times -= 1;
-                        return Ok(());
+                    Key::Delete => self.document.delete(&self.cursor_position),
+                    Key::Backspace => self.delete_before_cursor(),
+                    Key::Ctrl('w') => self.delete_word_before_cursor(),
+                    Key::Ctrl('u') => self.delete_line_before_cursor(),
+                    Key::Ctrl('t') => self.shift_cursor_line(true),
+                    Key::Ctrl('d') => self.shift_cursor_line(false),
+                    Key::Ctrl('r') => self.insert_pending = Some(InsertPending::Register),
+                    Key::Ctrl('v') => self.insert_pending = Some(InsertPending::Literal),
+                    Key::Ctrl('o') => {
+                        self.insert_after_command = true;
+                        self.mode = Mode::Normal;
                     }
-                    self.should_quit = true
-                }
-                Key::Esc => self.mode = Mode::Normal,
-                Key::Char('\n') => {
-                    self.document.insert(&self.cursor_position, '\n');
-                    self.move_cursor(Movement::Down);
-                    self.cursor_position.x = 0;
-                }
-                Key::Char(c) => {
-                    self.document.insert(&self.cursor_position, c);
-                    self.move_cursor(Movement::Right);
-                }
-                Key::Delete => self.document.delete(&self.cursor_position),
-                Key::Backspace => {
-                    if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                        self.move_cursor(Movement::Left);
-                        self.document.delete(&self.cursor_position);
+                    Key::Up => self.move_cursor(Movement::Up),
+                    Key::Down => self.move_cursor(Movement::Down),
+                    Key::Left => self.move_cursor(Movement::Left),
+                    Key::Right => self.move_cursor(Movement::Right),
+                    Key::Home => self.cursor_position.x = 0,
+                    Key::End => {
+                        let y = self.cursor_position.y;
+                        self.cursor_position.x = self.document.row(y).map_or(0, Row::len);
                     }
-                }
-                Key::Up => self.move_cursor(Movement::Up),
-                Key::Down => self.move_cursor(Movement::Down),
-                Key::Left => self.move_cursor(Movement::Left),
-                Key::Right => self.move_cursor(Movement::Right),
-                _ => (),
+                    Key::PageUp => self.move_cursor(Movement::PageUp),
+                    Key::PageDown => self.move_cursor(Movement::PageDown),
+                    _ => (),
+                },
             },
+            Mode::Replace => self.process_replace_key(pressed_key),
+            Mode::Visual | Mode::VisualLine => self.process_visual_key(pressed_key)?,
         }
         self.scroll();
         if self.quit_times < QUIT_TIMES {
@@ -444,28 +2824,64 @@ impl Editor {
         }
         Ok(())
     }
+    // Keeps the cursor on screen. `offset.x` counts screen columns, not
+    // graphemes, so tabs and wide characters scroll by their real width.
     fn scroll(&mut self) {
         let Position { x, y } = self.cursor_position;
         let line_num_width = 5; // 4 digits + 1 space
         let width = self.terminal.size().width as usize;
         let height = self.terminal.size().height as usize;
         let visible_width = width.saturating_sub(line_num_width);
-        
+        let tabstop = self.options.tabstop;
+        let (column, cell_width) = self.document.row(y).map_or((0, 1), |row| {
+            (
+                row.display_column(x, tabstop),
+                row.display_width_at(x, tabstop),
+            )
+        });
+
         if y < self.offset.y {
             self.offset.y = y;
         } else if y >= self.offset.y.saturating_add(height) {
             self.offset.y = y.saturating_sub(height).saturating_add(1);
         }
-        if x < self.offset.x {
-            self.offset.x = x;
-        } else if x >= self.offset.x.saturating_add(visible_width) {
-            self.offset.x = x.saturating_sub(visible_width).saturating_add(1);
+        if column < self.offset.x {
+            self.offset.x = column;
+        } else if column.saturating_add(cell_width) > self.offset.x.saturating_add(visible_width) {
+            self.offset.x = column
+                .saturating_add(cell_width)
+                .saturating_sub(visible_width);
         }
     }
     fn move_cursor(&mut self, movement: Movement) {
-        let Position { x, y } = self.cursor_position;
+        self.move_cursor_times(movement, None);
+    }
+    // Where `movement` repeated `count` times takes the cursor. With a count,
+    // `G` and `gg` go to that line instead.
+    fn motion_target(&self, movement: Movement, count: Option<usize>) -> Position {
         let lines = self.document.lines();
-        let (new_x, new_y) = movement.execute(x, y, &lines);
+        let context = Context {
+            lines: &lines,
+            keywords: self.document.iskeyword(),
+            height: self.terminal.size().height as usize,
+            scroll: self.options.scroll,
+        };
+        if let (Movement::StartOfFile | Movement::EndOfFile, Some(line)) = (movement, count) {
+            let y = line
+                .saturating_sub(1)
+                .min(self.document.len().saturating_sub(1));
+            let (x, y) = Movement::FirstNonWhitespace.execute(0, y, &context);
+            return Position { x, y };
+        }
+        let (mut x, mut y) = (self.cursor_position.x, self.cursor_position.y);
+        for _ in 0..count.unwrap_or(1) {
+            (x, y) = movement.execute(x, y, &context);
+        }
+        Position { x, y }
+    }
+    fn move_cursor_times(&mut self, movement: Movement, count: Option<usize>) {
+        let y = self.cursor_position.y;
+        let Position { x: new_x, y: new_y } = self.motion_target(movement, count);
 
         let mut x_pos = new_x;
         let y_pos = new_y;
@@ -479,8 +2895,78 @@ impl Editor {
             x_pos = width;
         }
 
+        let desired = if movement.is_vertical() {
+            let desired = self.desired_column();
+            x_pos = self.column_to_index(desired, y_pos);
+            Some(desired)
+        } else if movement == Movement::EndOfLine {
+            Some(DesiredColumn::End)
+        } else {
+            None
+        };
+        self.desired_column = desired.map(|desired| (desired, Position { x: x_pos, y: y_pos }));
+
+        // Page motions scroll the text along with the cursor so that it
+        // keeps its place on the screen.
+        if movement.scrolls_view() {
+            let last_line = self.document.len().saturating_sub(1);
+            if y_pos > y {
+                self.offset.y = self.offset.y.saturating_add(y_pos - y).min(last_line);
+            } else {
+                self.offset.y = self.offset.y.saturating_sub(y - y_pos);
+            }
+        }
+
         self.cursor_position = Position { x: x_pos, y: y_pos }
     }
+    fn desired_column(&self) -> DesiredColumn {
+        match &self.desired_column {
+            Some((desired, position)) if *position == self.cursor_position => *desired,
+            _ => {
+                DesiredColumn::Column(self.document.row(self.cursor_position.y).map_or(0, |row| {
+                    row.display_column(self.cursor_position.x, self.options.tabstop)
+                }))
+            }
+        }
+    }
+    // Grapheme index on row `y` closest to the desired screen column. Only
+    // Insert mode may put the cursor past the last character.
+    fn column_to_index(&self, desired: DesiredColumn, y: usize) -> usize {
+        let len = self.document.row(y).map_or(0, Row::len);
+        let last = if self.mode == Mode::Insert {
+            len
+        } else {
+            len.saturating_sub(1)
+        };
+        match desired {
+            DesiredColumn::End => last,
+            DesiredColumn::Column(column) => self
+                .document
+                .row(y)
+                .map_or(0, |row| row.index_at_column(column, self.options.tabstop))
+                .min(last),
+        }
+    }
+    // Ctrl-e / Ctrl-y: scroll the text by one line, only moving the cursor
+    // when it would otherwise leave the screen.
+    fn scroll_view(&mut self, direction: SearchDirection) {
+        let height = self.terminal.size().height as usize;
+        let last_line = self.document.len().saturating_sub(1);
+        self.offset.y = match direction {
+            SearchDirection::Forward => self.offset.y.saturating_add(1).min(last_line),
+            SearchDirection::Backward => self.offset.y.saturating_sub(1),
+        };
+        let bottom = self.offset.y.saturating_add(height.saturating_sub(1));
+        let y = self
+            .cursor_position
+            .y
+            .clamp(self.offset.y, bottom.max(self.offset.y));
+        if y != self.cursor_position.y {
+            self.cursor_position.y = y;
+            let width = self.document.row(y).map_or(0, Row::len);
+            self.cursor_position.x = self.cursor_position.x.min(width);
+        }
+    }
     fn draw_welcome_message(&self) {
         let mut welcome_message = format!("Phantom editor -- version {}", VERSION);
         let width = self.terminal.size().width as usize;
@@ -492,26 +2978,51 @@ impl Editor {
         welcome_message.truncate(width);
         println!("{}\r", welcome_message);
     }
-    pub fn draw_row(&self, row: &Row, line_number: usize) {
+    pub fn draw_row(
+        &self,
+        row: &Row,
+        line_number: usize,
+        selected: Option<(usize, usize)>,
+        marker: Option<char>,
+    ) {
         let width = self.terminal.size().width as usize;
         let line_num_width = 4;
         
         // Print line number
-        print!("{:>4} ", line_number);
+        print!("{:>4}", line_number);
+        // The quickfix entry on the line, if any, in place of the space.
+        match marker {
+            Some(marker) => {
+                Terminal::set_fg_color(match marker {
+                    'E' => ERROR_COLOR,
+                    'W' => WARNING_COLOR,
+                    _ => NOTE_COLOR,
+                });
+                print!("{}", marker);
+                Terminal::reset_fg_color();
+            }
+            None => print!(" "),
+        }
         
         let start = self.offset.x;
         let end = self.offset.x.saturating_add(width.saturating_sub(line_num_width + 1));
-        let row = row.render(start, end);
+        let row = row.render(start, end, self.options.tabstop, selected);
         println!("{}\r", row)
     }
     #[allow(clippy::integer_division, clippy::integer_arithmetic)]
     fn draw_rows(&self) {
         let height = self.terminal.size().height;
+        let markers = match &self.document.file_name {
+            Some(file_name) if !self.quickfix.is_empty() => self.quickfix.markers(file_name),
+            _ => HashMap::new(),
+        };
         for terminal_row in 0..height {
             Terminal::clear_current_line();
             let file_row = self.offset.y.saturating_add(terminal_row as usize);
             if let Some(row) = self.document.row(file_row) {
-                self.draw_row(row, file_row.saturating_add(1));
+                let selected = self.selected_in_row(file_row);
+                let marker = markers.get(&file_row).copied();
+                self.draw_row(row, file_row.saturating_add(1), selected, marker);
             } else if self.document.is_empty() && terminal_row == height / 3 {
                 self.draw_welcome_message();
             } else {
@@ -519,9 +3030,79 @@ impl Editor {
             }
         }
     }
+    // The items matching the query on the left, best first, with the
+    // selected one previewed on the right.
+    #[allow(clippy::integer_division)]
+    fn draw_picker(&self, picker: &Picker) {
+        let height = self.terminal.size().height as usize;
+        let width = self.terminal.size().width as usize;
+        let list_width = width / 2;
+        let preview_width = width.saturating_sub(list_width).saturating_sub(1);
+        let top = picker.selected.saturating_sub(height.saturating_sub(1));
+        let preview = picker.selected_item().map_or_else(Vec::new, |item| {
+            self.preview(&item.target, height, preview_width)
+        });
+        let mut matches = picker.matches().skip(top);
+        for terminal_row in 0..height {
+            Terminal::clear_current_line();
+            let line = match matches.next() {
+                Some((item, positions)) => {
+                    let selected = top.saturating_add(terminal_row) == picker.selected;
+                    picker_line(item, positions, selected, list_width)
+                }
+                None => " ".repeat(list_width),
+            };
+            let preview = preview.get(terminal_row).map_or("", String::as_str);
+            println!("{}│{}\r", line, preview);
+        }
+    }
+    // The rendered lines of what the picker would go to: the start of a
+    // file, a buffer where its cursor was left, or the lines around one of
+    // the current buffer with that line selected.
+    fn preview(&self, target: &picker::Target, height: usize, width: usize) -> Vec<String> {
+        let render = |document: &Document, first: usize, selected: Option<usize>| {
+            (first..first.saturating_add(height))
+                .filter_map(|y| {
+                    let row = document.row(y)?;
+                    let selection = selected.filter(|line| *line == y).map(|_| (0, row.len()));
+                    Some(row.render(0, width, self.options.tabstop, selection))
+                })
+                .collect()
+        };
+        match target {
+            picker::Target::File(file_name) => match Document::open_head(file_name, height) {
+                Ok(mut document) => {
+                    document.highlight(None, 0, Some(height));
+                    render(&document, 0, None)
+                }
+                Err(_) => Vec::new(),
+            },
+            picker::Target::Buffer(number) if *number == self.buffer_number => {
+                render(&self.document, self.offset.y, None)
+            }
+            picker::Target::Buffer(number) => self
+                .hidden_buffers
+                .iter()
+                .find(|buffer| buffer.number == *number)
+                .map_or_else(Vec::new, |buffer| {
+                    render(&buffer.document, buffer.offset.y, None)
+                }),
+            picker::Target::Line(y) => {
+                render(&self.document, y.saturating_sub(height / 2), Some(*y))
+            }
+        }
+    }
     fn draw_status_bar(&self) {
         let mut status;
         let width = self.terminal.size().width as usize;
+        if let Some(menu) = &self.wildmenu {
+            Terminal::set_bg_color(STATUS_BG_COLOR);
+            Terminal::set_fg_color(STATUS_FG_COLOR);
+            println!("{}\r", menu.render(width));
+            Terminal::reset_fg_color();
+            Terminal::reset_bg_color();
+            return;
+        }
         let modified_indicator = if self.document.is_dirty() {
             " (modified)"
         } else {
@@ -529,15 +3110,16 @@ impl Editor {
         };
         
         let mode_indicator = match self.mode {
+            Mode::Normal if self.insert_after_command => "(INSERT)",
             Mode::Normal => "NORMAL",
             Mode::Insert => "INSERT",
+            Mode::Replace => "REPLACE",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "VISUAL LINE",
         };
 
-        let mut file_name = "[No Name]".to_string();
-        if let Some(name) = &self.document.file_name {
-            file_name = name.clone();
-            file_name.truncate(20);
-        }
+        let mut file_name = self.buffer_name(self.buffer_number, &self.document);
+        truncate_to_width(&mut file_name, 20);
         status = format!(
             "{} - {} lines{} - {}",
             file_name,
@@ -546,17 +3128,31 @@ impl Editor {
             mode_indicator
         );
 
+        let search_count = match &self.search_count {
+            Some((position, current, total)) if *position == self.cursor_position => {
+                let shown = |count: usize| {
+                    if count >= SEARCH_COUNT_LIMIT {
+                        format!(">{}", SEARCH_COUNT_LIMIT.saturating_sub(1))
+                    } else {
+                        count.to_string()
+                    }
+                };
+                format!("[{}/{}] ", shown(*current), shown(*total))
+            }
+            _ => String::new(),
+        };
         let line_indicator = format!(
-            "{} | {}/{}",
+            "{}{} | {}/{}",
+            search_count,
             self.document.file_type(),
             self.cursor_position.y.saturating_add(1),
             self.document.len()
         );
         #[allow(clippy::integer_arithmetic)]
-        let len = status.len() + line_indicator.len();
+        let len = status.width() + line_indicator.width();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
-        status.truncate(width);
+        truncate_to_width(&mut status, width);
         Terminal::set_bg_color(STATUS_BG_COLOR);
         Terminal::set_fg_color(STATUS_FG_COLOR);
         println!("{}\r", status);
@@ -568,41 +3164,237 @@ impl Editor {
         let message = &self.status_message;
         if Instant::now() - message.time < Duration::new(5, 0) {
             let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
+            truncate_to_width(&mut text, self.terminal.size().width as usize);
             print!("{}", text);
         }
     }
-    fn prompt<C>(&mut self, prompt: &str, mut callback: C) -> Result<Option<String>, std::io::Error>
+    // Reads a line on the message bar. `history` is the one Up and Down go
+    // through and that the line is added to; Tab completes ex commands.
+    fn prompt<C>(
+        &mut self,
+        prompt: &str,
+        initial: &str,
+        history: Option<Kind>,
+        mut callback: C,
+    ) -> Result<Option<String>, std::io::Error>
     where
         C: FnMut(&mut Self, Key, &String),
     {
-        let mut result = String::new();
+        let mut line = Line::new(initial);
+        // The history entry shown and the text it had to start with.
+        let mut browsing: Option<(usize, String)> = None;
         loop {
-            self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
+            self.status_message = StatusMessage::from(format!("{}{}", prompt, line.text()));
+            self.prompt_cursor = Some(prompt.width().saturating_add(line.cursor_width()));
             self.refresh_screen()?;
-            let key = Terminal::read_key()?;
+            let key = self.read_key()?;
+            if !matches!(key, Key::Up | Key::Down) {
+                browsing = None;
+            }
+            if !matches!(key, Key::Char('\t') | Key::Ctrl('n' | 'p')) {
+                self.wildmenu = None;
+            }
             match key {
-                Key::Backspace => result.truncate(result.len().saturating_sub(1)),
                 Key::Char('\n') => break,
-                Key::Char(c) => {
-                    if !c.is_control() {
-                        result.push(c);
-                    }
-                }
                 Key::Esc => {
-                    result.truncate(0);
+                    line.set("");
                     break;
                 }
+                // Like Vim, Backspace on an empty line leaves it.
+                Key::Backspace if line.text().is_empty() => break,
+                Key::Backspace => line.backspace(),
+                Key::Delete => line.delete(),
+                Key::Left => line.left(),
+                Key::Right => line.right(),
+                Key::Home | Key::Ctrl('b') => line.home(),
+                Key::End | Key::Ctrl('e') => line.end(),
+                Key::Ctrl('w') => line.delete_word(),
+                Key::Ctrl('u') => line.delete_to_start(),
+                Key::Up | Key::Down => {
+                    if let Some(kind) = history {
+                        browsing = self.browse_history(kind, &mut line, browsing, key == Key::Up);
+                    }
+                }
+                Key::Char('\t') if history == Some(Kind::Command) => {
+                    self.complete_command_line(&mut line, false);
+                }
+                Key::Ctrl('n' | 'p') if self.wildmenu.is_some() => {
+                    self.complete_command_line(&mut line, key == Key::Ctrl('p'));
+                }
+                Key::Char(c) if !c.is_control() => line.insert(c),
                 _ => (),
             }
-            callback(self, key, &result);
+            let text = line.text().to_string();
+            callback(self, key, &text);
         }
+        self.prompt_cursor = None;
+        self.wildmenu = None;
         self.status_message = StatusMessage::from(String::new());
+        let result = line.text().to_string();
         if result.is_empty() {
             return Ok(None);
         }
+        // Lines run by `:normal` are not typed, so they are not remembered.
+        if let (Some(kind), false) = (history, self.executing_keys) {
+            self.remember(kind, &result);
+        }
         Ok(Some(result))
     }
+    fn remember(&mut self, kind: Kind, entry: &str) {
+        self.history.add(kind, entry);
+        if let Some(file) = History::file() {
+            self.history.save(&file).ok();
+        }
+    }
+    // Up and Down at a prompt: the next older or newer history entry that
+    // starts with what was typed before the first of them.
+    fn browse_history(
+        &self,
+        kind: Kind,
+        line: &mut Line,
+        browsing: Option<(usize, String)>,
+        older: bool,
+    ) -> Option<(usize, String)> {
+        let (index, typed) = match browsing {
+            Some((index, typed)) => (Some(index), typed),
+            None => (None, line.text().to_string()),
+        };
+        let found = if older {
+            self.history.older(kind, index, &typed)
+        } else {
+            index.and_then(|index| self.history.newer(kind, index, &typed))
+        };
+        match found.and_then(|found| Some((found, self.history.entries(kind).get(found)?))) {
+            Some((found, entry)) => {
+                line.set(entry);
+                Some((found, typed))
+            }
+            // Going past the newest entry brings back the typed text.
+            None if !older => {
+                line.set(&typed);
+                None
+            }
+            None => index.map(|index| (index, typed)),
+        }
+    }
+    // Tab at the `:` prompt: the first completion, and while the wildmenu
+    // is shown the next one (or with Ctrl-p the previous one).
+    fn complete_command_line(&mut self, line: &mut Line, backward: bool) {
+        if let Some(menu) = &mut self.wildmenu {
+            let last = menu.candidates.len().saturating_sub(1);
+            menu.selected = match (backward, menu.selected) {
+                (true, 0) => last,
+                (true, selected) => selected.saturating_sub(1),
+                (false, selected) if selected >= last => 0,
+                (false, selected) => selected.saturating_add(1),
+            };
+            if let Some(candidate) = menu.candidates.get(menu.selected) {
+                line.replace_before_cursor(menu.start, candidate);
+            }
+            return;
+        }
+        let buffers: Vec<String> = std::iter::once(&self.document)
+            .chain(self.hidden_buffers.iter().map(|buffer| &buffer.document))
+            .filter_map(|document| document.file_name.clone())
+            .collect();
+        let (start, candidates) = match cmdline::complete(line.before_cursor(), &buffers) {
+            Some(completion) => completion,
+            None => return,
+        };
+        if let Some(first) = candidates.first() {
+            line.replace_before_cursor(start, first);
+        }
+        if candidates.len() > 1 {
+            self.wildmenu = Some(Wildmenu {
+                candidates,
+                selected: 0,
+                start,
+            });
+        }
+    }
+}
+
+// The number after `:cnext`, `:cc` and the like.
+fn quickfix_count(argument: &str, default: usize) -> Result<usize, String> {
+    if argument.is_empty() {
+        return Ok(default);
+    }
+    argument
+        .parse()
+        .map_err(|_| format!("Invalid argument: {}", argument))
+}
+
+fn toggle_case(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| {
+            if c.is_lowercase() {
+                c.to_uppercase().collect::<Vec<char>>()
+            } else {
+                c.to_lowercase().collect::<Vec<char>>()
+            }
+        })
+        .collect()
+}
+
+// `path` relative to the working directory when it is inside it.
+fn relative_path(path: &Path) -> String {
+    let directory = env::current_dir().unwrap_or_default();
+    path.strip_prefix(&directory)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+// An item of the picker filling `width` columns, with the characters the
+// query matched in the color of search matches.
+fn picker_line(item: &Item, positions: &[usize], selected: bool, width: usize) -> String {
+    let mut line = String::from(if selected { "> " } else { "  " });
+    line.push_str(&item.prefix);
+    truncate_to_width(&mut line, width);
+    let mut used = line.width();
+    for (index, c) in item.text.chars().enumerate() {
+        let c = if c.is_control() { ' ' } else { c };
+        used = used.saturating_add(c.width().unwrap_or(0));
+        if used > width {
+            used = used.saturating_sub(c.width().unwrap_or(0));
+            break;
+        }
+        if positions.contains(&index) {
+            line.push_str(&format!(
+                "{}{}{}",
+                color::Fg(highlighting::Type::Match.to_color()),
+                c,
+                color::Fg(color::Reset)
+            ));
+        } else {
+            line.push(c);
+        }
+    }
+    line.push_str(&" ".repeat(width.saturating_sub(used)));
+    line
+}
+// Cuts `text` so that it fits into `width` screen columns.
+fn truncate_to_width(text: &mut String, width: usize) {
+    let mut used: usize = 0;
+    for (index, c) in text.char_indices() {
+        used = used.saturating_add(c.width().unwrap_or(0));
+        if used > width {
+            text.truncate(index);
+            return;
+        }
+    }
+}
+
+// The key a character given to `:normal` stands for, as the terminal would
+// report it.
+fn key_from_char(c: char) -> Key {
+    match c {
+        '\x1b' => Key::Esc,
+        '\r' => Key::Char('\n'),
+        '\x7f' => Key::Backspace,
+        '\n' | '\t' => Key::Char(c),
+        '\x01'..='\x1a' => Key::Ctrl(char::from((c as u8).saturating_add(0x60))),
+        c => Key::Char(c),
+    }
 }
 
 fn die(e: std::io::Error) {
@@ -0,0 +1,276 @@
+use crate::quickfix::Entry;
+use regex::{Captures, Regex};
+
+// Reads the errors in compiler output for `:make`. 'errorformat' is a
+// comma separated list of built-in parsers (rustc, gcc, go, python, tsc)
+// and patterns like Vim's, where `%f` is the file, `%l` the line, `%c` the
+// column, `%t` the first letter of the error type, `%m` the message and
+// `%%` a percent sign. A `\,` is a comma inside a pattern.
+
+enum Format {
+    // `error[E0308]: message` with the location on a later `--> ` line.
+    Rustc,
+    // `File "a.py", line 3` frames with the exception on the last line.
+    Python,
+    Line(Regex),
+}
+
+const GCC: &str =
+    r"^(?P<f>[^:\s]+):(?P<l>\d+):(?:(?P<c>\d+):)? (?:fatal )?(?P<t>error|warning|note): (?P<m>.*)$";
+const GO: &str = r"^(?P<f>[^:\s]+\.go):(?P<l>\d+)(?::(?P<c>\d+))?: (?P<m>.*)$";
+const TSC: &str = r"^(?P<f>[^(\s]+)\((?P<l>\d+),(?P<c>\d+)\): (?P<t>error|warning) (?P<m>.*)$";
+const TSC_PRETTY: &str = r"^(?P<f>[^:\s]+):(?P<l>\d+):(?P<c>\d+) - (?P<t>error|warning) (?P<m>.*)$";
+
+fn formats(errorformat: &str) -> Result<Vec<Format>, String> {
+    let mut formats = Vec::new();
+    for item in split_items(errorformat) {
+        let line = |pattern: &str| Regex::new(pattern).map(Format::Line);
+        let format = match item.as_str() {
+            "" => continue,
+            "rustc" | "cargo" => Format::Rustc,
+            "python" => Format::Python,
+            "gcc" | "clang" => line(GCC).map_err(|error| error.to_string())?,
+            "go" => line(GO).map_err(|error| error.to_string())?,
+            "tsc" => {
+                formats.push(line(TSC).map_err(|error| error.to_string())?);
+                line(TSC_PRETTY).map_err(|error| error.to_string())?
+            }
+            pattern if pattern.contains('%') => Format::Line(translate(pattern)?),
+            name => return Err(format!("Unknown errorformat: {}", name)),
+        };
+        formats.push(format);
+    }
+    Ok(formats)
+}
+
+fn split_items(errorformat: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = errorformat.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(',') => item.push(','),
+                Some(next) => {
+                    item.push(c);
+                    item.push(next);
+                }
+                None => item.push(c),
+            },
+            ',' => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    items.push(item);
+    items
+}
+
+// A pattern of 'errorformat' as a regex matching a whole line.
+fn translate(pattern: &str) -> Result<Regex, String> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+            continue;
+        }
+        match chars.next() {
+            Some('f') => regex.push_str(r"(?P<f>.+?)"),
+            Some('l') => regex.push_str(r"(?P<l>\d+)"),
+            Some('c') => regex.push_str(r"(?P<c>\d+)"),
+            Some('t') => regex.push_str(r"(?P<t>\w)"),
+            Some('m') => regex.push_str(r"(?P<m>.*)"),
+            Some('%') => regex.push('%'),
+            Some(other) => return Err(format!("Invalid %{} in errorformat", other)),
+            None => return Err("Invalid % at the end of errorformat".to_string()),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|error| error.to_string())
+}
+
+// The quickfix entry a line matched by a pattern stands for. Lines without
+// a `%t` are errors.
+fn entry(captures: &Captures) -> Option<Entry> {
+    let number = |name| {
+        captures
+            .name(name)
+            .and_then(|found| found.as_str().parse::<usize>().ok())
+    };
+    Some(Entry {
+        file_name: captures.name("f")?.as_str().to_string(),
+        line: number("l")?.saturating_sub(1),
+        column: number("c").unwrap_or(1).saturating_sub(1),
+        text: captures
+            .name("m")
+            .map_or("", |found| found.as_str())
+            .to_string(),
+        kind: Some(kind(captures.name("t").map_or("e", |found| found.as_str()))),
+    })
+}
+
+fn kind(word: &str) -> char {
+    word.chars().next().map_or('E', |c| c.to_ascii_uppercase())
+}
+
+pub fn parse(output: &str, errorformat: &str) -> Result<Vec<Entry>, String> {
+    let formats = formats(errorformat)?;
+    let rustc_message = Regex::new(r"^(?P<t>error|warning)(?:\[\w+\])?: (?P<m>.*)$")
+        .map_err(|error| error.to_string())?;
+    let rustc_location = Regex::new(r"^\s*--> (?P<f>.+):(?P<l>\d+):(?P<c>\d+)$")
+        .map_err(|error| error.to_string())?;
+    let python_frame = Regex::new(r#"^\s*File "(?P<f>[^"]+)", line (?P<l>\d+)"#)
+        .map_err(|error| error.to_string())?;
+    let mut entries = Vec::new();
+    // The rustc message waiting for its location.
+    let mut message: Option<(char, String)> = None;
+    // The Python frames waiting for the exception.
+    let mut frames: Vec<Entry> = Vec::new();
+    for line in output.lines() {
+        for format in &formats {
+            let found = match format {
+                Format::Rustc => {
+                    if let Some(captures) = rustc_message.captures(line) {
+                        message = Some((kind(&captures["t"]), captures["m"].to_string()));
+                        true
+                    } else if let Some(captures) = rustc_location.captures(line) {
+                        match (message.take(), entry(&captures)) {
+                            (Some((kind, text)), Some(entry)) => {
+                                entries.push(Entry {
+                                    text,
+                                    kind: Some(kind),
+                                    ..entry
+                                });
+                                true
+                            }
+                            _ => false,
+                        }
+                    } else {
+                        false
+                    }
+                }
+                Format::Python => {
+                    if line.starts_with("Traceback ") {
+                        frames.clear();
+                        true
+                    } else if let Some(captures) = python_frame.captures(line) {
+                        frames.extend(entry(&captures));
+                        true
+                    } else if !frames.is_empty() && !line.starts_with(char::is_whitespace) {
+                        entries.extend(frames.drain(..).map(|frame| Entry {
+                            text: line.trim().to_string(),
+                            ..frame
+                        }));
+                        true
+                    } else {
+                        false
+                    }
+                }
+                Format::Line(regex) => regex
+                    .captures(line)
+                    .and_then(|captures| entry(&captures))
+                    .map(|entry| entries.push(entry))
+                    .is_some(),
+            };
+            if found {
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn parsed(
+        output: &str,
+        errorformat: &str,
+    ) -> Vec<(String, usize, usize, Option<char>, String)> {
+        parse(output, errorformat)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.file_name,
+                    entry.line,
+                    entry.column,
+                    entry.kind,
+                    entry.text,
+                )
+            })
+            .collect()
+    }
+
+    fn found(
+        file_name: &str,
+        line: usize,
+        column: usize,
+        kind: char,
+        text: &str,
+    ) -> (String, usize, usize, Option<char>, String) {
+        (
+            file_name.to_string(),
+            line,
+            column,
+            Some(kind),
+            text.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_rustc() {
+        let output = "   Compiling phantom v1.0.0\n\
+            warning: unused variable: `x`\n  --> src/main.rs:3:9\n   |\n\
+            error[E0308]: mismatched types\n   --> src/editor.rs:10:5\n    |\n\
+            note: expected here\n  --> src/row.rs:1:1\n\
+            error: aborting due to 1 previous error\n";
+        assert_eq!(
+            parsed(output, "rustc"),
+            [
+                found("src/main.rs", 2, 8, 'W', "unused variable: `x`"),
+                found("src/editor.rs", 9, 4, 'E', "mismatched types")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_builtin() {
+        let output = "main.c:4:12: error: expected ';'\n\
+            main.c:9: warning: unused\n\
+            ./cmd/main.go:7:2: undefined: foo\n\
+            src/app.ts(3,7): error TS2322: Type 'string' is not assignable\n\
+            src/app.ts:5:1 - warning TS6133: 'x' is declared\n\
+            Traceback (most recent call last):\n  File \"app.py\", line 8, in <module>\n    main()\n\
+            \x20 File \"lib.py\", line 2, in main\n    1 / 0\nZeroDivisionError: division by zero\n";
+        assert_eq!(
+            parsed(output, "rustc,gcc,go,python,tsc"),
+            [
+                found("main.c", 3, 11, 'E', "expected ';'"),
+                found("main.c", 8, 0, 'W', "unused"),
+                found("./cmd/main.go", 6, 1, 'E', "undefined: foo"),
+                found(
+                    "src/app.ts",
+                    2,
+                    6,
+                    'E',
+                    "TS2322: Type 'string' is not assignable"
+                ),
+                found("src/app.ts", 4, 0, 'W', "TS6133: 'x' is declared"),
+                found("app.py", 7, 0, 'E', "ZeroDivisionError: division by zero"),
+                found("lib.py", 1, 0, 'E', "ZeroDivisionError: division by zero")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(
+            parsed("x.txt line 3: %oops, bad", "%f line %l: %%%m\\, %tad"),
+            [found("x.txt", 2, 0, 'B', "oops")]
+        );
+        assert!(parse("", "%f:%q").is_err());
+        assert!(parse("", "bogus").is_err());
+    }
+}
@@ -0,0 +1,525 @@
+use crate::registers::Registers;
+use crate::search::{self, Settings};
+use crate::Position;
+use std::collections::HashMap;
+
+// Parses ex command lines, `[range]name[!] [argument]`, where the range is
+// one or two addresses such as `%`, `.,$`, `'a,'b`, `/foo/+1;?bar?` or
+// `'<,'>`.
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Base {
+    Current,
+    Last,
+    Line(usize),
+    Mark(char),
+    Forward(String),
+    Backward(String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Address {
+    pub base: Base,
+    pub offset: isize,
+    // An address after `;` is counted from the one before it instead of
+    // from the cursor line.
+    pub from_previous: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Range {
+    pub addresses: Vec<Address>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExCommand {
+    pub range: Range,
+    pub name: String,
+    pub bang: bool,
+    pub argument: String,
+}
+
+#[derive(Clone, Copy)]
+pub struct Context<'a> {
+    // Cursor line, counting from 0.
+    pub current: usize,
+    pub lines: &'a [String],
+    pub marks: &'a HashMap<char, Position>,
+    pub last_search: Option<&'a str>,
+    pub search: Settings,
+}
+
+const INVALID_RANGE: &str = "Invalid range";
+
+// Every command by its full name and shortest abbreviation, with the usage
+// `:help` shows for it.
+#[rustfmt::skip]
+pub const COMMANDS: &[(&str, &str, &str)] = &[
+    ("BLines", "BLines", ":BLines  pick a line of this buffer"),
+    ("Buffers", "Buffers", ":Buffers  pick a buffer"),
+    ("Explore", "Ex", ":Ex[plore] [dir]  list a directory, by default the one of this file"),
+    ("Files", "Files", ":Files [dir]  pick a file under the directory"),
+    ("History", "History", ":History  pick a recently opened file"),
+    ("buffer", "b", ":b[uffer] {N|name}  show another buffer"),
+    ("bnext", "bn", ":bn[ext]  show the next buffer"),
+    ("bprevious", "bp", ":bp[revious]  show the previous buffer"),
+    ("bNext", "bN", ":bN[ext]  same as :bprevious"),
+    ("buffers", "buffers", ":buffers, :ls  list the buffers"),
+    ("cc", "cc", ":cc [N]  go to quickfix entry N, or the current one"),
+    ("cd", "cd", ":cd [dir|-]  change the working directory"),
+    ("chdir", "chd", ":chd[ir] [dir|-]  same as :cd"),
+    ("cdo", "cdo", ":cdo {cmd}  run a command at every quickfix entry"),
+    ("cfirst", "cfir", ":cfir[st] [N]  go to the first quickfix entry"),
+    ("clast", "cla", ":cla[st] [N]  go to the last quickfix entry"),
+    ("cnext", "cn", ":cn[ext] [N]  go to the next quickfix entry"),
+    ("copen", "cope", ":cope[n]  show the quickfix list"),
+    ("copy", "co", ":[range]co[py] {address}, :t  copy lines below the address"),
+    ("cprevious", "cp", ":cp[revious] [N], :cN[ext]  go to the previous quickfix entry"),
+    ("cNext", "cN", ":cN[ext] [N]  same as :cprevious"),
+    ("crewind", "cr", ":cr[ewind] [N]  same as :cfirst"),
+    ("delete", "d", ":[range]d[elete] [x] [count]  delete lines"),
+    ("edit", "e", ":e[dit][!] [file]  open a file, or reload this one"),
+    ("files", "files", ":files  same as :buffers"),
+    ("global", "g", ":[range]g[lobal][!]/pattern/[command]  run on matching lines"),
+    ("grep", "gr", ":gr[ep][!] {pattern} [path]...  search files into the quickfix list"),
+    ("help", "h", ":h[elp] [command]  show the usage of a command"),
+    ("lcd", "lcd", ":lcd [dir|-]  same as :cd"),
+    ("lchdir", "lch", ":lch[dir] [dir|-]  same as :cd"),
+    ("ls", "ls", ":ls  list the buffers"),
+    ("make", "mak", ":mak[e][!] [args]  run 'makeprg' and read its errors into the quickfix list"),
+    ("move", "m", ":[range]m[ove] {address}  move lines below the address"),
+    ("nohlsearch", "noh", ":noh[lsearch]  stop highlighting matches until the next search"),
+    ("normal", "norm", ":[range]norm[al] {keys}  run Normal mode keys on lines"),
+    ("pwd", "pw", ":pw[d]  show the working directory"),
+    ("qall", "qa", ":qa[ll][!]  quit, checking every buffer"),
+    ("quit", "q", ":q[uit][!]  quit"),
+    ("quitall", "quita", ":quita[ll][!]  same as :qall"),
+    ("read", "r", ":[line]r[ead] {file|!cmd}  insert a file or command output"),
+    ("saveas", "sav", ":sav[eas][!] {file}  save under another name"),
+    ("set", "se", ":se[t] {option}...  change or show options"),
+    ("sort", "sor", ":[range]sor[t][!] [n][i][u][r] [/pattern/]  sort lines"),
+    ("substitute", "s", ":[range]s[ubstitute]/pattern/replacement/[flags] [count]"),
+    ("t", "t", ":[range]t {address}  copy lines below the address"),
+    ("update", "up", ":up[date]  save if there are changes"),
+    ("vglobal", "v", ":[range]v[global]/pattern/[command]  run on other lines"),
+    ("vimgrep", "vim", ":vim[grep] /pattern/[g][j] [path]...  search files into the quickfix list"),
+    ("wall", "wa", ":wa[ll]  save every changed buffer"),
+    ("wq", "wq", ":[range]wq[!] [file]  save and quit"),
+    ("wqall", "wqa", ":wqa[ll]  save every changed buffer and quit"),
+    ("xall", "xa", ":xa[ll]  same as :wqall"),
+    ("write", "w", ":[range]w[rite][!] [>>] [file|!cmd]  save or write lines"),
+    ("xit", "x", ":[range]x[it][!] [file]  save if changed and quit"),
+    ("yank", "y", ":[range]y[ank] [x] [count]  yank lines"),
+];
+
+// The full name of a command given by any abbreviation at least as long as
+// its shortest one.
+pub fn full_name(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|(full, short, _)| full.starts_with(name) && name.starts_with(short))
+        .map(|(full, _, _)| *full)
+}
+
+pub fn usage(name: &str) -> Option<&'static str> {
+    let name = full_name(name)?;
+    COMMANDS
+        .iter()
+        .find(|(full, _, _)| *full == name)
+        .map(|(_, _, usage)| *usage)
+}
+
+// Where the command name starts in `line`, after the range, or None when
+// the range is invalid.
+pub fn name_start(line: &str) -> Option<usize> {
+    let mut input = line.trim_start_matches(|c: char| c == ':' || c.is_whitespace());
+    parse_range(&mut input).ok()?;
+    Some(line.len().saturating_sub(input.trim_start().len()))
+}
+
+pub fn parse(line: &str) -> Result<ExCommand, String> {
+    let mut input = line.trim_start_matches(|c: char| c == ':' || c.is_whitespace());
+    let range = parse_range(&mut input)?;
+    let input = input.trim_start();
+    let name_len = match input.find(|c: char| !c.is_ascii_alphabetic()) {
+        Some(0) => input.chars().next().map_or(0, char::len_utf8),
+        Some(len) => len,
+        None => input.len(),
+    };
+    let (name, mut rest) = input.split_at(name_len);
+    let bang = name.starts_with(|c: char| c.is_ascii_alphabetic()) && rest.starts_with('!');
+    if bang {
+        rest = &rest[1..];
+    }
+    Ok(ExCommand {
+        range,
+        name: name.to_string(),
+        bang,
+        argument: rest.trim_start().to_string(),
+    })
+}
+
+// A missing address next to a separator means the cursor line, so `,5` is
+// `.,5` and `5;` is `5;.`.
+fn parse_range(input: &mut &str) -> Result<Range, String> {
+    let mut addresses = Vec::new();
+    if let Some(rest) = input.trim_start().strip_prefix('%') {
+        *input = rest;
+        addresses.push(Address::new(Base::Line(1)));
+        addresses.push(Address::new(Base::Last));
+        return Ok(Range { addresses });
+    }
+    let mut from_previous = false;
+    let mut after_separator = false;
+    loop {
+        let address = parse_address(input)?;
+        *input = input.trim_start();
+        let separator = input.chars().next().filter(|c| *c == ',' || *c == ';');
+        if address.is_none() && separator.is_none() && !after_separator {
+            break;
+        }
+        let mut address = address.unwrap_or_else(|| Address::new(Base::Current));
+        address.from_previous = from_previous;
+        addresses.push(address);
+        match separator {
+            Some(separator) => {
+                *input = &input[1..];
+                from_previous = separator == ';';
+                after_separator = true;
+            }
+            None => break,
+        }
+    }
+    Ok(Range { addresses })
+}
+
+fn parse_address(input: &mut &str) -> Result<Option<Address>, String> {
+    *input = input.trim_start();
+    let mut chars = input.chars();
+    let base = match chars.next() {
+        Some('.') => Some(Base::Current),
+        Some('$') => Some(Base::Last),
+        Some('\'') => match chars.next() {
+            Some(mark) => Some(Base::Mark(mark)),
+            None => return Err(INVALID_RANGE.to_string()),
+        },
+        Some(delimiter @ ('/' | '?')) => {
+            let (pattern, rest) = split_pattern(chars.as_str(), delimiter);
+            chars = rest.chars();
+            if delimiter == '/' {
+                Some(Base::Forward(pattern))
+            } else {
+                Some(Base::Backward(pattern))
+            }
+        }
+        _ => None,
+    };
+    let base = match base {
+        Some(base) => {
+            *input = chars.as_str();
+            Some(base)
+        }
+        None if input.starts_with(|c: char| c.is_ascii_digit()) => {
+            Some(Base::Line(take_number(input)))
+        }
+        None => None,
+    };
+    // Any number of offsets may follow; a bare number after a base adds to
+    // it and a sign without a number means one.
+    let mut offset: isize = 0;
+    let mut has_offset = false;
+    loop {
+        let sign = match input.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            Some(c) if c.is_ascii_digit() && base.is_some() => 0,
+            _ => break,
+        };
+        if sign != 0 {
+            *input = &input[1..];
+        }
+        let number = if input.starts_with(|c: char| c.is_ascii_digit()) {
+            take_number(input)
+        } else {
+            1
+        };
+        let number = isize::try_from(number).unwrap_or(isize::MAX);
+        offset = if sign < 0 {
+            offset.saturating_sub(number)
+        } else {
+            offset.saturating_add(number)
+        };
+        has_offset = true;
+    }
+    if base.is_none() && !has_offset {
+        return Ok(None);
+    }
+    Ok(Some(Address {
+        base: base.unwrap_or(Base::Current),
+        offset,
+        from_previous: false,
+    }))
+}
+
+// The address `:m` and `:t` take as their argument.
+pub fn parse_target(argument: &str) -> Result<Address, String> {
+    let mut input = argument.trim();
+    match parse_address(&mut input)? {
+        Some(address) if input.trim().is_empty() => Ok(address),
+        Some(_) => Err(format!("Trailing characters: {}", input.trim())),
+        None => Err(INVALID_RANGE.to_string()),
+    }
+}
+
+fn take_number(input: &mut &str) -> usize {
+    let digits = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let number = input[..digits].parse().unwrap_or(usize::MAX);
+    *input = &input[digits..];
+    number
+}
+
+// Splits a pattern ended by `delimiter` off `input`; `\` followed by the
+// delimiter stands for the delimiter itself. The closing delimiter may be
+// left out at the end of the line.
+pub fn split_pattern(input: &str, delimiter: char) -> (String, &str) {
+    let mut pattern = String::new();
+    let mut chars = input.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c == delimiter {
+            return (pattern, &input[index.saturating_add(c.len_utf8())..]);
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => pattern.push(next),
+                Some((_, next)) => {
+                    pattern.push(c);
+                    pattern.push(next);
+                }
+                None => pattern.push(c),
+            }
+        } else {
+            pattern.push(c);
+        }
+    }
+    (pattern, "")
+}
+
+// Splits the `[x] [count]` argument of commands like `:d` and `:y`.
+pub fn register_and_count(argument: &str) -> Result<(Option<char>, Option<usize>), String> {
+    let argument = argument.trim();
+    let (register, rest) = match argument.chars().next() {
+        Some(c) if !c.is_ascii_digit() && Registers::is_valid(c) => {
+            (Some(c), argument[c.len_utf8()..].trim_start())
+        }
+        _ => (None, argument),
+    };
+    let count = match rest {
+        "" => None,
+        rest => Some(
+            rest.parse::<usize>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| format!("Trailing characters: {}", rest))?,
+        ),
+    };
+    Ok((register, count))
+}
+
+// With a count, a command works on that many lines starting at the last
+// line of its range.
+pub fn with_count((first, last): (usize, usize), count: Option<usize>) -> (usize, usize) {
+    match count {
+        Some(count) => (last, last.saturating_add(count.saturating_sub(1))),
+        None => (first, last),
+    }
+}
+
+impl Address {
+    fn new(base: Base) -> Self {
+        Self {
+            base,
+            offset: 0,
+            from_previous: false,
+        }
+    }
+    // Line number the address points to, counting from 1. Line 0 is the
+    // position before the first line, used by commands that put text below
+    // an address.
+    pub fn line(&self, context: &Context) -> Result<usize, String> {
+        // Like Vim, an empty buffer still has one (empty) line.
+        let last = context.lines.len().max(1);
+        let base = match &self.base {
+            Base::Current => context.current.saturating_add(1),
+            Base::Last => last,
+            Base::Line(line) => *line,
+            Base::Mark(mark) => context
+                .marks
+                .get(mark)
+                .map(|position| position.y.saturating_add(1).min(last))
+                .ok_or_else(|| "Mark not set".to_string())?,
+            Base::Forward(pattern) => search(context, pattern, true)?,
+            Base::Backward(pattern) => search(context, pattern, false)?,
+        };
+        let line = if self.offset < 0 {
+            base.checked_sub(self.offset.unsigned_abs())
+        } else {
+            base.checked_add(self.offset.unsigned_abs())
+        };
+        match line {
+            Some(line) if line <= last => Ok(line),
+            _ => Err(INVALID_RANGE.to_string()),
+        }
+    }
+}
+
+// Finds the next line matching `pattern` after (or before) the cursor
+// line, wrapping around the end of the buffer. An empty pattern repeats the
+// last search.
+#[allow(clippy::integer_arithmetic)]
+fn search(context: &Context, pattern: &str, forward: bool) -> Result<usize, String> {
+    let pattern = match (pattern, context.last_search) {
+        ("", Some(last_search)) => last_search,
+        ("", None) => return Err("No previous search pattern".to_string()),
+        (pattern, _) => pattern,
+    };
+    let regex = search::compile(pattern, context.search)?;
+    let len = context.lines.len();
+    (1..=len)
+        .map(|step| {
+            if forward {
+                (context.current + step) % len
+            } else {
+                (context.current + len - step % len) % len
+            }
+        })
+        .find(|index| regex.is_match(&context.lines[*index]))
+        .map(|index| index + 1)
+        .ok_or_else(|| format!("Pattern not found: {}", pattern))
+}
+
+impl Range {
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+    // The first and last line of the range, counting from 0, or None when
+    // no address was given. Only the last two addresses count; a backwards
+    // range is swapped.
+    pub fn resolve(&self, context: &Context) -> Result<Option<(usize, usize)>, String> {
+        let mut lines: Vec<usize> = Vec::new();
+        for address in &self.addresses {
+            let current = match (address.from_previous, lines.last()) {
+                (true, Some(previous)) => previous.saturating_sub(1),
+                _ => context.current,
+            };
+            lines.push(address.line(&Context {
+                current,
+                ..*context
+            })?);
+        }
+        let (first, last) = match lines.as_slice() {
+            [] => return Ok(None),
+            [line] => (*line, *line),
+            [.., first, last] => (*first.min(last), *first.max(last)),
+        };
+        Ok(Some((first.saturating_sub(1), last.saturating_sub(1))))
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn lines() -> Vec<String> {
+        [
+            "fn main() {",
+            "    foo();",
+            "    bar();",
+            "}",
+            "fn foo() {}",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+    }
+
+    fn resolve(range: &str, current: usize) -> Result<Option<(usize, usize)>, String> {
+        let lines = lines();
+        let mut marks = HashMap::new();
+        marks.insert('a', Position { x: 3, y: 1 });
+        let context = Context {
+            current,
+            lines: &lines,
+            marks: &marks,
+            last_search: Some("bar"),
+            search: Settings::default(),
+        };
+        parse(range)?.range.resolve(&context)
+    }
+
+    #[test]
+    fn test_parse() {
+        let command = parse(":'<,'>s/a\\/b/c/g").unwrap();
+        assert_eq!(command.range.addresses.len(), 2);
+        assert_eq!(command.range.addresses[0].base, Base::Mark('<'));
+        assert_eq!(command.name, "s");
+        assert_eq!(command.argument, "/a\\/b/c/g");
+        let command = parse("q!").unwrap();
+        assert!(command.range.is_empty());
+        assert_eq!((command.name.as_str(), command.bang), ("q", true));
+        let command = parse("w !sort").unwrap();
+        assert_eq!((command.name.as_str(), command.bang), ("w", false));
+        assert_eq!(command.argument, "!sort");
+        let command = parse(".,$!sort").unwrap();
+        assert_eq!(
+            (command.name.as_str(), command.argument.as_str()),
+            ("!", "sort")
+        );
+        let command = parse("/foo\\/bar/+2d").unwrap();
+        assert_eq!(
+            command.range.addresses[0],
+            Address {
+                base: Base::Forward("foo/bar".to_string()),
+                offset: 2,
+                from_previous: false,
+            }
+        );
+        assert_eq!(command.name, "d");
+        assert_eq!(register_and_count(" a 3"), Ok((Some('a'), Some(3))));
+        assert_eq!(register_and_count("5"), Ok((None, Some(5))));
+        assert!(register_and_count("a b").is_err());
+        assert_eq!(full_name("sav"), Some("saveas"));
+        assert_eq!(full_name("s"), Some("substitute"));
+        assert_eq!(full_name("sa"), None);
+        assert_eq!(full_name("glo"), Some("global"));
+        assert_eq!(full_name("norma"), Some("normal"));
+        assert_eq!(full_name("wri"), Some("write"));
+        assert_eq!(full_name("x"), Some("xit"));
+        assert_eq!(full_name("cN"), Some("cNext"));
+        assert_eq!(name_start(":'<,'>s/a/b/"), Some(6));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("", 2), Ok(None));
+        assert_eq!(resolve("%", 2), Ok(Some((0, 4))));
+        assert_eq!(resolve(".,$", 2), Ok(Some((2, 4))));
+        assert_eq!(resolve("3", 0), Ok(Some((2, 2))));
+        assert_eq!(resolve(".+1,+2", 0), Ok(Some((1, 2))));
+        assert_eq!(resolve("$-2,.4", 0), Ok(Some((2, 4))));
+        assert_eq!(resolve("-", 3), Ok(Some((2, 2))));
+        assert_eq!(resolve("'a,", 3), Ok(Some((1, 3))));
+        assert_eq!(resolve("/fn/", 0), Ok(Some((4, 4))));
+        assert_eq!(resolve("/fn/", 4), Ok(Some((0, 0))));
+        assert_eq!(resolve("/^\\s\\+b/", 0), Ok(Some((2, 2))));
+        assert_eq!(resolve("?foo?", 3), Ok(Some((1, 1))));
+        assert_eq!(resolve("//", 0), Ok(Some((2, 2))));
+        assert_eq!(resolve("2;+1", 4), Ok(Some((1, 2))));
+        assert!(resolve("2,+1", 4).is_err());
+        assert_eq!(resolve("4,2", 0), Ok(Some((1, 3))));
+        assert!(resolve("7", 0).is_err());
+        assert!(resolve("'b", 0).is_err());
+        assert!(resolve("/nothing/", 0).is_err());
+    }
+}
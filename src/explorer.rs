@@ -0,0 +1,106 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// The directory listing `:e` shows for a directory, like Vim's netrw: one
+// entry per line, `../` first, then directories ending in `/` and then
+// files, each sorted by name.
+
+pub fn list(directory: &Path) -> io::Result<Vec<String>> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Following symbolic links, so a link to a directory can be entered.
+        if entry.path().is_dir() {
+            directories.push(format!("{}/", name));
+        } else {
+            files.push(name);
+        }
+    }
+    directories.sort();
+    files.sort();
+    let parent = directory.parent().map(|_| "../".to_string());
+    Ok(parent.into_iter().chain(directories).chain(files).collect())
+}
+
+// What an entry of the listing of `directory` stands for, or None for a
+// line that is not one.
+pub fn entry_path(directory: &Path, entry: &str) -> Option<PathBuf> {
+    match entry.trim_end_matches('/') {
+        "" | "." => None,
+        ".." => directory.parent().map(Path::to_path_buf),
+        name => Some(directory.join(name)),
+    }
+}
+
+// Makes an empty file, or a directory with its missing parents, failing
+// when there already is something by that name.
+pub fn create(path: &Path, directory: bool) -> io::Result<()> {
+    if directory {
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists"));
+        }
+        fs::create_dir_all(path)
+    } else {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map(|_| ())
+    }
+}
+
+// Renames `from`, without replacing what might be at `to`.
+pub fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists"));
+    }
+    fs::rename(from, to)
+}
+
+// Deletes a file, or a directory only when it is empty, like netrw.
+pub fn delete(path: &Path) -> io::Result<()> {
+    if path.is_dir() && !path.is_symlink() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::files::Scratch;
+
+    #[test]
+    fn test_explorer() {
+        let scratch = Scratch::new("explorer");
+        let root = scratch.path();
+        fs::create_dir_all(root.join("src")).unwrap();
+        scratch.write("b.txt", "");
+        scratch.write("A.md", "");
+        assert_eq!(list(root).unwrap(), ["../", "src/", "A.md", "b.txt"]);
+        assert_eq!(entry_path(root, "src/"), Some(root.join("src")));
+        assert_eq!(
+            entry_path(root, "../"),
+            root.parent().map(Path::to_path_buf)
+        );
+        assert_eq!(entry_path(root, ""), None);
+
+        create(&root.join("src/new/deep"), true).unwrap();
+        create(&root.join("src/new/c.rs"), false).unwrap();
+        assert!(create(&root.join("src/new/c.rs"), false).is_err());
+        assert!(create(&root.join("src/new"), true).is_err());
+        assert!(rename(&root.join("b.txt"), &root.join("A.md")).is_err());
+        rename(&root.join("b.txt"), &root.join("src/b.txt")).unwrap();
+        assert_eq!(list(&root.join("src")).unwrap(), ["../", "new/", "b.txt"]);
+        // Only empty directories are deleted.
+        assert!(delete(&root.join("src/new")).is_err());
+        delete(&root.join("src/new/c.rs")).unwrap();
+        delete(&root.join("src/new/deep")).unwrap();
+        delete(&root.join("src/new")).unwrap();
+        assert_eq!(list(&root.join("src")).unwrap(), ["../", "b.txt"]);
+    }
+}
@@ -0,0 +1,112 @@
+use ignore::WalkBuilder;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Telling whether two names are the same file, so that `./a.rs`, `a.rs` and
+// `/home/me/a.rs` share a buffer, and going through the files of a project
+// for `:grep` and the picker.
+
+// `path` made absolute with symbolic links resolved. For a file that does
+// not exist yet, only its directory is resolved, or when that does not
+// exist either, `.` and `..` are taken out of the absolute path.
+pub fn canonical(path: &Path) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+    let absolute = env::current_dir().map_or_else(|_| path.to_path_buf(), |dir| dir.join(path));
+    let mut normal = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    match (normal.parent(), normal.file_name()) {
+        (Some(parent), Some(name)) => {
+            fs::canonicalize(parent).map_or_else(|_| normal.clone(), |parent| parent.join(name))
+        }
+        _ => normal,
+    }
+}
+
+pub fn same_file(a: &str, b: &str) -> bool {
+    a == b || canonical(Path::new(a)) == canonical(Path::new(b))
+}
+
+// Every file under `paths`, or the working directory when there are none,
+// sorted by name within each directory and leaving out what `.gitignore`
+// files and the like exclude.
+pub fn walk(paths: &[String]) -> impl Iterator<Item = PathBuf> {
+    let mut paths = paths.iter();
+    let mut builder = WalkBuilder::new(paths.next().map_or(".", String::as_str));
+    for path in paths {
+        builder.add(path);
+    }
+    builder
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b));
+    builder
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_file())
+        })
+        .map(|entry| {
+            let path = entry.path();
+            path.strip_prefix(".").unwrap_or(path).to_path_buf()
+        })
+}
+
+// A directory for a test's files, removed when the test ends whether it
+// passed or not.
+#[cfg(test)]
+pub struct Scratch(PathBuf);
+
+#[cfg(test)]
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("phantom-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    // Writes the file `name`, making the directories it is in.
+    pub fn write(&self, name: &str, contents: &str) {
+        let path = self.0.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, contents).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_same_file() {
+        assert!(same_file("src/main.rs", "./src/main.rs"));
+        assert!(same_file("src/main.rs", "src/../src/main.rs"));
+        let absolute = env::current_dir().unwrap().join("src/main.rs");
+        assert!(same_file(&absolute.display().to_string(), "src/main.rs"));
+        assert!(same_file("src/new.rs", "./src/new.rs"));
+        assert!(same_file("no/such/dir/a.rs", "no/such/./dir/a.rs"));
+        assert!(!same_file("src/main.rs", "src/row.rs"));
+    }
+}
@@ -1,24 +1,347 @@
+use crate::IsKeyword;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::Regex;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Languages are described by TOML files like those in syntax/, which are
+// built in. Files in ~/.config/phantom/syntax/ (or under $XDG_CONFIG_HOME)
+// add languages, or replace a built-in one when they have the same file
+// name; the name without `.toml` is what `:set filetype=` takes.
+//
+//     name = "Rust"                  # shown in the status bar
+//     filenames = ["Cargo.lock"]     # whole names, tried first
+//     globs = ["*.rs.in"]            # then these, on the name or path
+//     extensions = ["rs"]
+//     shebangs = ["rust-script"]     # interpreters after `#!`
+//     content = ['^#!\[']            # regexes for the first lines
+//     iskeyword = "@,48-57,_"        # optional, like Vim's option
+//     [comments]
+//     line = ["//"]
+//     block = [["/*", "*/"]]         # any number of start and end pairs
+//     nested = true                  # block comments inside block comments
+//     [strings]
+//     delimiters = ['"']
+//     characters = true              # 'c' literals
+//     [numbers]                      # no table, no numbers
+//     separator = "_"                # allowed between digits
+//     suffixes = true                # letters after digits, like 0xff or 1u8
+//     [keywords]
+//     primary = ["fn", "let"]
+//     secondary = ["bool", "u8"]
+
+const BUNDLED: &[(&str, &str)] = &[
+    ("c", include_str!("../syntax/c.toml")),
+    ("cpp", include_str!("../syntax/cpp.toml")),
+    ("dockerfile", include_str!("../syntax/dockerfile.toml")),
+    ("go", include_str!("../syntax/go.toml")),
+    ("haskell", include_str!("../syntax/haskell.toml")),
+    ("html", include_str!("../syntax/html.toml")),
+    ("java", include_str!("../syntax/java.toml")),
+    ("javascript", include_str!("../syntax/javascript.toml")),
+    ("lisp", include_str!("../syntax/lisp.toml")),
+    ("lua", include_str!("../syntax/lua.toml")),
+    ("make", include_str!("../syntax/make.toml")),
+    ("python", include_str!("../syntax/python.toml")),
+    ("rust", include_str!("../syntax/rust.toml")),
+    ("sh", include_str!("../syntax/sh.toml")),
+    ("sql", include_str!("../syntax/sql.toml")),
+    ("toml", include_str!("../syntax/toml.toml")),
+    ("typescript", include_str!("../syntax/typescript.toml")),
+];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    name: String,
+    #[serde(default)]
+    filenames: Vec<String>,
+    #[serde(default)]
+    globs: Vec<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    #[serde(default)]
+    shebangs: Vec<String>,
+    #[serde(default)]
+    content: Vec<String>,
+    iskeyword: Option<String>,
+    #[serde(default)]
+    comments: Comments,
+    #[serde(default)]
+    strings: Strings,
+    numbers: Option<Numbers>,
+    #[serde(default)]
+    keywords: Keywords,
+    // `globs` and `content` compiled.
+    #[serde(skip)]
+    glob_set: GlobSet,
+    #[serde(skip)]
+    content_regexes: Vec<Regex>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct Comments {
+    line: Vec<String>,
+    block: Vec<(String, String)>,
+    nested: bool,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct Strings {
+    delimiters: Vec<char>,
+    characters: bool,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Numbers {
+    separator: Option<char>,
+    suffixes: bool,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct Keywords {
+    primary: Vec<String>,
+    secondary: Vec<String>,
+}
+
+// The definitions that were read, by the name `:set filetype=` takes, with
+// the ones from the config directory first so that they are tried first.
+pub struct Syntaxes {
+    definitions: Vec<(String, Definition)>,
+    // What went wrong reading the files in the config directory.
+    pub errors: Vec<String>,
+}
+
+fn directory() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|home| !home.is_empty()) {
+        Some(config) => Some(Path::new(&config).join("phantom/syntax")),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".config/phantom/syntax")),
+    }
+}
+
+// How many lines at the start (and for modelines, at the end) of a file are
+// looked at, like Vim's 'modelines'.
+const DETECT_LINES: usize = 5;
+
+fn parse(file_name: &str, contents: &str) -> Result<Definition, String> {
+    // On one line, unlike the error's own message.
+    let mut definition: Definition =
+        toml::from_str(contents).map_err(|error: toml::de::Error| {
+            let line = error
+                .span()
+                .and_then(|span| contents.get(..span.start))
+                .map_or(1, |before| before.matches('\n').count().saturating_add(1));
+            format!("{}:{}: {}", file_name, line, error.message().trim())
+        })?;
+    let comments = &definition.comments;
+    if comments.line.iter().any(String::is_empty)
+        || comments
+            .block
+            .iter()
+            .any(|(start, end)| start.is_empty() || end.is_empty())
+    {
+        return Err(format!("{}: empty comment delimiter", file_name));
+    }
+    if let Some(iskeyword) = &definition.iskeyword {
+        IsKeyword::parse(iskeyword).map_err(|error| format!("{}: {}", file_name, error))?;
+    }
+    let mut globs = GlobSetBuilder::new();
+    for glob in &definition.globs {
+        globs.add(Glob::new(glob).map_err(|error| format!("{}: {}", file_name, error))?);
+    }
+    definition.glob_set = globs
+        .build()
+        .map_err(|error| format!("{}: {}", file_name, error))?;
+    definition.content_regexes = definition
+        .content
+        .iter()
+        .map(|pattern| Regex::new(pattern))
+        .collect::<Result<_, _>>()
+        .map_err(|error| format!("{}: {}", file_name, error))?;
+    Ok(definition)
+}
+
+impl Syntaxes {
+    // The built-in definitions, replaced or added to by the `.toml` files
+    // in `directory`.
+    pub fn load(directory: Option<&Path>) -> Self {
+        let mut errors = Vec::new();
+        let mut definitions = Vec::new();
+        let mut files: Vec<PathBuf> = directory
+            .and_then(|directory| fs::read_dir(directory).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "toml")
+            })
+            .collect();
+        files.sort();
+        for path in files {
+            let id = path
+                .file_stem()
+                .map_or_else(String::new, |stem| stem.to_string_lossy().to_string());
+            let file_name = path.display().to_string();
+            match fs::read_to_string(&path)
+                .map_err(|error| format!("{}: {}", file_name, error))
+                .and_then(|contents| parse(&file_name, &contents))
+            {
+                Ok(definition) => definitions.push((id, definition)),
+                Err(error) => errors.push(error),
+            }
+        }
+        for (id, contents) in BUNDLED {
+            if definitions.iter().any(|(other, _)| other == id) {
+                continue;
+            }
+            match parse(id, contents) {
+                Ok(definition) => definitions.push(((*id).to_string(), definition)),
+                Err(error) => errors.push(error),
+            }
+        }
+        Self {
+            definitions,
+            errors,
+        }
+    }
+    // The definition for a file by its name, a glob or its extension, or
+    // else by the interpreter in a `#!` first line, or last by what its
+    // first lines look like.
+    fn detect(&self, file_name: &str, lines: &[&str]) -> Option<&(String, Definition)> {
+        let path = Path::new(file_name);
+        let base_name = path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_string());
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string());
+        let interpreter = lines.first().and_then(|line| interpreter(line));
+        let head = lines.get(..DETECT_LINES).unwrap_or(lines);
+        let find = |found: &dyn Fn(&Definition) -> bool| {
+            self.definitions
+                .iter()
+                .find(|(_, definition)| found(definition))
+        };
+        find(&|definition| definition.filenames.contains(&base_name))
+            .or_else(|| {
+                find(&|definition| {
+                    definition.glob_set.is_match(&base_name) || definition.glob_set.is_match(path)
+                })
+            })
+            .or_else(|| {
+                let extension = extension.as_ref()?;
+                find(&|definition| definition.extensions.contains(extension))
+            })
+            .or_else(|| {
+                let interpreter = interpreter.as_deref()?;
+                find(&|definition| {
+                    definition
+                        .shebangs
+                        .iter()
+                        .any(|name| runs(interpreter, name))
+                })
+            })
+            .or_else(|| {
+                find(&|definition| {
+                    head.iter().any(|line| {
+                        definition
+                            .content_regexes
+                            .iter()
+                            .any(|regex| regex.is_match(line))
+                    })
+                })
+            })
+    }
+    fn named(&self, id: &str) -> Option<&(String, Definition)> {
+        self.definitions.iter().find(|(other, _)| other == id)
+    }
+}
+
+// The program a `#!` line runs, looking past `env` and its options.
+fn interpreter(line: &str) -> Option<String> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let program = words.next()?;
+    let name = |path: &str| {
+        Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+    };
+    match name(program)?.as_str() {
+        "env" => words.find(|word| !word.starts_with('-')).and_then(name),
+        program => Some(program.to_string()),
+    }
+}
+
+// Whether `interpreter` is the program `name`, or a version of it like
+// `python3.12` is of `python3` and `python`.
+fn runs(interpreter: &str, name: &str) -> bool {
+    interpreter == name
+        || interpreter.strip_prefix(name).map_or(false, |version| {
+            version.chars().all(|c| c.is_ascii_digit() || c == '.')
+        })
+}
+
+// The filetype a Vim modeline like `# vim: set ft=sh :` or `// vim: ft=c`
+// sets, if `line` has one.
+fn modeline(line: &str) -> Option<&str> {
+    let start = ["vim:", "vi:", "ex:"]
+        .iter()
+        .filter_map(|marker| {
+            line.match_indices(marker)
+                .find(|(index, _)| {
+                    line.get(..*index).map_or(false, |before| {
+                        before.is_empty() || before.ends_with(char::is_whitespace)
+                    })
+                })
+                .map(|(index, _)| index.saturating_add(marker.len()))
+        })
+        .min()?;
+    line.get(start..)?
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .find_map(|option| {
+            option
+                .strip_prefix("ft=")
+                .or_else(|| option.strip_prefix("filetype="))
+        })
+}
+
+pub fn syntaxes() -> &'static Syntaxes {
+    static SYNTAXES: OnceLock<Syntaxes> = OnceLock::new();
+    SYNTAXES.get_or_init(|| Syntaxes::load(directory().as_deref()))
+}
+
 pub struct FileType {
+    // The name of the definition, empty for none.
+    id: String,
     name: String,
     hl_opts: HighlightingOptions,
+    iskeyword: IsKeyword,
 }
 
 #[derive(Default)]
 pub struct HighlightingOptions {
-    numbers: bool,
-    strings: bool,
-    characters: bool,
-    comments: bool,
-    multiline_comments: bool,
-    primary_keywords: Vec<String>,
-    secondary_keywords: Vec<String>,
+    comments: Comments,
+    strings: Strings,
+    numbers: Option<Numbers>,
+    keywords: Keywords,
 }
 
 impl Default for FileType {
     fn default() -> Self {
         Self {
+            id: String::new(),
             name: String::from("No filetype"),
             hl_opts: HighlightingOptions::default(),
+            iskeyword: IsKeyword::default(),
         }
     }
 }
@@ -27,348 +350,186 @@ impl FileType {
     pub fn name(&self) -> String {
         self.name.clone()
     }
+    pub fn id(&self) -> &str {
+        &self.id
+    }
     pub fn highlighting_options(&self) -> &HighlightingOptions {
         &self.hl_opts
     }
-    pub fn from(file_name: &str) -> Self {
-        if file_name.ends_with(".rs") {
-            return Self {
-                name: String::from("Rust"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "as".to_string(),
-                        "break".to_string(),
-                        "const".to_string(),
-                        "continue".to_string(),
-                        "crate".to_string(),
-                        "else".to_string(),
-                        "enum".to_string(),
-                        "extern".to_string(),
-                        "false".to_string(),
-                        "fn".to_string(),
-                        "for".to_string(),
-                        "if".to_string(),
-                        "impl".to_string(),
-                        "in".to_string(),
-                        "let".to_string(),
-                        "loop".to_string(),
-                        "match".to_string(),
-                        "mod".to_string(),
-                        "move".to_string(),
-                        "mut".to_string(),
-                        "pub".to_string(),
-                        "ref".to_string(),
-                        "return".to_string(),
-                        "self".to_string(),
-                        "Self".to_string(),
-                        "static".to_string(),
-                        "struct".to_string(),
-                        "super".to_string(),
-                        "trait".to_string(),
-                        "true".to_string(),
-                        "type".to_string(),
-                        "unsafe".to_string(),
-                        "use".to_string(),
-                        "where".to_string(),
-                        "while".to_string(),
-                        "dyn".to_string(),
-                        "abstract".to_string(),
-                        "become".to_string(),
-                        "box".to_string(),
-                        "do".to_string(),
-                        "final".to_string(),
-                        "macro".to_string(),
-                        "override".to_string(),
-                        "priv".to_string(),
-                        "typeof".to_string(),
-                        "unsized".to_string(),
-                        "virtual".to_string(),
-                        "yield".to_string(),
-                        "async".to_string(),
-                        "await".to_string(),
-                        "try".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "bool".to_string(),
-                        "char".to_string(),
-                        "i8".to_string(),
-                        "i16".to_string(),
-                        "i32".to_string(),
-                        "i64".to_string(),
-                        "isize".to_string(),
-                        "u8".to_string(),
-                        "u16".to_string(),
-                        "u32".to_string(),
-                        "u64".to_string(),
-                        "usize".to_string(),
-                        "f32".to_string(),
-                        "f64".to_string(),
-                    ],
-                },
-            };
-        }
-        
-        if file_name.ends_with(".js") || file_name.ends_with(".jsx") {
-            return Self {
-                name: String::from("JavaScript"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "await".to_string(), "break".to_string(), "case".to_string(),
-                        "catch".to_string(), "class".to_string(), "const".to_string(),
-                        "continue".to_string(), "default".to_string(), "delete".to_string(),
-                        "do".to_string(), "else".to_string(), "export".to_string(),
-                        "extends".to_string(), "finally".to_string(), "for".to_string(),
-                        "function".to_string(), "if".to_string(), "import".to_string(),
-                        "in".to_string(), "instanceof".to_string(), "let".to_string(),
-                        "new".to_string(), "return".to_string(), "static".to_string(),
-                        "super".to_string(), "switch".to_string(), "this".to_string(),
-                        "throw".to_string(), "try".to_string(), "typeof".to_string(),
-                        "var".to_string(), "void".to_string(), "while".to_string(),
-                        "with".to_string(), "yield".to_string(), "async".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "true".to_string(), "false".to_string(), "null".to_string(),
-                        "undefined".to_string(), "NaN".to_string(), "Infinity".to_string(),
-                    ],
-                },
-            };
-        }
-        
-        if file_name.ends_with(".ts") || file_name.ends_with(".tsx") {
-            return Self {
-                name: String::from("TypeScript"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "await".to_string(), "break".to_string(), "case".to_string(),
-                        "catch".to_string(), "class".to_string(), "const".to_string(),
-                        "continue".to_string(), "default".to_string(), "delete".to_string(),
-                        "do".to_string(), "else".to_string(), "export".to_string(),
-                        "extends".to_string(), "finally".to_string(), "for".to_string(),
-                        "function".to_string(), "if".to_string(), "import".to_string(),
-                        "in".to_string(), "instanceof".to_string(), "let".to_string(),
-                        "new".to_string(), "return".to_string(), "static".to_string(),
-                        "super".to_string(), "switch".to_string(), "this".to_string(),
-                        "throw".to_string(), "try".to_string(), "typeof".to_string(),
-                        "var".to_string(), "void".to_string(), "while".to_string(),
-                        "with".to_string(), "yield".to_string(), "async".to_string(),
-                        "interface".to_string(), "type".to_string(), "namespace".to_string(),
-                        "enum".to_string(), "private".to_string(), "public".to_string(),
-                        "protected".to_string(), "readonly".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "string".to_string(), "number".to_string(), "boolean".to_string(),
-                        "any".to_string(), "void".to_string(), "never".to_string(),
-                        "true".to_string(), "false".to_string(), "null".to_string(),
-                        "undefined".to_string(),
-                    ],
-                },
-            };
-        }
-        
-        if file_name.ends_with(".py") {
-            return Self {
-                name: String::from("Python"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: false,
-                    primary_keywords: vec![
-                        "and".to_string(), "as".to_string(), "assert".to_string(),
-                        "async".to_string(), "await".to_string(), "break".to_string(),
-                        "class".to_string(), "continue".to_string(), "def".to_string(),
-                        "del".to_string(), "elif".to_string(), "else".to_string(),
-                        "except".to_string(), "finally".to_string(), "for".to_string(),
-                        "from".to_string(), "global".to_string(), "if".to_string(),
-                        "import".to_string(), "in".to_string(), "is".to_string(),
-                        "lambda".to_string(), "nonlocal".to_string(), "not".to_string(),
-                        "or".to_string(), "pass".to_string(), "raise".to_string(),
-                        "return".to_string(), "try".to_string(), "while".to_string(),
-                        "with".to_string(), "yield".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "True".to_string(), "False".to_string(), "None".to_string(),
-                        "self".to_string(), "cls".to_string(),
-                    ],
-                },
-            };
-        }
-        
-        if file_name.ends_with(".c") || file_name.ends_with(".h") {
-            return Self {
-                name: String::from("C"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "auto".to_string(), "break".to_string(), "case".to_string(),
-                        "const".to_string(), "continue".to_string(), "default".to_string(),
-                        "do".to_string(), "else".to_string(), "enum".to_string(),
-                        "extern".to_string(), "for".to_string(), "goto".to_string(),
-                        "if".to_string(), "register".to_string(), "return".to_string(),
-                        "sizeof".to_string(), "static".to_string(), "struct".to_string(),
-                        "switch".to_string(), "typedef".to_string(), "union".to_string(),
-                        "volatile".to_string(), "while".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "char".to_string(), "double".to_string(), "float".to_string(),
-                        "int".to_string(), "long".to_string(), "short".to_string(),
-                        "signed".to_string(), "unsigned".to_string(), "void".to_string(),
-                    ],
-                },
-            };
-        }
-        
-        if file_name.ends_with(".cpp") || file_name.ends_with(".cc") || 
-           file_name.ends_with(".cxx") || file_name.ends_with(".hpp") {
-            return Self {
-                name: String::from("C++"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "alignas".to_string(), "alignof".to_string(), "and".to_string(),
-                        "asm".to_string(), "auto".to_string(), "break".to_string(),
-                        "case".to_string(), "catch".to_string(), "class".to_string(),
-                        "const".to_string(), "constexpr".to_string(), "continue".to_string(),
-                        "decltype".to_string(), "default".to_string(), "delete".to_string(),
-                        "do".to_string(), "else".to_string(), "enum".to_string(),
-                        "explicit".to_string(), "extern".to_string(), "for".to_string(),
-                        "friend".to_string(), "goto".to_string(), "if".to_string(),
-                        "inline".to_string(), "namespace".to_string(), "new".to_string(),
-                        "noexcept".to_string(), "nullptr".to_string(), "operator".to_string(),
-                        "private".to_string(), "protected".to_string(), "public".to_string(),
-                        "return".to_string(), "sizeof".to_string(), "static".to_string(),
-                        "struct".to_string(), "switch".to_string(), "template".to_string(),
-                        "this".to_string(), "throw".to_string(), "try".to_string(),
-                        "typedef".to_string(), "typeid".to_string(), "typename".to_string(),
-                        "union".to_string(), "using".to_string(), "virtual".to_string(),
-                        "volatile".to_string(), "while".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "bool".to_string(), "char".to_string(), "double".to_string(),
-                        "float".to_string(), "int".to_string(), "long".to_string(),
-                        "short".to_string(), "signed".to_string(), "unsigned".to_string(),
-                        "void".to_string(), "wchar_t".to_string(),
-                    ],
-                },
-            };
+    pub fn iskeyword(&self) -> &IsKeyword {
+        &self.iskeyword
+    }
+    // From a modeline in the first or last lines of the file, or else
+    // detected from its name and contents.
+    pub fn detect(file_name: &str, lines: &[&str]) -> Self {
+        let head = lines.iter().take(DETECT_LINES);
+        let tail = lines
+            .iter()
+            .skip(DETECT_LINES.max(lines.len().saturating_sub(DETECT_LINES)));
+        if let Some(file_type) = head
+            .chain(tail)
+            .filter_map(|line| modeline(line))
+            .find_map(Self::named)
+        {
+            return file_type;
         }
-        
-        if file_name.ends_with(".go") {
-            return Self {
-                name: String::from("Go"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "break".to_string(), "case".to_string(), "chan".to_string(),
-                        "const".to_string(), "continue".to_string(), "default".to_string(),
-                        "defer".to_string(), "else".to_string(), "fallthrough".to_string(),
-                        "for".to_string(), "func".to_string(), "go".to_string(),
-                        "goto".to_string(), "if".to_string(), "import".to_string(),
-                        "interface".to_string(), "map".to_string(), "package".to_string(),
-                        "range".to_string(), "return".to_string(), "select".to_string(),
-                        "struct".to_string(), "switch".to_string(), "type".to_string(),
-                        "var".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "bool".to_string(), "byte".to_string(), "complex64".to_string(),
-                        "complex128".to_string(), "error".to_string(), "float32".to_string(),
-                        "float64".to_string(), "int".to_string(), "int8".to_string(),
-                        "int16".to_string(), "int32".to_string(), "int64".to_string(),
-                        "rune".to_string(), "string".to_string(), "uint".to_string(),
-                        "uint8".to_string(), "uint16".to_string(), "uint32".to_string(),
-                        "uint64".to_string(), "uintptr".to_string(),
-                    ],
-                },
-            };
+        syntaxes()
+            .detect(file_name, lines)
+            .map_or_else(Self::default, Self::from_definition)
+    }
+    // `:set filetype=`, where an empty name means none.
+    pub fn named(id: &str) -> Option<Self> {
+        if id.is_empty() {
+            return Some(Self::default());
         }
-        
-        if file_name.ends_with(".java") {
-            return Self {
-                name: String::from("Java"),
-                hl_opts: HighlightingOptions {
-                    numbers: true,
-                    strings: true,
-                    characters: true,
-                    comments: true,
-                    multiline_comments: true,
-                    primary_keywords: vec![
-                        "abstract".to_string(), "assert".to_string(), "break".to_string(),
-                        "case".to_string(), "catch".to_string(), "class".to_string(),
-                        "const".to_string(), "continue".to_string(), "default".to_string(),
-                        "do".to_string(), "else".to_string(), "enum".to_string(),
-                        "extends".to_string(), "final".to_string(), "finally".to_string(),
-                        "for".to_string(), "goto".to_string(), "if".to_string(),
-                        "implements".to_string(), "import".to_string(), "instanceof".to_string(),
-                        "interface".to_string(), "native".to_string(), "new".to_string(),
-                        "package".to_string(), "private".to_string(), "protected".to_string(),
-                        "public".to_string(), "return".to_string(), "static".to_string(),
-                        "strictfp".to_string(), "super".to_string(), "switch".to_string(),
-                        "synchronized".to_string(), "this".to_string(), "throw".to_string(),
-                        "throws".to_string(), "transient".to_string(), "try".to_string(),
-                        "volatile".to_string(), "while".to_string(),
-                    ],
-                    secondary_keywords: vec![
-                        "boolean".to_string(), "byte".to_string(), "char".to_string(),
-                        "double".to_string(), "float".to_string(), "int".to_string(),
-                        "long".to_string(), "short".to_string(), "void".to_string(),
-                        "true".to_string(), "false".to_string(), "null".to_string(),
-                    ],
-                },
-            };
+        syntaxes().named(id).map(Self::from_definition)
+    }
+    fn from_definition((id, definition): &(String, Definition)) -> Self {
+        Self {
+            id: id.clone(),
+            name: definition.name.clone(),
+            hl_opts: HighlightingOptions {
+                comments: definition.comments.clone(),
+                strings: definition.strings.clone(),
+                numbers: definition.numbers.clone(),
+                keywords: definition.keywords.clone(),
+            },
+            iskeyword: definition
+                .iskeyword
+                .as_deref()
+                .and_then(|iskeyword| IsKeyword::parse(iskeyword).ok())
+                .unwrap_or_default(),
         }
-        
-        Self::default()
     }
 }
 
 impl HighlightingOptions {
-    pub fn numbers(&self) -> bool {
-        self.numbers
+    pub fn numbers(&self) -> Option<&Numbers> {
+        self.numbers.as_ref()
     }
-    pub fn strings(&self) -> bool {
-        self.strings
+    pub fn string_delimiters(&self) -> &[char] {
+        &self.strings.delimiters
     }
     pub fn characters(&self) -> bool {
-        self.characters
+        self.strings.characters
+    }
+    pub fn line_comments(&self) -> &[String] {
+        &self.comments.line
+    }
+    pub fn block_comments(&self) -> &[(String, String)] {
+        &self.comments.block
     }
-    pub fn comments(&self) -> bool {
-        self.comments
+    pub fn nested_comments(&self) -> bool {
+        self.comments.nested
     }
     pub fn primary_keywords(&self) -> &Vec<String> {
-        &self.primary_keywords
+        &self.keywords.primary
     }
     pub fn secondary_keywords(&self) -> &Vec<String> {
-        &self.secondary_keywords
+        &self.keywords.secondary
     }
-    pub fn multiline_comments(&self) -> bool {
-        self.multiline_comments
+}
+
+impl Numbers {
+    // Whether `c` can go on a number after its first digit.
+    pub fn continues(&self, c: char) -> bool {
+        c == '.'
+            || c.is_ascii_digit()
+            || Some(c) == self.separator
+            || (self.suffixes && c.is_alphanumeric())
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::files::Scratch;
+
+    fn detected(syntaxes: &Syntaxes, file_name: &str, lines: &[&str]) -> Option<String> {
+        syntaxes
+            .detect(file_name, lines)
+            .map(|(_, definition)| definition.name.clone())
+    }
+
+    #[test]
+    fn test_bundled() {
+        let syntaxes = Syntaxes::load(None);
+        assert!(syntaxes.errors.is_empty(), "{:?}", syntaxes.errors);
+        assert_eq!(syntaxes.definitions.len(), BUNDLED.len());
+        let found = |file_name, lines: &[&str]| detected(&syntaxes, file_name, lines);
+        assert_eq!(found("src/main.rs", &[]), Some("Rust".to_string()));
+        assert_eq!(found("a.hpp", &[]), Some("C++".to_string()));
+        assert_eq!(found("a.cc", &[]), Some("C++".to_string()));
+        assert_eq!(found("a.pyi", &[]), Some("Python".to_string()));
+        assert_eq!(found("a.mjs", &[]), Some("JavaScript".to_string()));
+        assert_eq!(
+            found("script", &["#!/usr/bin/env -S python3 -u"]),
+            Some("Python".to_string())
+        );
+        assert_eq!(
+            found("script", &["#!/usr/bin/python3.12"]),
+            Some("Python".to_string())
+        );
+        assert_eq!(
+            found("script", &["#!/usr/bin/node"]),
+            Some("JavaScript".to_string())
+        );
+        assert_eq!(found("notes.txt", &["hello"]), None);
+        // Names before extensions and globs before shebangs.
+        assert_eq!(found("src/Makefile", &[]), Some("Make".to_string()));
+        assert_eq!(found("rules.mk", &[]), Some("Make".to_string()));
+        assert_eq!(found("/home/me/.bashrc", &[]), Some("Shell".to_string()));
+        assert_eq!(found("Cargo.lock", &[]), Some("TOML".to_string()));
+        assert_eq!(
+            found("Dockerfile.dev", &["#!/bin/sh"]),
+            Some("Dockerfile".to_string())
+        );
+        assert_eq!(
+            found("build", &["# Built by CI", "FROM rust:1.80 AS build"]),
+            Some("Dockerfile".to_string())
+        );
+        assert_eq!(found("tool", &["import os"]), Some("Python".to_string()));
+        let python = syntaxes
+            .named("python")
+            .map(FileType::from_definition)
+            .unwrap();
+        assert_eq!(python.highlighting_options().line_comments(), ["#"]);
+        assert!(!python.highlighting_options().nested_comments());
+    }
+
+    #[test]
+    fn test_modeline() {
+        assert_eq!(modeline("# vim: set ft=sh :"), Some("sh"));
+        assert_eq!(modeline("// vi:filetype=c:ts=4"), Some("c"));
+        assert_eq!(modeline("/* ex: ts=8 ft=cpp */"), Some("cpp"));
+        assert_eq!(modeline("let devim: ft=x"), None);
+        assert_eq!(modeline("# vim: ts=4"), None);
+        let lines = ["x", "y", "z", "1", "2", "3", "4", "# vim: ft=python"];
+        assert_eq!(FileType::detect("notes.txt", &lines).id(), "python");
+        assert_eq!(
+            FileType::detect("a.rs", &["// vim: ft=unknown"]).id(),
+            "rust"
+        );
+    }
+
+    #[test]
+    fn test_config_directory() {
+        let root = Scratch::new("syntax");
+        root.write(
+            "rust.toml",
+            "name = \"Rusty\"\nextensions = [\"rs\"]\n[keywords]\nprimary = [\"fn\"]\n",
+        );
+        root.write("zig.toml", "name = \"Zig\"\nextensions = [\"zig\"]\n");
+        root.write("bad.toml", "name = \"Bad\"\ncolour = true\n");
+        root.write("glob.toml", "name = \"Glob\"\nglobs = [\"[\"]\n");
+        root.write("notes.txt", "not a definition");
+        let syntaxes = Syntaxes::load(Some(root.path()));
+        assert_eq!(detected(&syntaxes, "a.rs", &[]), Some("Rusty".to_string()));
+        assert_eq!(detected(&syntaxes, "a.zig", &[]), Some("Zig".to_string()));
+        assert_eq!(detected(&syntaxes, "a.py", &[]), Some("Python".to_string()));
+        assert_eq!(syntaxes.definitions.len(), BUNDLED.len().saturating_add(1));
+        assert_eq!(syntaxes.errors.len(), 2);
+        assert!(syntaxes.errors[0].contains("bad.toml"));
+        assert!(syntaxes.errors[1].contains("glob.toml"));
     }
 }
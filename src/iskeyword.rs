@@ -0,0 +1,130 @@
+// Vim's default: letters, digits, underscore and Latin-1 letters.
+pub const DEFAULT_ISKEYWORD: &str = "@,48-57,_,192-255";
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CharClass {
+    Whitespace,
+    Punctuation,
+    Keyword,
+}
+
+#[derive(Clone)]
+pub struct IsKeyword {
+    spec: String,
+    table: [bool; 256],
+}
+
+impl Default for IsKeyword {
+    fn default() -> Self {
+        Self::parse(DEFAULT_ISKEYWORD).unwrap_or(Self {
+            spec: String::new(),
+            table: [false; 256],
+        })
+    }
+}
+
+impl IsKeyword {
+    // Parses a Vim style 'iskeyword' value such as "@,48-57,_,192-255,$".
+    // Every comma separated part is a character, a decimal character code,
+    // a range of either, or "@" for all alphabetic characters. A leading
+    // "^" removes the part from the set instead of adding it.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut table = [false; 256];
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            let (include, part) = match part.strip_prefix('^') {
+                Some(rest) if !rest.is_empty() => (false, rest),
+                _ => (true, part),
+            };
+            if part == "@" {
+                for (code, entry) in table.iter_mut().enumerate() {
+                    #[allow(clippy::cast_possible_truncation)]
+                    if (code as u8).is_ascii_alphabetic() {
+                        *entry = include;
+                    }
+                }
+                continue;
+            }
+            let (from, to) = match part.char_indices().skip(1).find(|(_, c)| *c == '-') {
+                Some((index, _)) => (
+                    Self::parse_item(&part[..index]),
+                    Self::parse_item(&part[index.saturating_add(1)..]),
+                ),
+                None => (Self::parse_item(part), Self::parse_item(part)),
+            };
+            match (from, to) {
+                (Some(from), Some(to)) if from <= to && to < 256 => {
+                    for entry in &mut table[from..=to] {
+                        *entry = include;
+                    }
+                }
+                _ => return Err(format!("Invalid iskeyword part: {}", part)),
+            }
+        }
+        Ok(Self {
+            spec: spec.to_string(),
+            table,
+        })
+    }
+    fn parse_item(item: &str) -> Option<usize> {
+        if item == "@-@" || item == "@" {
+            return Some('@' as usize);
+        }
+        if !item.is_empty() && item.chars().all(|c| c.is_ascii_digit()) {
+            return item.parse().ok();
+        }
+        let mut chars = item.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c as usize),
+            _ => None,
+        }
+    }
+    pub fn spec(&self) -> &str {
+        &self.spec
+    }
+    pub fn is_keyword(&self, c: char) -> bool {
+        match self.table.get(c as usize) {
+            Some(included) => *included,
+            // Outside Latin-1 the table has no say; letters and digits of
+            // any script are treated as word characters.
+            None => c.is_alphanumeric(),
+        }
+    }
+    pub fn class(&self, c: char) -> CharClass {
+        if c.is_whitespace() {
+            CharClass::Whitespace
+        } else if self.is_keyword(c) {
+            CharClass::Keyword
+        } else {
+            CharClass::Punctuation
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let keywords = IsKeyword::default();
+        assert!(keywords.is_keyword('a'));
+        assert!(keywords.is_keyword('Z'));
+        assert!(keywords.is_keyword('7'));
+        assert!(keywords.is_keyword('_'));
+        assert!(keywords.is_keyword('é'));
+        assert!(!keywords.is_keyword('$'));
+        assert_eq!(keywords.class(' '), CharClass::Whitespace);
+        assert_eq!(keywords.class('.'), CharClass::Punctuation);
+    }
+
+    #[test]
+    fn test_parse() {
+        let keywords = IsKeyword::parse("@,48-57,_,$,^x,-").unwrap();
+        assert!(keywords.is_keyword('$'));
+        assert!(keywords.is_keyword('-'));
+        assert!(!keywords.is_keyword('x'));
+        assert!(keywords.is_keyword('y'));
+        assert!(IsKeyword::parse("a-").is_err());
+        assert!(IsKeyword::parse("300").is_err());
+    }
+}
@@ -7,12 +7,27 @@
     clippy::wildcard_enum_match_arm,
     clippy::else_if_without_else
 )]
+mod cmdline;
 mod document;
 mod editor;
+mod errorformat;
+mod ex;
+mod explorer;
+mod files;
 mod filetype;
 mod highlighting;
+mod iskeyword;
 mod movement;
+mod normal;
+mod options;
+mod picker;
+mod quickfix;
+mod registers;
 mod row;
+mod search;
+mod shell;
+mod sort;
+mod substitute;
 mod terminal;
 pub use document::Document;
 use editor::Editor;
@@ -20,6 +35,7 @@ pub use editor::Position;
 pub use editor::SearchDirection;
 pub use filetype::FileType;
 pub use filetype::HighlightingOptions;
+pub use iskeyword::IsKeyword;
 pub use row::Row;
 pub use terminal::Terminal;
 
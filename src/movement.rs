@@ -1,3 +1,34 @@
+use crate::iskeyword::CharClass;
+use crate::IsKeyword;
+use std::cmp;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Everything a movement may need to know besides the cursor itself.
+pub struct Context<'a> {
+    pub lines: &'a [String],
+    pub keywords: &'a IsKeyword,
+    // Number of text lines visible in the window.
+    pub height: usize,
+    // The 'scroll' option: lines moved by half page motions, 0 for half
+    // the window height.
+    pub scroll: usize,
+}
+
+impl<'a> Context<'a> {
+    // Like Vim's Ctrl-f, keep two lines of the previous page visible.
+    fn page(&self) -> usize {
+        cmp::max(self.height.saturating_sub(2), 1)
+    }
+    #[allow(clippy::integer_division)]
+    fn half_page(&self) -> usize {
+        if self.scroll > 0 {
+            self.scroll
+        } else {
+            cmp::max(self.height / 2, 1)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Movement {
     // Basic movements
@@ -28,13 +59,12 @@ pub enum Movement {
 }
 
 impl Movement {
-    pub fn execute(&self, cx: usize, cy: usize, lines: &[String]) -> (usize, usize) {
+    pub fn execute(&self, cx: usize, cy: usize, context: &Context) -> (usize, usize) {
+        let lines = context.lines;
+        let last_line = lines.len().saturating_sub(1);
         match self {
             Movement::Left => (cx.saturating_sub(1), cy),
-            Movement::Right => {
-                let line = lines.get(cy).map(|l| l.len()).unwrap_or(0);
-                (std::cmp::min(cx + 1, line), cy)
-            }
+            Movement::Right => (cmp::min(cx + 1, line_len(lines, cy)), cy),
             Movement::Up => (cx, cy.saturating_sub(1)),
             Movement::Down => {
                 if cy + 1 < lines.len() {
@@ -44,18 +74,18 @@ impl Movement {
                 }
             }
 
-            Movement::NextWord => Self::next_word(cx, cy, lines),
-            Movement::PrevWord => Self::prev_word(cx, cy, lines),
-            Movement::EndOfWord => Self::end_of_word(cx, cy, lines),
+            Movement::NextWord => Self::next_word(cx, cy, lines, context.keywords),
+            Movement::PrevWord => Self::prev_word(cx, cy, lines, context.keywords),
+            Movement::EndOfWord => Self::end_of_word(cx, cy, lines, context.keywords),
 
             Movement::StartOfLine => (0, cy),
-            Movement::EndOfLine => {
-                let line = lines.get(cy).map(|l| l.len()).unwrap_or(0);
-                (line.saturating_sub(1), cy)
-            }
+            Movement::EndOfLine => (line_len(lines, cy).saturating_sub(1), cy),
             Movement::FirstNonWhitespace => {
                 if let Some(line) = lines.get(cy) {
-                    let pos = line.chars().position(|c| !c.is_whitespace()).unwrap_or(0);
+                    let pos = line
+                        .graphemes(true)
+                        .position(|g| !g.chars().all(char::is_whitespace))
+                        .unwrap_or(0);
                     (pos, cy)
                 } else {
                     (cx, cy)
@@ -63,117 +93,293 @@ impl Movement {
             }
 
             Movement::StartOfFile => (0, 0),
-            Movement::EndOfFile => {
-                let last_line = lines.len().saturating_sub(1);
-                let col = lines
-                    .get(last_line)
-                    .map(|l| l.len().saturating_sub(1))
-                    .unwrap_or(0);
-                (col, last_line)
-            }
+            Movement::EndOfFile => (line_len(lines, last_line).saturating_sub(1), last_line),
 
-            Movement::PageDown => {
-                let page_size = 10; // Adjust based on editor height
-                (
-                    cx,
-                    std::cmp::min(cy + page_size, lines.len().saturating_sub(1)),
-                )
+            Movement::PageDown => (cx, cmp::min(cy + context.page(), last_line)),
+            Movement::PageUp => (cx, cy.saturating_sub(context.page())),
+            Movement::HalfPageDown => (cx, cmp::min(cy + context.half_page(), last_line)),
+            Movement::HalfPageUp => (cx, cy.saturating_sub(context.half_page())),
+        }
+    }
+    // Operators applied with these motions work on whole lines.
+    pub fn is_linewise(self) -> bool {
+        matches!(self, Movement::StartOfFile | Movement::EndOfFile) || self.is_vertical()
+    }
+    // Operators applied with these motions include the character the
+    // motion ends on.
+    pub fn is_inclusive(self) -> bool {
+        matches!(self, Movement::EndOfWord | Movement::EndOfLine)
+    }
+    pub fn is_vertical(self) -> bool {
+        matches!(self, Movement::Up | Movement::Down) || self.scrolls_view()
+    }
+    pub fn scrolls_view(self) -> bool {
+        matches!(
+            self,
+            Movement::PageDown | Movement::PageUp | Movement::HalfPageDown | Movement::HalfPageUp
+        )
+    }
+
+    // Port of Vim's fwd_word(): skip the rest of the current word, then any
+    // whitespace, stopping on the first character of the next word or on an
+    // empty line.
+    fn next_word(cx: usize, cy: usize, lines: &[String], keywords: &IsKeyword) -> (usize, usize) {
+        let mut cursor = WordCursor::new(cx, cy, lines, keywords);
+        let start_class = cursor.class();
+        let last_line = cy.saturating_add(1) >= lines.len();
+        match cursor.next() {
+            Step::Blocked => return (cx, cy),
+            Step::Wrapped if last_line => return (cx, cy),
+            _ => (),
+        }
+        if start_class != CharClass::Whitespace {
+            while cursor.class() == start_class {
+                if cursor.next() == Step::Blocked {
+                    return cursor.position();
+                }
+            }
+        }
+        while cursor.class() == CharClass::Whitespace && !cursor.on_empty_line() {
+            if cursor.next() == Step::Blocked {
+                break;
             }
-            Movement::PageUp => {
-                let page_size = 10;
-                (cx, cy.saturating_sub(page_size))
+        }
+        cursor.position()
+    }
+
+    // Port of Vim's bck_word(): step back over whitespace (stopping on an
+    // empty line) and then to the first character of the word before.
+    fn prev_word(cx: usize, cy: usize, lines: &[String], keywords: &IsKeyword) -> (usize, usize) {
+        let mut cursor = WordCursor::new(cx, cy, lines, keywords);
+        if cursor.prev() == Step::Blocked {
+            return (cx, cy);
+        }
+        while cursor.class() == CharClass::Whitespace {
+            if cursor.on_empty_line() {
+                return cursor.position();
             }
-            Movement::HalfPageDown => {
-                let page_size = 5;
-                (
-                    cx,
-                    std::cmp::min(cy + page_size, lines.len().saturating_sub(1)),
-                )
+            if cursor.prev() == Step::Blocked {
+                return cursor.position();
             }
-            Movement::HalfPageUp => {
-                let page_size = 5;
-                (cx, cy.saturating_sub(page_size))
+        }
+        let class = cursor.class();
+        while cursor.class() == class {
+            if cursor.prev() == Step::Blocked {
+                return cursor.position();
             }
         }
+        cursor.next();
+        cursor.position()
     }
 
-    fn next_word(cx: usize, cy: usize, lines: &[String]) -> (usize, usize) {
-        if let Some(line) = lines.get(cy) {
-            let rest = line.chars().skip(cx);
-
-            // Skip current word if in middle of word
-            let mut pos = cx;
-            for ch in rest.clone() {
-                if !ch.is_alphanumeric() && ch != '_' {
-                    break;
+    // Port of Vim's end_word(): move to the last character of the current
+    // word, or of the next one when already at the end of a word.
+    fn end_of_word(cx: usize, cy: usize, lines: &[String], keywords: &IsKeyword) -> (usize, usize) {
+        let mut cursor = WordCursor::new(cx, cy, lines, keywords);
+        let start_class = cursor.class();
+        if cursor.next() == Step::Blocked {
+            return (cx, cy);
+        }
+        if cursor.class() != start_class || start_class == CharClass::Whitespace {
+            while cursor.class() == CharClass::Whitespace {
+                if cursor.next() == Step::Blocked {
+                    return cursor.position();
                 }
-                pos += 1;
             }
-
-            // Skip whitespace
-            let rest = line.chars().skip(pos);
-            for (i, ch) in rest.enumerate() {
-                if ch.is_alphanumeric() || ch == '_' {
-                    return (pos + i, cy);
-                }
+        }
+        let class = cursor.class();
+        while cursor.class() == class {
+            if cursor.next() == Step::Blocked {
+                return cursor.position();
             }
         }
-        (cx, cy)
+        cursor.prev();
+        cursor.position()
     }
+}
 
-    fn prev_word(cx: usize, cy: usize, lines: &[String]) -> (usize, usize) {
-        if let Some(line) = lines.get(cy) {
-            if cx == 0 {
-                return (cx, cy);
-            }
+// Positions are grapheme indices, the same unit `Row` uses.
+fn line_len(lines: &[String], y: usize) -> usize {
+    lines.get(y).map_or(0, |line| line.graphemes(true).count())
+}
 
-            let line_str: String = line.chars().take(cx).collect();
-            let chars: Vec<char> = line_str.chars().collect();
+#[derive(PartialEq, Clone, Copy)]
+enum Step {
+    Moved,
+    Wrapped,
+    Blocked,
+}
 
-            let mut pos = cx.saturating_sub(1);
+// Walks the buffer one grapheme at a time the way Vim's inc()/dec() do: every
+// line has an extra position past its last character (its line break), which
+// counts as whitespace.
+struct WordCursor<'a> {
+    lines: &'a [String],
+    keywords: &'a IsKeyword,
+    x: usize,
+    y: usize,
+    line: Vec<char>,
+}
 
-            // Skip whitespace backward
-            while pos > 0 && !chars[pos].is_alphanumeric() && chars[pos] != '_' {
-                pos -= 1;
+impl<'a> WordCursor<'a> {
+    fn new(x: usize, y: usize, lines: &'a [String], keywords: &'a IsKeyword) -> Self {
+        let mut cursor = Self {
+            lines,
+            keywords,
+            x,
+            y,
+            line: Vec::new(),
+        };
+        cursor.load_line();
+        cursor.x = cmp::min(x, cursor.line.len());
+        cursor
+    }
+    fn load_line(&mut self) {
+        self.line = self
+            .lines
+            .get(self.y)
+            .map(|line| {
+                line.graphemes(true)
+                    .map(|grapheme| grapheme.chars().next().unwrap_or(' '))
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+    fn class(&self) -> CharClass {
+        self.line
+            .get(self.x)
+            .map_or(CharClass::Whitespace, |c| self.keywords.class(*c))
+    }
+    fn on_empty_line(&self) -> bool {
+        self.line.is_empty()
+    }
+    fn next(&mut self) -> Step {
+        if self.x < self.line.len() {
+            self.x = self.x.saturating_add(1);
+            if self.x == self.line.len() {
+                return Step::Wrapped;
             }
+            return Step::Moved;
+        }
+        if self.y.saturating_add(1) < self.lines.len() {
+            self.y = self.y.saturating_add(1);
+            self.x = 0;
+            self.load_line();
+            return Step::Wrapped;
+        }
+        Step::Blocked
+    }
+    fn prev(&mut self) -> Step {
+        if self.x > 0 {
+            self.x = self.x.saturating_sub(1);
+            return Step::Moved;
+        }
+        if self.y > 0 {
+            self.y = self.y.saturating_sub(1);
+            self.load_line();
+            self.x = self.line.len();
+            return Step::Wrapped;
+        }
+        Step::Blocked
+    }
+    // The line break position is only a stepping stone; the cursor itself
+    // lands on the last character instead.
+    fn position(&self) -> (usize, usize) {
+        if self.x >= self.line.len() {
+            (self.line.len().saturating_sub(1), self.y)
+        } else {
+            (self.x, self.y)
+        }
+    }
+}
 
-            // Skip word backward
-            while pos > 0 && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
-                pos -= 1;
-            }
+#[cfg(test)]
+mod test_super {
+    use super::*;
 
-            // Move forward once if we stopped at non-word char
-            if pos > 0 && !chars[pos].is_alphanumeric() && chars[pos] != '_' {
-                pos += 1;
-            }
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|line| line.to_string()).collect()
+    }
 
-            return (pos, cy);
+    fn context<'a>(lines: &'a [String], keywords: &'a IsKeyword) -> Context<'a> {
+        Context {
+            lines,
+            keywords,
+            height: 10,
+            scroll: 0,
         }
-        (cx, cy)
     }
 
-    fn end_of_word(cx: usize, cy: usize, lines: &[String]) -> (usize, usize) {
-        if let Some(line) = lines.get(cy) {
-            let rest = line.chars().skip(cx);
+    #[test]
+    fn test_next_word() {
+        let keywords = IsKeyword::default();
+        let lines = lines(&["foo.bar()  baz", "", "  qux"]);
+        let next = |x, y| Movement::NextWord.execute(x, y, &context(&lines, &keywords));
+        assert_eq!(next(0, 0), (3, 0));
+        assert_eq!(next(3, 0), (4, 0));
+        assert_eq!(next(4, 0), (7, 0));
+        assert_eq!(next(7, 0), (11, 0));
+        assert_eq!(next(11, 0), (0, 1));
+        assert_eq!(next(0, 1), (2, 2));
+        assert_eq!(next(2, 2), (4, 2));
+        assert_eq!(next(4, 2), (4, 2));
+    }
 
-            let mut pos = cx;
-            let mut found_word = false;
+    #[test]
+    fn test_prev_word() {
+        let keywords = IsKeyword::default();
+        let lines = lines(&["foo.bar()", "", "  qux"]);
+        let prev = |x, y| Movement::PrevWord.execute(x, y, &context(&lines, &keywords));
+        assert_eq!(prev(2, 2), (0, 1));
+        assert_eq!(prev(0, 1), (7, 0));
+        assert_eq!(prev(7, 0), (4, 0));
+        assert_eq!(prev(4, 0), (3, 0));
+        assert_eq!(prev(3, 0), (0, 0));
+        assert_eq!(prev(0, 0), (0, 0));
+    }
 
-            for ch in rest {
-                if ch.is_alphanumeric() || ch == '_' {
-                    found_word = true;
-                    pos += 1;
-                } else if found_word {
-                    return (pos.saturating_sub(1), cy);
-                } else {
-                    pos += 1;
-                }
-            }
+    #[test]
+    fn test_end_of_word() {
+        let keywords = IsKeyword::default();
+        let lines = lines(&["foo.bar()", "", "  qux"]);
+        let end = |x, y| Movement::EndOfWord.execute(x, y, &context(&lines, &keywords));
+        assert_eq!(end(0, 0), (2, 0));
+        assert_eq!(end(2, 0), (3, 0));
+        assert_eq!(end(3, 0), (6, 0));
+        assert_eq!(end(6, 0), (8, 0));
+        assert_eq!(end(8, 0), (4, 2));
+    }
 
-            if found_word {
-                return (pos.saturating_sub(1), cy);
-            }
-        }
-        (cx, cy)
+    #[test]
+    fn test_iskeyword() {
+        let keywords = IsKeyword::parse("@,48-57,_,$").unwrap();
+        let lines = lines(&["$foo + bar"]);
+        let context = context(&lines, &keywords);
+        assert_eq!(Movement::NextWord.execute(0, 0, &context), (5, 0));
+    }
+
+    #[test]
+    fn test_graphemes() {
+        let keywords = IsKeyword::default();
+        let lines = lines(&["  héllo 界界", "e\u{301}x"]);
+        let context = context(&lines, &keywords);
+        assert_eq!(Movement::EndOfLine.execute(0, 0, &context), (9, 0));
+        assert_eq!(Movement::FirstNonWhitespace.execute(5, 0, &context), (2, 0));
+        assert_eq!(Movement::Right.execute(1, 1, &context), (2, 1));
+        assert_eq!(Movement::Right.execute(2, 1, &context), (2, 1));
+        assert_eq!(Movement::EndOfFile.execute(0, 0, &context), (1, 1));
+    }
+
+    #[test]
+    fn test_page_movements() {
+        let keywords = IsKeyword::default();
+        let lines: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let mut context = context(&lines, &keywords);
+        assert_eq!(Movement::HalfPageDown.execute(0, 0, &context), (0, 5));
+        assert_eq!(Movement::PageDown.execute(0, 0, &context), (0, 8));
+        assert_eq!(Movement::PageDown.execute(0, 95, &context), (0, 99));
+        assert_eq!(Movement::PageUp.execute(0, 4, &context), (0, 0));
+        context.scroll = 3;
+        assert_eq!(Movement::HalfPageUp.execute(0, 10, &context), (0, 7));
     }
 }
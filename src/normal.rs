@@ -0,0 +1,383 @@
+use crate::movement::Movement;
+use crate::picker::Source;
+use crate::registers::Registers;
+
+// Parses the keys typed so far in Normal mode, following Vim's grammar:
+// `["x][count]command` or `["x][count]operator[count]motion`, where an operator
+// typed twice (`dd`, `gUU`, `g~g~`) works on whole lines.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Lowercase,
+    Uppercase,
+    ToggleCase,
+    Indent,
+    Dedent,
+}
+
+impl Operator {
+    fn from_keys(keys: &str) -> Option<(Self, &'static str)> {
+        [
+            (Operator::Delete, "d"),
+            (Operator::Yank, "y"),
+            (Operator::Lowercase, "gu"),
+            (Operator::Uppercase, "gU"),
+            (Operator::ToggleCase, "g~"),
+            (Operator::Indent, ">"),
+            (Operator::Dedent, "<"),
+        ]
+        .into_iter()
+        .find(|(_, operator_keys)| keys.starts_with(operator_keys))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Target {
+    Motion(Movement),
+    Lines,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Move(Movement),
+    Operate(Operator, Target),
+    ReplaceChar(char),
+    ToggleCase,
+    // `J` joins lines with a single space between them, `gJ` as they are.
+    Join(bool),
+    // `&` repeats the last `:s` on the cursor line, `g&` on every line.
+    RepeatSubstitute(bool),
+    SetMark(char),
+    // `'x` jumps to the line of a mark, `` `x `` to its exact position.
+    JumpToMark(char, bool),
+    // `*` and `#` search forward and backward for the word under the
+    // cursor, `g*` and `g#` also for where it is part of another word.
+    SearchWord(bool, bool),
+    // `\f`, `\b`, `\r` and `\l` open the picker for files, buffers,
+    // recent files and lines, with backslash as Vim's default leader key.
+    Pick(Source),
+    // Any other single key command, carried out by the editor.
+    Key(char),
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Command {
+    pub count: Option<usize>,
+    pub register: Option<char>,
+    pub action: Action,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Parsed {
+    Pending,
+    Invalid,
+    Complete(Command),
+}
+
+pub fn motion(keys: &str) -> Option<Movement> {
+    match keys {
+        "h" => Some(Movement::Left),
+        "j" => Some(Movement::Down),
+        "k" => Some(Movement::Up),
+        "l" | " " => Some(Movement::Right),
+        "w" => Some(Movement::NextWord),
+        "b" => Some(Movement::PrevWord),
+        "e" => Some(Movement::EndOfWord),
+        "0" => Some(Movement::StartOfLine),
+        "$" => Some(Movement::EndOfLine),
+        "^" => Some(Movement::FirstNonWhitespace),
+        "G" => Some(Movement::EndOfFile),
+        "gg" => Some(Movement::StartOfFile),
+        _ => None,
+    }
+}
+
+// Splits a leading count off `keys`. A lone "0" is a motion, not a count.
+fn split_count(keys: &str) -> (Option<usize>, &str) {
+    let digits = keys
+        .char_indices()
+        .find(|(index, c)| !c.is_ascii_digit() || (*index == 0 && *c == '0'))
+        .map_or(keys.len(), |(index, _)| index);
+    (keys[..digits].parse().ok(), &keys[digits..])
+}
+
+fn multiply(first: Option<usize>, second: Option<usize>) -> Option<usize> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(first.saturating_mul(second)),
+        (first, None) => first,
+        (None, second) => second,
+    }
+}
+
+// Splits the `["x][count]` prefix off `keys`.
+fn split_prefix(keys: &str) -> Result<(Option<usize>, Option<char>, &str), Parsed> {
+    let (count, rest) = split_count(keys);
+    let (register, rest) = match rest.strip_prefix('"') {
+        Some(after) => {
+            let mut chars = after.chars();
+            match chars.next() {
+                None => return Err(Parsed::Pending),
+                Some(name) if Registers::is_valid(name) => (Some(name), chars.as_str()),
+                Some(_) => return Err(Parsed::Invalid),
+            }
+        }
+        None => (None, rest),
+    };
+    let (second_count, rest) = split_count(rest);
+    if rest.is_empty() {
+        return Err(Parsed::Pending);
+    }
+    Ok((multiply(count, second_count), register, rest))
+}
+
+pub fn parse(keys: &str) -> Parsed {
+    let (count, register, rest) = match split_prefix(keys) {
+        Ok(prefix) => prefix,
+        Err(parsed) => return parsed,
+    };
+    let complete = |action| {
+        Parsed::Complete(Command {
+            count,
+            register,
+            action,
+        })
+    };
+    if let Some((operator, operator_keys)) = Operator::from_keys(rest) {
+        let target = parse_operator(&rest[operator_keys.len()..], operator_keys);
+        return match target {
+            Parsed::Complete(Command {
+                count: motion_count,
+                action,
+                ..
+            }) => Parsed::Complete(Command {
+                count: multiply(count, motion_count),
+                register,
+                action: match action {
+                    Action::Operate(_, target) => Action::Operate(operator, target),
+                    action => action,
+                },
+            }),
+            other => other,
+        };
+    }
+    if let Some(movement) = motion(rest) {
+        return complete(Action::Move(movement));
+    }
+    match rest {
+        "J" => return complete(Action::Join(true)),
+        "gJ" => return complete(Action::Join(false)),
+        "&" => return complete(Action::RepeatSubstitute(false)),
+        "g&" => return complete(Action::RepeatSubstitute(true)),
+        "*" => return complete(Action::SearchWord(true, true)),
+        "#" => return complete(Action::SearchWord(false, true)),
+        "g*" => return complete(Action::SearchWord(true, false)),
+        "g#" => return complete(Action::SearchWord(false, false)),
+        "\\" => return Parsed::Pending,
+        "\\f" => return complete(Action::Pick(Source::Files)),
+        "\\b" => return complete(Action::Pick(Source::Buffers)),
+        "\\r" => return complete(Action::Pick(Source::Recent)),
+        "\\l" => return complete(Action::Pick(Source::Lines)),
+        _ => (),
+    }
+    let mut chars = rest.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('r' | 'g' | 'm' | '\'' | '`'), None, _) => Parsed::Pending,
+        (Some('r'), Some(c), None) => complete(Action::ReplaceChar(c)),
+        (Some('m'), Some(c), None) => complete(Action::SetMark(c)),
+        (Some('\''), Some(c), None) => complete(Action::JumpToMark(c, false)),
+        (Some('`'), Some(c), None) => complete(Action::JumpToMark(c, true)),
+        (Some('~'), None, _) => complete(Action::ToggleCase),
+        (Some(c), None, _) => complete(Action::Key(c)),
+        _ => Parsed::Invalid,
+    }
+}
+
+// Visual mode shares the motions of Normal mode, but its operators act on
+// the selection right away and take no motion.
+pub fn parse_visual(keys: &str) -> Parsed {
+    let (count, register, rest) = match split_prefix(keys) {
+        Ok(prefix) => prefix,
+        Err(parsed) => return parsed,
+    };
+    let complete = |action| {
+        Parsed::Complete(Command {
+            count,
+            register,
+            action,
+        })
+    };
+    if let Some(movement) = motion(rest) {
+        return complete(Action::Move(movement));
+    }
+    match rest {
+        "g" => Parsed::Pending,
+        "J" => complete(Action::Join(true)),
+        "gJ" => complete(Action::Join(false)),
+        "d" | "x" | "D" | "X" | "y" | "Y" | ">" | "<" | "~" | "u" | "U" | "o" | "v" | "V" | ":"
+        | "!" => complete(Action::Key(rest.chars().next().unwrap_or(' '))),
+        _ => Parsed::Invalid,
+    }
+}
+
+// Parses what follows an operator. The operator itself is filled in by
+// the caller; here it is only a placeholder.
+fn parse_operator(keys: &str, operator_keys: &str) -> Parsed {
+    let (count, rest) = split_count(keys);
+    let complete = |target| {
+        Parsed::Complete(Command {
+            count,
+            register: None,
+            action: Action::Operate(Operator::Delete, target),
+        })
+    };
+    if rest.is_empty() || rest == "g" {
+        return Parsed::Pending;
+    }
+    // `gUU` is accepted as well as `gUgU`.
+    if rest == operator_keys || (operator_keys.len() > 1 && operator_keys.ends_with(rest)) {
+        return complete(Target::Lines);
+    }
+    motion(rest).map_or(Parsed::Invalid, |movement| {
+        complete(Target::Motion(movement))
+    })
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn command(count: Option<usize>, action: Action) -> Parsed {
+        Parsed::Complete(Command {
+            count,
+            register: None,
+            action,
+        })
+    }
+
+    #[test]
+    fn test_registers() {
+        assert_eq!(parse("\""), Parsed::Pending);
+        assert_eq!(parse("\"a"), Parsed::Pending);
+        assert_eq!(parse("\"!"), Parsed::Invalid);
+        assert_eq!(
+            parse("2\"a3yy"),
+            Parsed::Complete(Command {
+                count: Some(6),
+                register: Some('a'),
+                action: Action::Operate(Operator::Yank, Target::Lines),
+            })
+        );
+        assert_eq!(
+            parse("\"Bp"),
+            Parsed::Complete(Command {
+                count: None,
+                register: Some('B'),
+                action: Action::Key('p'),
+            })
+        );
+    }
+
+    #[test]
+    fn test_counts_and_motions() {
+        assert_eq!(parse("3"), Parsed::Pending);
+        assert_eq!(
+            parse("0"),
+            command(None, Action::Move(Movement::StartOfLine))
+        );
+        assert_eq!(
+            parse("10j"),
+            command(Some(10), Action::Move(Movement::Down))
+        );
+        assert_eq!(parse("g"), Parsed::Pending);
+        assert_eq!(
+            parse("5gg"),
+            command(Some(5), Action::Move(Movement::StartOfFile))
+        );
+        assert_eq!(parse("i"), command(None, Action::Key('i')));
+        assert_eq!(parse("gx"), Parsed::Invalid);
+    }
+
+    #[test]
+    fn test_replace_and_case() {
+        assert_eq!(parse("r"), Parsed::Pending);
+        assert_eq!(parse("4rx"), command(Some(4), Action::ReplaceChar('x')));
+        assert_eq!(parse("~"), command(None, Action::ToggleCase));
+    }
+
+    #[test]
+    fn test_operators() {
+        let lines = |operator| Action::Operate(operator, Target::Lines);
+        let motion = |operator, movement| Action::Operate(operator, Target::Motion(movement));
+        assert_eq!(parse("d"), Parsed::Pending);
+        assert_eq!(parse("dd"), command(None, lines(Operator::Delete)));
+        assert_eq!(
+            parse("2d3w"),
+            command(Some(6), motion(Operator::Delete, Movement::NextWord))
+        );
+        assert_eq!(
+            parse("y$"),
+            command(None, motion(Operator::Yank, Movement::EndOfLine))
+        );
+        assert_eq!(parse("gU"), Parsed::Pending);
+        assert_eq!(parse("gUU"), command(None, lines(Operator::Uppercase)));
+        assert_eq!(parse("gUg"), Parsed::Pending);
+        assert_eq!(parse("gUgU"), command(None, lines(Operator::Uppercase)));
+        assert_eq!(parse("g~~"), command(None, lines(Operator::ToggleCase)));
+        assert_eq!(parse("guu"), command(None, lines(Operator::Lowercase)));
+        assert_eq!(
+            parse("gugg"),
+            command(None, motion(Operator::Lowercase, Movement::StartOfFile))
+        );
+        assert_eq!(parse("dx"), Parsed::Invalid);
+        assert_eq!(parse("3>>"), command(Some(3), lines(Operator::Indent)));
+        assert_eq!(
+            parse("<j"),
+            command(None, motion(Operator::Dedent, Movement::Down))
+        );
+    }
+
+    #[test]
+    fn test_marks() {
+        assert_eq!(parse("m"), Parsed::Pending);
+        assert_eq!(parse("ma"), command(None, Action::SetMark('a')));
+        assert_eq!(parse("'"), Parsed::Pending);
+        assert_eq!(parse("'a"), command(None, Action::JumpToMark('a', false)));
+        assert_eq!(parse("`<"), command(None, Action::JumpToMark('<', true)));
+    }
+
+    #[test]
+    fn test_search() {
+        assert_eq!(parse("2n"), command(Some(2), Action::Key('n')));
+        assert_eq!(parse("?"), command(None, Action::Key('?')));
+        assert_eq!(parse("*"), command(None, Action::SearchWord(true, true)));
+        assert_eq!(parse("g"), Parsed::Pending);
+        assert_eq!(
+            parse("3g#"),
+            command(Some(3), Action::SearchWord(false, false))
+        );
+        assert_eq!(parse("\\"), Parsed::Pending);
+        assert_eq!(parse("\\f"), command(None, Action::Pick(Source::Files)));
+        assert_eq!(parse("\\x"), Parsed::Invalid);
+    }
+
+    #[test]
+    fn test_visual() {
+        assert_eq!(
+            parse_visual("3j"),
+            command(Some(3), Action::Move(Movement::Down))
+        );
+        assert_eq!(parse_visual("d"), command(None, Action::Key('d')));
+        assert_eq!(parse_visual("g"), Parsed::Pending);
+        assert_eq!(parse_visual("gJ"), command(None, Action::Join(false)));
+        assert_eq!(parse_visual("i"), Parsed::Invalid);
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(parse("J"), command(None, Action::Join(true)));
+        assert_eq!(parse("4gJ"), command(Some(4), Action::Join(false)));
+        assert_eq!(parse("g&"), command(None, Action::RepeatSubstitute(true)));
+    }
+}
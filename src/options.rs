@@ -0,0 +1,302 @@
+use crate::search::{Settings, Syntax};
+
+// Editor settings changed with `:set`. Every option has a full name and an
+// optional short alias, like Vim.
+pub const NAMES: &[(&str, &str)] = &[
+    ("autoindent", "ai"),
+    ("errorformat", "efm"),
+    ("expandtab", "et"),
+    ("filetype", "ft"),
+    ("hlsearch", "hls"),
+    ("ignorecase", "ic"),
+    ("incsearch", "is"),
+    ("iskeyword", "isk"),
+    ("makeprg", "mp"),
+    ("rustregex", "rre"),
+    ("scroll", "scr"),
+    ("shiftwidth", "sw"),
+    ("smartcase", "scs"),
+    ("tabstop", "ts"),
+    ("wrapscan", "ws"),
+];
+
+pub struct Options {
+    // Copy the indentation of the current line when opening a new one.
+    pub autoindent: bool,
+    // How `:make` reads errors, see errorformat.rs.
+    pub errorformat: String,
+    // Indent with spaces instead of tabs.
+    pub expandtab: bool,
+    // The syntax definition of the current buffer. It belongs to the
+    // document, so the editor copies it here and back around `:set`.
+    pub filetype: String,
+    // Keep the matches of the last search highlighted.
+    pub hlsearch: bool,
+    // Searches and `:s` ignore case.
+    pub ignorecase: bool,
+    // Show where a search pattern matches while it is typed.
+    pub incsearch: bool,
+    // The characters words are made of for `w`, `b` and `e`, see
+    // iskeyword.rs. It belongs to the document, so the editor copies it
+    // here and back around `:set`.
+    pub iskeyword: String,
+    // The command `:make` runs.
+    pub makeprg: String,
+    // Take search patterns in the `regex` crate's syntax instead of Vim's.
+    pub rustregex: bool,
+    // Lines moved by Ctrl-d / Ctrl-u; 0 means half the window height.
+    pub scroll: usize,
+    // Columns shifted by >> and <<; 0 means the value of 'tabstop'.
+    pub shiftwidth: usize,
+    // With 'ignorecase', typed patterns with upper case letters match case.
+    pub smartcase: bool,
+    // Number of screen cells a tab advances to.
+    pub tabstop: usize,
+    // Searches continue at the other end of the file.
+    pub wrapscan: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            autoindent: true,
+            errorformat: "rustc,gcc,go,python,tsc".to_string(),
+            expandtab: false,
+            filetype: String::new(),
+            hlsearch: false,
+            ignorecase: false,
+            incsearch: true,
+            iskeyword: String::new(),
+            makeprg: "cargo build".to_string(),
+            rustregex: false,
+            scroll: 0,
+            shiftwidth: 8,
+            smartcase: false,
+            tabstop: 8,
+            wrapscan: true,
+        }
+    }
+}
+
+impl Options {
+    pub fn shiftwidth(&self) -> usize {
+        if self.shiftwidth == 0 {
+            self.tabstop
+        } else {
+            self.shiftwidth
+        }
+    }
+    pub fn search(&self) -> Settings {
+        Settings {
+            syntax: if self.rustregex {
+                Syntax::Rust
+            } else {
+                Syntax::Vim
+            },
+            ignore_case: self.ignorecase,
+            smart_case: self.smartcase,
+        }
+    }
+    // Whitespace filling `width` columns, using tabs unless 'expandtab'
+    // is set.
+    #[allow(clippy::integer_arithmetic, clippy::integer_division)]
+    pub fn indentation(&self, width: usize) -> String {
+        if self.expandtab {
+            " ".repeat(width)
+        } else {
+            let tabstop = self.tabstop.max(1);
+            format!(
+                "{}{}",
+                "\t".repeat(width / tabstop),
+                " ".repeat(width % tabstop)
+            )
+        }
+    }
+    fn full_name(name: &str) -> Option<&'static str> {
+        NAMES
+            .iter()
+            .find(|(full, short)| *full == name || (!short.is_empty() && *short == name))
+            .map(|(full, _)| *full)
+    }
+    fn flag(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "autoindent" => Some(&mut self.autoindent),
+            "expandtab" => Some(&mut self.expandtab),
+            "hlsearch" => Some(&mut self.hlsearch),
+            "ignorecase" => Some(&mut self.ignorecase),
+            "incsearch" => Some(&mut self.incsearch),
+            "rustregex" => Some(&mut self.rustregex),
+            "smartcase" => Some(&mut self.smartcase),
+            "wrapscan" => Some(&mut self.wrapscan),
+            _ => None,
+        }
+    }
+    fn number(&mut self, name: &str) -> Option<&mut usize> {
+        match name {
+            "scroll" => Some(&mut self.scroll),
+            "shiftwidth" => Some(&mut self.shiftwidth),
+            "tabstop" => Some(&mut self.tabstop),
+            _ => None,
+        }
+    }
+    fn string(&mut self, name: &str) -> Option<&mut String> {
+        match name {
+            "errorformat" => Some(&mut self.errorformat),
+            "filetype" => Some(&mut self.filetype),
+            "iskeyword" => Some(&mut self.iskeyword),
+            "makeprg" => Some(&mut self.makeprg),
+            _ => None,
+        }
+    }
+    fn value(&mut self, name: &str) -> String {
+        if let Some(flag) = self.flag(name) {
+            let prefix = if *flag { "" } else { "no" };
+            return format!("{}{}", prefix, name);
+        }
+        if let Some(number) = self.number(name) {
+            return format!("{}={}", name, number);
+        }
+        if let Some(string) = self.string(name) {
+            return format!("{}={}", name, string);
+        }
+        String::new()
+    }
+    fn set_value(&mut self, name: &str, value: &str, argument: &str) -> Result<(), String> {
+        if let Some(string) = self.string(name) {
+            *string = value.to_string();
+            return Ok(());
+        }
+        let parsed: usize = value
+            .parse()
+            .map_err(|_| format!("Number required after =: {}", argument))?;
+        if name == "tabstop" && parsed == 0 {
+            return Err(format!("Argument must be positive: {}", argument));
+        }
+        match self.number(name) {
+            Some(number) => {
+                *number = parsed;
+                Ok(())
+            }
+            None => Err(format!("Invalid argument: {}", argument)),
+        }
+    }
+    // Applies every space separated argument of `:set`; a space after a
+    // backslash is part of the argument. Supported forms are `name=value`,
+    // `name:value`, `name?`, and for on/off options `name`, `noname`,
+    // `invname` and `name!`; the returned message, if any, is what should be
+    // shown to the user.
+    pub fn set(&mut self, arguments: &str) -> Result<Option<String>, String> {
+        let mut shown = Vec::new();
+        for argument in split_arguments(arguments) {
+            let argument = argument.as_str();
+            let unknown = || format!("Unknown option: {}", argument);
+            if let Some(index) = argument.find(|c| c == '=' || c == ':') {
+                let name = Self::full_name(&argument[..index]).ok_or_else(unknown)?;
+                self.set_value(name, &argument[index.saturating_add(1)..], argument)?;
+                continue;
+            }
+            if let Some(name) = argument.strip_suffix('?') {
+                let name = Self::full_name(name).ok_or_else(unknown)?;
+                shown.push(self.value(name));
+                continue;
+            }
+            let (name, change): (&str, fn(bool) -> bool) =
+                if let Some(name) = argument.strip_suffix('!') {
+                    (name, |value| !value)
+                } else if let Some(name) = argument.strip_prefix("inv") {
+                    (name, |value| !value)
+                } else if let Some(name) = argument
+                    .strip_prefix("no")
+                    .filter(|name| Self::full_name(name).is_some())
+                {
+                    (name, |_| false)
+                } else {
+                    (argument, |_| true)
+                };
+            let name = Self::full_name(name).ok_or_else(unknown)?;
+            if let Some(flag) = self.flag(name) {
+                *flag = change(*flag);
+            } else if Self::full_name(argument) == Some(name) {
+                shown.push(self.value(name));
+            } else {
+                return Err(format!("Invalid argument: {}", argument));
+            }
+        }
+        if shown.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(shown.join("  ")))
+        }
+    }
+}
+
+fn split_arguments(arguments: &str) -> Vec<String> {
+    let mut split = Vec::new();
+    let mut argument = String::new();
+    let mut chars = arguments.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars
+                .peek()
+                .map_or(false, |next| next.is_whitespace() || *next == '\\') =>
+            {
+                argument.extend(chars.next());
+            }
+            c if c.is_whitespace() => {
+                if !argument.is_empty() {
+                    split.push(std::mem::take(&mut argument));
+                }
+            }
+            c => argument.push(c),
+        }
+    }
+    if !argument.is_empty() {
+        split.push(argument);
+    }
+    split
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut options = Options::default();
+        assert_eq!(options.set("ts=4 sw=2 et"), Ok(None));
+        assert_eq!(options.tabstop, 4);
+        assert_eq!(options.shiftwidth, 2);
+        assert!(options.expandtab);
+        assert_eq!(options.set("noet"), Ok(None));
+        assert!(!options.expandtab);
+        assert_eq!(options.set("et!"), Ok(None));
+        assert!(options.expandtab);
+        assert_eq!(
+            options.set("invexpandtab ai?"),
+            Ok(Some("autoindent".to_string()))
+        );
+        assert!(!options.expandtab);
+        assert_eq!(options.set("ts"), Ok(Some("tabstop=4".to_string())));
+        assert!(options.set("ts=0").is_err());
+        assert!(options.set("nots").is_err());
+        assert!(options.set("bogus").is_err());
+        assert_eq!(options.set("mp=make\\ -j4 efm=%f:%l:%m"), Ok(None));
+        assert_eq!(options.makeprg, "make -j4");
+        assert_eq!(options.set("mp?"), Ok(Some("makeprg=make -j4".to_string())));
+        assert!(options.set("nomp").is_err());
+        assert_eq!(options.set("isk=@,48-57,_,-"), Ok(None));
+        assert_eq!(
+            options.set("isk?"),
+            Ok(Some("iskeyword=@,48-57,_,-".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_indentation() {
+        let mut options = Options::default();
+        options.tabstop = 4;
+        assert_eq!(options.indentation(6), "\t  ");
+        options.expandtab = true;
+        assert_eq!(options.indentation(6), "      ");
+    }
+}
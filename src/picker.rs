@@ -0,0 +1,302 @@
+use crate::cmdline::Line;
+use crate::files;
+use std::cmp::Reverse;
+
+// The fuzzy picker for files, buffers, recently opened files and the lines
+// of the current buffer. What was typed matches the items whose text has
+// its characters in order; they are ranked by how well those characters
+// line up with the starts of words and with each other, much like fzf.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Source {
+    Files,
+    Buffers,
+    Recent,
+    Lines,
+}
+
+impl Source {
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Files => "Files",
+            Self::Buffers => "Buffers",
+            Self::Recent => "History",
+            Self::Lines => "Lines",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Target {
+    File(String),
+    Buffer(usize),
+    // Counting from 0.
+    Line(usize),
+}
+
+pub struct Item {
+    // Shown before the text without being matched, like a line number.
+    pub prefix: String,
+    pub text: String,
+    pub target: Target,
+}
+
+pub struct Picker {
+    pub source: Source,
+    pub query: Line,
+    items: Vec<Item>,
+    // The items matching the query, best first, with the characters of
+    // their text that matched.
+    matches: Vec<(usize, Vec<usize>)>,
+    // The query `matches` was ranked for.
+    ranked_query: String,
+    pub selected: usize,
+}
+
+const SCORE_MATCH: i32 = 16;
+const GAP_START: i32 = -3;
+const GAP_EXTENSION: i32 = -1;
+const BONUS_CONSECUTIVE: i32 = 4;
+// The first character of the text and those after a space.
+const BONUS_WHITE: i32 = 10;
+// After a path separator.
+const BONUS_DELIMITER: i32 = 9;
+// After other punctuation like `_` or `.`.
+const BONUS_BOUNDARY: i32 = 8;
+// The upper case letter in `camelCase`, or a digit after a letter.
+const BONUS_CAMEL: i32 = 7;
+// The first character of the query counts this many times as much.
+const FIRST_CHAR_MULTIPLIER: i32 = 2;
+
+fn bonus(previous: Option<char>, c: char) -> i32 {
+    if !c.is_alphanumeric() {
+        return 0;
+    }
+    match previous {
+        None => BONUS_WHITE,
+        Some(previous) if previous.is_whitespace() => BONUS_WHITE,
+        Some('/' | '\\') => BONUS_DELIMITER,
+        Some(previous) if !previous.is_alphanumeric() => BONUS_BOUNDARY,
+        Some(previous) if previous.is_lowercase() && c.is_uppercase() => BONUS_CAMEL,
+        Some(previous) if !previous.is_ascii_digit() && c.is_ascii_digit() => BONUS_CAMEL,
+        _ => 0,
+    }
+}
+
+// How well `query` matches `text` and which characters of `text` it
+// matched, or None when they are not all in it in order. Case is ignored
+// unless the query has upper case letters.
+#[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+pub fn score(query: &str, text: &str) -> Option<(i32, Vec<usize>)> {
+    let ignore_case = !query.chars().any(char::is_uppercase);
+    let fold = |c: char| {
+        if ignore_case {
+            c.to_lowercase().next().unwrap_or(c)
+        } else {
+            c
+        }
+    };
+    let query: Vec<char> = query.chars().map(fold).collect();
+    let original: Vec<char> = text.chars().collect();
+    let text: Vec<char> = original.iter().copied().map(fold).collect();
+    let (rows, columns) = (query.len(), text.len());
+    if rows == 0 {
+        return Some((0, Vec::new()));
+    }
+    // Quick check that the query is in the text at all.
+    let mut rest = text.iter();
+    if !query.iter().all(|q| rest.any(|c| c == q)) {
+        return None;
+    }
+    // `matched[i][j]`: the best score with query character `i` at text
+    // character `j`, and where query character `i - 1` was then.
+    // `gap[i][j]`: the best score with query character `i` somewhere
+    // before `j`, with the gap up to `j` paid for.
+    let mut matched = vec![vec![None::<(i32, usize)>; columns]; rows];
+    let mut gap = vec![vec![None::<(i32, usize)>; columns]; rows];
+    for i in 0..rows {
+        for j in 0..columns {
+            if j > 0 {
+                let from_match = matched[i][j - 1].map(|(score, _)| (score + GAP_START, j - 1));
+                let from_gap = gap[i][j - 1].map(|(score, from)| (score + GAP_EXTENSION, from));
+                gap[i][j] = from_match
+                    .into_iter()
+                    .chain(from_gap)
+                    .max_by_key(|(score, _)| *score);
+            }
+            if query[i] != text[j] {
+                continue;
+            }
+            let bonus = bonus(j.checked_sub(1).map(|k| original[k]), original[j]);
+            matched[i][j] = if i == 0 {
+                Some((SCORE_MATCH + bonus * FIRST_CHAR_MULTIPLIER, 0))
+            } else if j == 0 {
+                None
+            } else {
+                let consecutive = matched[i - 1][j - 1]
+                    .map(|(score, _)| (score + SCORE_MATCH + bonus.max(BONUS_CONSECUTIVE), j - 1));
+                let after_gap =
+                    gap[i - 1][j - 1].map(|(score, from)| (score + SCORE_MATCH + bonus, from));
+                consecutive
+                    .into_iter()
+                    .chain(after_gap)
+                    .max_by_key(|(score, _)| *score)
+            };
+        }
+    }
+    let (mut j, best) = matched[rows - 1]
+        .iter()
+        .enumerate()
+        .filter_map(|(j, cell)| cell.map(|(score, _)| (j, score)))
+        .max_by_key(|(j, score)| (*score, Reverse(*j)))?;
+    let mut positions = vec![0; rows];
+    for i in (0..rows).rev() {
+        positions[i] = j;
+        if let Some((_, from)) = matched[i][j] {
+            j = from;
+        }
+    }
+    Some((best, positions))
+}
+
+// The files under `root`, sorted by their whole path.
+pub fn files(root: &str) -> Vec<String> {
+    let mut files: Vec<String> = files::walk(&[root.to_string()])
+        .map(|path| path.display().to_string())
+        .collect();
+    files.sort();
+    files
+}
+
+impl Picker {
+    pub fn new(source: Source, items: Vec<Item>) -> Self {
+        let mut picker = Self {
+            source,
+            query: Line::new(""),
+            items,
+            matches: Vec::new(),
+            ranked_query: String::new(),
+            selected: 0,
+        };
+        picker.rank();
+        picker
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn matches(&self) -> impl Iterator<Item = (&Item, &Vec<usize>)> {
+        self.matches
+            .iter()
+            .filter_map(|(index, positions)| Some((self.items.get(*index)?, positions)))
+    }
+    pub fn match_count(&self) -> usize {
+        self.matches.len()
+    }
+    pub fn selected_item(&self) -> Option<&Item> {
+        let (index, _) = self.matches.get(self.selected)?;
+        self.items.get(*index)
+    }
+    // Ranks the items again after the query changed. When it only grew, the
+    // items that did not match before cannot match now.
+    pub fn rank(&mut self) {
+        let query = self.query.text().to_string();
+        let candidates: Vec<usize> =
+            if !self.ranked_query.is_empty() && query.starts_with(&self.ranked_query) {
+                self.matches.iter().map(|(index, _)| *index).collect()
+            } else {
+                (0..self.items.len()).collect()
+            };
+        let mut ranked: Vec<(i32, usize, usize, Vec<usize>)> = candidates
+            .into_iter()
+            .filter_map(|index| {
+                let item = self.items.get(index)?;
+                let (score, positions) = score(&query, &item.text)?;
+                Some((score, item.text.len(), index, positions))
+            })
+            .collect();
+        // Better scores first, then shorter texts, then the original order,
+        // which is all there is while nothing was typed.
+        if !query.is_empty() {
+            ranked.sort_by_key(|(score, len, index, _)| (Reverse(*score), *len, *index));
+        }
+        self.matches = ranked
+            .into_iter()
+            .map(|(_, _, index, positions)| (index, positions))
+            .collect();
+        self.ranked_query = query;
+        self.selected = 0;
+    }
+    pub fn select(&mut self, forward: bool, count: usize) {
+        let last = self.matches.len().saturating_sub(1);
+        self.selected = if forward {
+            self.selected.saturating_add(count).min(last)
+        } else {
+            self.selected.saturating_sub(count)
+        };
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn ranked(query: &str, texts: &[&str]) -> Vec<String> {
+        let items = texts
+            .iter()
+            .map(|text| Item {
+                prefix: String::new(),
+                text: text.to_string(),
+                target: Target::File(text.to_string()),
+            })
+            .collect();
+        let mut picker = Picker::new(Source::Files, items);
+        for c in query.chars() {
+            picker.query.insert(c);
+            picker.rank();
+        }
+        picker
+            .matches()
+            .map(|(item, _)| item.text.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("", "abc"), Some((0, Vec::new())));
+        assert_eq!(
+            score("ac", "abc").map(|(_, positions)| positions),
+            Some(vec![0, 2])
+        );
+        assert_eq!(score("abd", "abc"), None);
+        assert_eq!(score("A", "abc"), None);
+        assert!(score("a", "ABC").is_some());
+        // The start of a word is preferred over the first place that fits.
+        assert_eq!(
+            score("ed", "src/editor.rs").map(|(_, positions)| positions),
+            Some(vec![4, 5])
+        );
+        assert_eq!(
+            score("fb", "foo_bar").map(|(_, positions)| positions),
+            Some(vec![0, 4])
+        );
+        let consecutive = score("main", "src/main.rs").map(|(score, _)| score);
+        let scattered = score("main", "src/my_animation.rs").map(|(score, _)| score);
+        assert!(consecutive > scattered);
+    }
+
+    #[test]
+    fn test_rank() {
+        assert_eq!(
+            ranked(
+                "mr",
+                &["README.md", "src/mirror.rs", "src/main.rs", "Cargo.toml"]
+            ),
+            ["src/main.rs", "src/mirror.rs"]
+        );
+        assert_eq!(ranked("", &["long", "a"]), ["long", "a"]);
+        assert_eq!(
+            ranked("edr", &["src/editor.rs", "src/document.rs"]),
+            ["src/editor.rs"]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::ex::split_pattern;
+use crate::files;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use unicode_segmentation::UnicodeSegmentation;
+
+// The quickfix list: places in files filled by `:grep` and `:vimgrep`, gone
+// through with `:cnext` and the like and listed by `:copen`.
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub file_name: String,
+    // Counting from 0; the column in graphemes.
+    pub line: usize,
+    pub column: usize,
+    pub text: String,
+    // `E` for errors, `W` for warnings and so on; None for search matches.
+    pub kind: Option<char>,
+}
+
+#[derive(Default)]
+pub struct Quickfix {
+    entries: Vec<Entry>,
+    current: usize,
+    // The files of the entries, resolved once so that `./a.rs` and `a.rs`
+    // are the same.
+    paths: Vec<PathBuf>,
+}
+
+impl Quickfix {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        let paths = entries
+            .iter()
+            .map(|entry| files::canonical(Path::new(&entry.file_name)))
+            .collect();
+        Self {
+            entries,
+            current: 0,
+            paths,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+    pub fn current(&self) -> usize {
+        self.current
+    }
+    // Makes entry `index` the current one.
+    pub fn select(&mut self, index: usize) -> Result<&Entry, String> {
+        if self.entries.is_empty() {
+            return Err("No Errors".to_string());
+        }
+        self.current = index.min(self.entries.len().saturating_sub(1));
+        self.entries
+            .get(self.current)
+            .ok_or_else(|| "No Errors".to_string())
+    }
+    // `:cnext` and `:cprevious`, `count` entries away from the current one.
+    pub fn step(&mut self, count: usize, forward: bool) -> Result<&Entry, String> {
+        if self.entries.is_empty() {
+            return Err("No Errors".to_string());
+        }
+        let index = if forward {
+            self.current.saturating_add(count)
+        } else {
+            self.current.checked_sub(count).unwrap_or(usize::MAX)
+        };
+        if index >= self.entries.len() {
+            return Err("No more items".to_string());
+        }
+        self.select(index)
+    }
+    // The gutter markers for `file_name`: the kind of entry on each line,
+    // errors going over warnings when a line has both.
+    pub fn markers(&self, file_name: &str) -> HashMap<usize, char> {
+        let path = files::canonical(Path::new(file_name));
+        let mut markers = HashMap::new();
+        for (entry, _) in self
+            .entries
+            .iter()
+            .zip(&self.paths)
+            .filter(|(_, entry_path)| **entry_path == path)
+        {
+            if let Some(kind) = entry.kind {
+                let marker = markers.entry(entry.line).or_insert(kind);
+                if kind == 'E' {
+                    *marker = kind;
+                }
+            }
+        }
+        markers
+    }
+    // Keeps relative file names pointing at the same files after the
+    // working directory changed from `directory`.
+    pub fn rebase(&mut self, directory: &Path) {
+        for entry in &mut self.entries {
+            if Path::new(&entry.file_name).is_relative() {
+                entry.file_name = directory.join(&entry.file_name).display().to_string();
+            }
+        }
+    }
+    // How `:copen` shows an entry.
+    pub fn line(entry: &Entry) -> String {
+        let kind = match entry.kind {
+            Some('E') => " error",
+            Some('W') => " warning",
+            Some('N') => " note",
+            Some('I') => " info",
+            _ => "",
+        };
+        format!(
+            "{}|{} col {}{}| {}",
+            entry.file_name,
+            entry.line.saturating_add(1),
+            entry.column.saturating_add(1),
+            kind,
+            entry.text.trim()
+        )
+    }
+}
+
+// The arguments of `:grep` and `:vimgrep`.
+#[derive(PartialEq, Debug)]
+pub struct Grep {
+    pub pattern: String,
+    // Every match of a line instead of only the first.
+    pub all: bool,
+    // Stay where the cursor is instead of going to the first match.
+    pub no_jump: bool,
+    pub paths: Vec<String>,
+}
+
+// `:grep[!] {pattern} [path]...` takes a pattern up to the first space,
+// unless it is quoted, while `:vimgrep[!] /{pattern}/[g][j] [path]...` takes
+// it between any two non-word characters like `:s`. Without a path the
+// working directory is searched.
+pub fn parse_grep(argument: &str, vim: bool) -> Result<Grep, String> {
+    let argument = argument.trim_start();
+    let first = argument
+        .chars()
+        .next()
+        .ok_or_else(|| "Argument required".to_string())?;
+    let delimited = vim && !first.is_alphanumeric() && first != '\\';
+    let (pattern, rest) = if delimited {
+        split_pattern(&argument[first.len_utf8()..], first)
+    } else if !vim && (first == '"' || first == '\'') {
+        split_pattern(&argument[1..], first)
+    } else {
+        let end = argument.find(char::is_whitespace).unwrap_or(argument.len());
+        (argument[..end].to_string(), &argument[end..])
+    };
+    if pattern.is_empty() {
+        return Err("Argument required".to_string());
+    }
+    let mut words = rest.split_whitespace();
+    let mut grep = Grep {
+        pattern,
+        all: false,
+        no_jump: false,
+        paths: Vec::new(),
+    };
+    // The flags directly follow a `/pattern/`.
+    if delimited && !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        let flags = words.next().unwrap_or_default();
+        if let Some(c) = flags.chars().find(|c| !matches!(c, 'g' | 'j')) {
+            return Err(format!("Trailing characters: {}", c));
+        }
+        grep.all = flags.contains('g');
+        grep.no_jump = flags.contains('j');
+    }
+    grep.paths = words.map(str::to_string).collect();
+    Ok(grep)
+}
+
+// Every line of the files under `paths` that `regex` matches. Files that
+// are not text are skipped.
+pub fn grep(regex: &Regex, paths: &[String], all: bool) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for path in files::walk(paths) {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let file_name = path.display().to_string();
+        for (line, text) in contents.lines().enumerate() {
+            for found in regex.find_iter(text) {
+                entries.push(Entry {
+                    file_name: file_name.clone(),
+                    line,
+                    column: text[..found.start()].graphemes(true).count(),
+                    text: text.to_string(),
+                    kind: None,
+                });
+                if !all {
+                    break;
+                }
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::files::Scratch;
+
+    fn entry(line: usize) -> Entry {
+        Entry {
+            file_name: "a.rs".to_string(),
+            line,
+            column: 0,
+            text: String::new(),
+            kind: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_grep() {
+        assert_eq!(
+            parse_grep("foo src README.md", false),
+            Ok(Grep {
+                pattern: "foo".to_string(),
+                all: false,
+                no_jump: false,
+                paths: vec!["src".to_string(), "README.md".to_string()],
+            })
+        );
+        assert_eq!(
+            parse_grep("\"fn main\"", false).map(|grep| grep.pattern),
+            Ok("fn main".to_string())
+        );
+        let grep = parse_grep("/a\\/b c/gj src", true).unwrap();
+        assert_eq!(grep.pattern, "a/b c");
+        assert!(grep.all && grep.no_jump);
+        assert_eq!(grep.paths, ["src"]);
+        let grep = parse_grep("foo", true).unwrap();
+        assert!(!grep.all && !grep.no_jump && grep.paths.is_empty());
+        assert!(parse_grep("/foo/x", true).is_err());
+        assert!(parse_grep("", false).is_err());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut quickfix = Quickfix::new(vec![entry(0), entry(1), entry(2)]);
+        assert_eq!(quickfix.step(2, true).map(|entry| entry.line), Ok(2));
+        assert!(quickfix.step(1, true).is_err());
+        assert_eq!(quickfix.step(1, false).map(|entry| entry.line), Ok(1));
+        assert!(quickfix.step(2, false).is_err());
+        assert_eq!(quickfix.current(), 1);
+        assert!(Quickfix::default().step(1, true).is_err());
+    }
+
+    #[test]
+    fn test_markers() {
+        let entries = [
+            ("./cmd/main.go", 3, Some('W')),
+            ("cmd/main.go", 3, Some('E')),
+            ("cmd/./main.go", 5, Some('W')),
+            ("cmd/main.go", 7, None),
+            ("cmd/other.go", 9, Some('E')),
+        ];
+        let mut quickfix = Quickfix::new(
+            entries
+                .iter()
+                .map(|(file_name, line, kind)| Entry {
+                    file_name: file_name.to_string(),
+                    kind: *kind,
+                    ..entry(*line)
+                })
+                .collect(),
+        );
+        let markers = quickfix.markers("cmd/main.go");
+        assert_eq!(markers, HashMap::from([(3, 'E'), (5, 'W')]));
+        assert_eq!(
+            quickfix.markers("./cmd/other.go"),
+            HashMap::from([(9, 'E')])
+        );
+        quickfix.rebase(Path::new("/project"));
+        assert_eq!(quickfix.entries()[0].file_name, "/project/./cmd/main.go");
+    }
+
+    #[test]
+    fn test_grep() {
+        let root = Scratch::new("grep");
+        root.write(".gitignore", "ignored.txt\n");
+        root.write("ignored.txt", "foo\n");
+        root.write("src/b.rs", "let x = foo(foo);\nbar\n");
+        root.write("a.txt", "äfoo\n");
+        let regex = Regex::new("foo").unwrap();
+        let paths = [root.path().display().to_string()];
+        let found: Vec<(String, usize, usize)> = grep(&regex, &paths, true)
+            .into_iter()
+            .map(|entry| {
+                let file_name = entry.file_name.trim_start_matches(&paths[0]).to_string();
+                (file_name, entry.line, entry.column)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("/a.txt".to_string(), 0, 1),
+                ("/src/b.rs".to_string(), 0, 8),
+                ("/src/b.rs".to_string(), 0, 12)
+            ]
+        );
+        assert_eq!(grep(&regex, &paths, false).len(), 2);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+// Text taken by a delete or yank. Linewise text is pasted as whole lines.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct Register {
+    pub lines: Vec<String>,
+    pub linewise: bool,
+}
+
+impl Register {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+    fn append(&mut self, other: Register) {
+        if self.linewise || other.linewise || self.lines.is_empty() {
+            self.lines.extend(other.lines);
+            self.linewise = self.linewise || other.linewise;
+            return;
+        }
+        let mut lines = other.lines.into_iter();
+        if let (Some(last), Some(first)) = (self.lines.last_mut(), lines.next()) {
+            last.push_str(&first);
+        }
+        self.lines.extend(lines);
+    }
+}
+
+// Vim's registers: `"` holds the last delete or yank, `0` the last yank,
+// `1`-`9` the last deletes of whole lines, `-` the last smaller delete,
+// `a`-`z` are written with `"x` (uppercase appends) and `_` discards.
+#[derive(Default)]
+pub struct Registers {
+    registers: HashMap<char, Register>,
+}
+
+impl Registers {
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_')
+    }
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        let name = name.unwrap_or('"').to_ascii_lowercase();
+        self.registers
+            .get(&name)
+            .filter(|register| !register.is_empty())
+    }
+    // Stores text into the named register, or into the ones Vim picks for
+    // an unnamed yank or delete.
+    pub fn store(&mut self, name: Option<char>, register: Register, yank: bool) {
+        match name {
+            Some('_') => return,
+            Some(name) if name.is_ascii_uppercase() => {
+                let target = self.registers.entry(name.to_ascii_lowercase()).or_default();
+                target.append(register);
+                let appended = target.clone();
+                self.registers.insert('"', appended);
+                return;
+            }
+            Some(name) if name != '"' => {
+                self.registers.insert(name, register.clone());
+            }
+            _ if yank => {
+                self.registers.insert('0', register.clone());
+            }
+            _ if register.linewise || register.lines.len() > 1 => {
+                for number in (1..9).rev() {
+                    let from = char::from(b'0'.saturating_add(number));
+                    let to = char::from(b'1'.saturating_add(number));
+                    if let Some(previous) = self.registers.remove(&from) {
+                        self.registers.insert(to, previous);
+                    }
+                }
+                self.registers.insert('1', register.clone());
+            }
+            _ => {
+                self.registers.insert('-', register.clone());
+            }
+        }
+        self.registers.insert('"', register);
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn register(lines: &[&str], linewise: bool) -> Register {
+        Register {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            linewise,
+        }
+    }
+
+    #[test]
+    fn test_unnamed() {
+        let mut registers = Registers::default();
+        registers.store(None, register(&["foo"], true), true);
+        registers.store(None, register(&["bar"], true), false);
+        registers.store(None, register(&["baz"], true), false);
+        registers.store(None, register(&["x"], false), false);
+        assert_eq!(registers.get(None), Some(&register(&["x"], false)));
+        assert_eq!(registers.get(Some('0')), Some(&register(&["foo"], true)));
+        assert_eq!(registers.get(Some('1')), Some(&register(&["baz"], true)));
+        assert_eq!(registers.get(Some('2')), Some(&register(&["bar"], true)));
+        assert_eq!(registers.get(Some('-')), Some(&register(&["x"], false)));
+    }
+
+    #[test]
+    fn test_named() {
+        let mut registers = Registers::default();
+        registers.store(Some('a'), register(&["foo"], false), true);
+        registers.store(Some('A'), register(&["bar"], false), true);
+        assert_eq!(
+            registers.get(Some('a')),
+            Some(&register(&["foobar"], false))
+        );
+        registers.store(Some('A'), register(&["baz"], true), true);
+        assert_eq!(
+            registers.get(Some('a')),
+            Some(&register(&["foobar", "baz"], true))
+        );
+        registers.store(Some('_'), register(&["gone"], false), false);
+        assert_eq!(
+            registers.get(None),
+            Some(&register(&["foobar", "baz"], true))
+        );
+        assert_eq!(registers.get(Some('0')), None);
+    }
+}
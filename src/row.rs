@@ -1,10 +1,13 @@
 use crate::highlighting;
 use crate::HighlightingOptions;
 use crate::SearchDirection;
+use regex::Regex;
 use std::cmp;
 use std::fmt;
 use termion::color;
+use termion::style;
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 impl fmt::Display for Row {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -12,11 +15,26 @@ impl fmt::Display for Row {
     }
 }
 
+// A block comment still open at the end of a row: which of the filetype's
+// block comments it is, and how deeply nested.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OpenComment {
+    block: usize,
+    depth: usize,
+}
+
 #[derive(Default, Clone)]
 pub struct Row {
     string: String,
     highlighting: Vec<highlighting::Type>,
     pub is_highlighted: bool,
+    // Whether `highlighting` shows search matches, which have to be redone
+    // even when the text has not changed.
+    has_matches: bool,
+    // What `highlighting` left open for the next row.
+    open_comment: Option<OpenComment>,
+    // Set on the lines `:g` still has to visit.
+    pub is_marked: bool,
     len: usize,
 }
 
@@ -26,41 +44,80 @@ impl From<&str> for Row {
             string: String::from(slice),
             highlighting: Vec::new(),
             is_highlighted: false,
+            has_matches: false,
+            open_comment: None,
+            is_marked: false,
             len: slice.graphemes(true).count(),
         }
     }
 }
 
 impl Row {
-    pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
+    // Renders the screen columns `start..end`. Tabs are expanded to the
+    // next tab stop, control characters are shown as `^X`, and wide
+    // characters cut by either edge are replaced with spaces. The graphemes
+    // in `selected` are shown in reverse video; a selection reaching past
+    // the end of the row covers one more cell for the line break.
+    pub fn render(
+        &self,
+        start: usize,
+        end: usize,
+        tabstop: usize,
+        selected: Option<(usize, usize)>,
+    ) -> String {
         let mut result = String::new();
         let mut current_highlighting = &highlighting::Type::None;
-        #[allow(clippy::integer_arithmetic)]
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end - start)
-        {
-            if let Some(c) = grapheme.chars().next() {
-                let highlighting_type = self
-                    .highlighting
-                    .get(index)
-                    .unwrap_or(&highlighting::Type::None);
-                if highlighting_type != current_highlighting {
-                    current_highlighting = highlighting_type;
-                    let start_highlight =
-                        format!("{}", termion::color::Fg(highlighting_type.to_color()));
-                    result.push_str(&start_highlight[..]);
-                }
-                if c == '\t' {
-                    result.push_str(" ");
+        let mut current_selected = false;
+        let mut column: usize = 0;
+        let is_selected =
+            |index: usize| selected.map_or(false, |(from, to)| from <= index && index < to);
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if column >= end {
+                break;
+            }
+            let next_column = column.saturating_add(grapheme_width(grapheme, column, tabstop));
+            if next_column <= start {
+                column = next_column;
+                continue;
+            }
+            let highlighting_type = self
+                .highlighting
+                .get(index)
+                .unwrap_or(&highlighting::Type::None);
+            if highlighting_type != current_highlighting {
+                current_highlighting = highlighting_type;
+                let start_highlight =
+                    format!("{}", termion::color::Fg(highlighting_type.to_color()));
+                result.push_str(&start_highlight[..]);
+            }
+            if is_selected(index) != current_selected {
+                current_selected = !current_selected;
+                if current_selected {
+                    result.push_str(&format!("{}", style::Invert));
                 } else {
-                    result.push(c);
+                    result.push_str(&format!("{}", style::NoInvert));
                 }
             }
+            if grapheme == "\t" || column < start || next_column > end {
+                let visible = cmp::min(next_column, end).saturating_sub(cmp::max(column, start));
+                result.push_str(&" ".repeat(visible));
+            } else if let Some(c) = control_char(grapheme) {
+                result.push('^');
+                result.push(c);
+            } else {
+                result.push_str(grapheme);
+            }
+            column = next_column;
+        }
+        if is_selected(self.len) && column >= start && column < end {
+            if !current_selected {
+                current_selected = true;
+                result.push_str(&format!("{}", style::Invert));
+            }
+            result.push(' ');
+        }
+        if current_selected {
+            result.push_str(&format!("{}", style::NoInvert));
         }
         let end_highlight = format!("{}", termion::color::Fg(color::Reset));
         result.push_str(&end_highlight[..]);
@@ -69,6 +126,36 @@ impl Row {
     pub fn len(&self) -> usize {
         self.len
     }
+    // Number of screen cells taken by the graphemes before `at`.
+    pub fn display_column(&self, at: usize, tabstop: usize) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take(at)
+            .fold(0, |column, grapheme| {
+                column.saturating_add(grapheme_width(grapheme, column, tabstop))
+            })
+    }
+    // Number of screen cells the grapheme at `at` takes, at least one.
+    pub fn display_width_at(&self, at: usize, tabstop: usize) -> usize {
+        let column = self.display_column(at, tabstop);
+        self.string[..]
+            .graphemes(true)
+            .nth(at)
+            .map_or(1, |grapheme| grapheme_width(grapheme, column, tabstop))
+            .max(1)
+    }
+    // Index of the grapheme drawn at screen cell `column`, or the row length
+    // when the row is shorter than that.
+    pub fn index_at_column(&self, column: usize, tabstop: usize) -> usize {
+        let mut current: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            current = current.saturating_add(grapheme_width(grapheme, current, tabstop));
+            if current > column {
+                return index;
+            }
+        }
+        self.len
+    }
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
@@ -132,66 +219,108 @@ impl Row {
             string: splitted_row,
             len: splitted_length,
             is_highlighted: false,
+            has_matches: false,
+            open_comment: None,
+            is_marked: false,
             highlighting: Vec::new(),
         }
     }
+    // The spaces and tabs the row starts with.
+    pub fn indentation(&self) -> &str {
+        let end = self
+            .string
+            .find(|c| c != ' ' && c != '\t')
+            .unwrap_or(self.string.len());
+        &self.string[..end]
+    }
+    // The graphemes `start..end` as a string.
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        self.string[..]
+            .graphemes(true)
+            .skip(start)
+            .take(end.saturating_sub(start))
+            .collect()
+    }
     pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
-    pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
-        if at > self.len || query.is_empty() {
+    // Byte offset of grapheme `index`, or the length of the row past its
+    // end.
+    fn byte_index(&self, index: usize) -> usize {
+        self.string[..]
+            .grapheme_indices(true)
+            .nth(index)
+            .map_or(self.string.len(), |(byte, _)| byte)
+    }
+    // Index of the grapheme byte `byte` belongs to.
+    fn grapheme_index(&self, byte: usize) -> usize {
+        if byte >= self.string.len() {
+            return self.len;
+        }
+        self.string[..]
+            .grapheme_indices(true)
+            .take_while(|(start, _)| *start <= byte)
+            .count()
+            .saturating_sub(1)
+    }
+    // The first match starting at or after grapheme `at`, or searching
+    // backward the last one starting before it. The whole row is searched
+    // so that anchors and word boundaries see the text around `at`.
+    pub fn find(&self, regex: &Regex, at: usize, direction: SearchDirection) -> Option<usize> {
+        if at > self.len {
             return None;
         }
-        let start = if direction == SearchDirection::Forward {
-            at
-        } else {
-            0
-        };
-        let end = if direction == SearchDirection::Forward {
-            self.len
-        } else {
-            at
-        };
-        #[allow(clippy::integer_arithmetic)]
-        let substring: String = self.string[..]
-            .graphemes(true)
-            .skip(start)
-            .take(end - start)
-            .collect();
-        let matching_byte_index = if direction == SearchDirection::Forward {
-            substring.find(query)
-        } else {
-            substring.rfind(query)
-        };
-        if let Some(matching_byte_index) = matching_byte_index {
-            for (grapheme_index, (byte_index, _)) in
-                substring[..].grapheme_indices(true).enumerate()
-            {
-                if matching_byte_index == byte_index {
-                    #[allow(clippy::integer_arithmetic)]
-                    return Some(start + grapheme_index);
+        let at = self.byte_index(at);
+        let start = match direction {
+            SearchDirection::Forward => regex.find_at(&self.string, at)?.start(),
+            SearchDirection::Backward => {
+                let mut last = None;
+                let mut from = 0;
+                // Every position a match can start at, not only the ones
+                // after the end of the previous match.
+                while let Some(found) = regex.find_at(&self.string, from) {
+                    if found.start() >= at {
+                        break;
+                    }
+                    last = Some(found.start());
+                    from = found.start().saturating_add(
+                        self.string[found.start()..]
+                            .chars()
+                            .next()
+                            .map_or(1, char::len_utf8),
+                    );
                 }
+                last?
             }
-        }
-        None
+        };
+        Some(self.grapheme_index(start))
     }
 
-    fn highlight_match(&mut self, word: &Option<String>) {
-        if let Some(word) = word {
-            if word.is_empty() {
-                return;
-            }
-            let mut index = 0;
-            while let Some(search_match) = self.find(word, index, SearchDirection::Forward) {
-                if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count())
-                {
-                    #[allow(clippy::indexing_slicing)]
-                    for i in search_match..next_index {
-                        self.highlighting[i] = highlighting::Type::Match;
-                    }
-                    index = next_index;
-                } else {
-                    break;
+    // The graphemes `start..end` of every match, left to right and not
+    // overlapping.
+    pub fn matches(&self, regex: &Regex) -> Vec<(usize, usize)> {
+        regex
+            .find_iter(&self.string)
+            .map(|found| {
+                let end = self.string[..]
+                    .grapheme_indices(true)
+                    .take_while(|(start, _)| *start < found.end())
+                    .count();
+                (self.grapheme_index(found.start()), end)
+            })
+            .collect()
+    }
+
+    fn highlight_match(&mut self, pattern: Option<&Regex>) {
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => return,
+        };
+        for (start, end) in self.matches(pattern) {
+            self.has_matches = true;
+            for index in start..end {
+                if let Some(highlighting) = self.highlighting.get_mut(index) {
+                    *highlighting = highlighting::Type::Match;
                 }
             }
         }
@@ -216,7 +345,7 @@ impl Row {
                 return false;
             }
         }
-        for _ in 0..substring.len() {
+        for _ in substring.chars() {
             self.highlighting.push(hl_type);
             *index += 1;
         }
@@ -311,48 +440,74 @@ impl Row {
         &mut self,
         index: &mut usize,
         opts: &HighlightingOptions,
-        c: char,
         chars: &[char],
     ) -> bool {
-        if opts.comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '/' {
-                    for _ in *index..chars.len() {
-                        self.highlighting.push(highlighting::Type::Comment);
-                        *index += 1;
-                    }
-                    return true;
-                }
-            };
+        if opts
+            .line_comments()
+            .iter()
+            .any(|start| is_at(chars, *index, start))
+        {
+            for _ in *index..chars.len() {
+                self.highlighting.push(highlighting::Type::Comment);
+                *index += 1;
+            }
+            return true;
         }
         false
     }
-    #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
-    fn highlight_multiline_comment(
+    // The block comment starting at `index`, if one does.
+    fn start_block_comment(
         &mut self,
         index: &mut usize,
         opts: &HighlightingOptions,
-        c: char,
         chars: &[char],
-    ) -> bool {
-        if opts.comments() && c == '/' && *index < chars.len() {
-            if let Some(next_char) = chars.get(index.saturating_add(1)) {
-                if *next_char == '*' {
-                    let closing_index =
-                        if let Some(closing_index) = self.string[*index + 2..].find("*/") {
-                            *index + closing_index + 4
-                        } else {
-                            chars.len()
-                        };
-                    for _ in *index..closing_index {
-                        self.highlighting.push(highlighting::Type::MultilineComment);
-                        *index += 1;
-                    }
-                    return true;
+    ) -> Option<OpenComment> {
+        let block = opts
+            .block_comments()
+            .iter()
+            .position(|(start, _)| is_at(chars, *index, start))?;
+        let (start, _) = opts.block_comments().get(block)?;
+        self.push_comment(index, start.chars().count());
+        Some(OpenComment { block, depth: 1 })
+    }
+    // Goes on with the block comment `open` from `index` up to where it
+    // ends, or to the end of the row when it does not.
+    fn highlight_block_comment(
+        &mut self,
+        index: &mut usize,
+        opts: &HighlightingOptions,
+        mut open: OpenComment,
+        chars: &[char],
+    ) -> Option<OpenComment> {
+        let (start, end) = match opts.block_comments().get(open.block) {
+            Some(delimiters) => delimiters,
+            None => {
+                self.push_comment(index, chars.len().saturating_sub(*index));
+                return None;
+            }
+        };
+        // The end first, for comments like `"""` that start and end alike.
+        while *index < chars.len() {
+            if is_at(chars, *index, end) {
+                self.push_comment(index, end.chars().count());
+                open.depth = open.depth.saturating_sub(1);
+                if open.depth == 0 {
+                    return None;
                 }
-            };
+            } else if opts.nested_comments() && is_at(chars, *index, start) {
+                self.push_comment(index, start.chars().count());
+                open.depth = open.depth.saturating_add(1);
+            } else {
+                self.push_comment(index, 1);
+            }
         }
-        false
+        Some(open)
+    }
+    fn push_comment(&mut self, index: &mut usize, len: usize) {
+        for _ in 0..len {
+            self.highlighting.push(highlighting::Type::MultilineComment);
+        }
+        *index = index.saturating_add(len);
     }
 
     fn highlight_string(
@@ -362,12 +517,12 @@ impl Row {
         c: char,
         chars: &[char],
     ) -> bool {
-        if opts.strings() && c == '"' {
+        if opts.string_delimiters().contains(&c) {
             loop {
                 self.highlighting.push(highlighting::Type::String);
                 *index += 1;
                 if let Some(next_char) = chars.get(*index) {
-                    if *next_char == '"' {
+                    if *next_char == c {
                         break;
                     }
                 } else {
@@ -387,7 +542,11 @@ impl Row {
         c: char,
         chars: &[char],
     ) -> bool {
-        if opts.numbers() && c.is_ascii_digit() {
+        let numbers = match opts.numbers() {
+            Some(numbers) => numbers,
+            None => return false,
+        };
+        if c.is_ascii_digit() {
             if *index > 0 {
                 #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
                 let prev_char = chars[*index - 1];
@@ -399,7 +558,7 @@ impl Row {
                 self.highlighting.push(highlighting::Type::Number);
                 *index += 1;
                 if let Some(next_char) = chars.get(*index) {
-                    if *next_char != '.' && !next_char.is_ascii_digit() {
+                    if !numbers.continues(*next_char) {
                         break;
                     }
                 } else {
@@ -410,47 +569,36 @@ impl Row {
         }
         false
     }
-    #[allow(clippy::indexing_slicing, clippy::integer_arithmetic)]
+    #[allow(clippy::integer_arithmetic)]
     pub fn highlight(
         &mut self,
         opts: &HighlightingOptions,
-        word: &Option<String>,
-        start_with_comment: bool,
-    ) -> bool {
-        let chars: Vec<char> = self.string.chars().collect();
-        if self.is_highlighted && word.is_none() {
-            if let Some(hl_type) = self.highlighting.last() {
-                if *hl_type == highlighting::Type::MultilineComment
-                    && self.string.len() > 1
-                    && self.string[self.string.len() - 2..] == *"*/"
-                {
-                    return true;
-                }
-            }
-            return false;
+        pattern: Option<&Regex>,
+        open_comment: Option<OpenComment>,
+    ) -> Option<OpenComment> {
+        if self.is_highlighted && pattern.is_none() && !self.has_matches {
+            return self.open_comment;
         }
+        // One entry per grapheme, so that highlighting indices line up with
+        // cursor positions.
+        let chars: Vec<char> = self.string[..]
+            .graphemes(true)
+            .map(|grapheme| grapheme.chars().next().unwrap_or(' '))
+            .collect();
         self.highlighting = Vec::new();
+        self.has_matches = false;
         let mut index = 0;
-        let mut in_ml_comment = start_with_comment;
-        if in_ml_comment {
-            let closing_index = if let Some(closing_index) = self.string.find("*/") {
-                closing_index + 2
-            } else {
-                chars.len()
-            };
-            for _ in 0..closing_index {
-                self.highlighting.push(highlighting::Type::MultilineComment);
-            }
-            index = closing_index;
-        }
+        let mut open_comment = open_comment;
         while let Some(c) = chars.get(index) {
-            if self.highlight_multiline_comment(&mut index, &opts, *c, &chars) {
-                in_ml_comment = true;
+            if let Some(open) = open_comment
+                .take()
+                .or_else(|| self.start_block_comment(&mut index, opts, &chars))
+            {
+                open_comment = self.highlight_block_comment(&mut index, opts, open, &chars);
                 continue;
             }
-            in_ml_comment = false;
             if self.highlight_char(&mut index, opts, *c, &chars)
-                || self.highlight_comment(&mut index, opts, *c, &chars)
+                || self.highlight_comment(&mut index, opts, &chars)
                 || self.highlight_primary_keywords(&mut index, &opts, &chars)
                 || self.highlight_secondary_keywords(&mut index, &opts, &chars)
                 || self.highlight_string(&mut index, opts, *c, &chars)
@@ -461,13 +609,45 @@ impl Row {
             self.highlighting.push(highlighting::Type::None);
             index += 1;
         }
-        self.highlight_match(word);
-        if in_ml_comment && &self.string[self.string.len().saturating_sub(2)..] != "*/" {
-            return true;
-        }
+        self.highlight_match(pattern);
+        self.open_comment = open_comment;
         self.is_highlighted = true;
-        false
+        open_comment
+    }
+}
+
+fn grapheme_width(grapheme: &str, column: usize, tabstop: usize) -> usize {
+    if grapheme == "\t" {
+        let tabstop = cmp::max(tabstop, 1);
+        #[allow(clippy::integer_arithmetic)]
+        return tabstop - column % tabstop;
     }
+    if control_char(grapheme).is_some() {
+        return 2;
+    }
+    grapheme.width()
+}
+
+// The letter used to display a control character as `^X`.
+fn control_char(grapheme: &str) -> Option<char> {
+    let mut chars = grapheme.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_control() =>
+        {
+            #[allow(clippy::cast_possible_truncation)]
+            Some(((c as u8) ^ 0x40) as char)
+        }
+        _ => None,
+    }
+}
+
+// Whether `pattern` starts at `chars[index]`.
+fn is_at(chars: &[char], index: usize, pattern: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    !pattern.is_empty()
+        && chars
+            .get(index..)
+            .map_or(false, |rest| rest.starts_with(&pattern))
 }
 
 fn is_separator(c: char) -> bool {
@@ -492,7 +672,7 @@ mod test_super {
             highlighting::Type::None,
             highlighting::Type::None,
         ];
-        row.highlight_match(&Some("t".to_string()));
+        row.highlight_match(Some(&Regex::new("t").unwrap()));
         assert_eq!(
             vec![
                 highlighting::Type::Number,
@@ -509,11 +689,131 @@ mod test_super {
         )
     }
 
+    #[test]
+    fn test_highlight_clears_matches() {
+        let opts = HighlightingOptions::default();
+        let mut row = Row::from("a test");
+        row.highlight(&opts, Some(&Regex::new("t").unwrap()), None);
+        assert!(row.highlighting.contains(&highlighting::Type::Match));
+        row.highlight(&opts, None, None);
+        assert!(!row.highlighting.contains(&highlighting::Type::Match));
+    }
+
+    // The graphemes of each line highlighted as comments, as `#`.
+    fn comments(file_type: &str, lines: &[&str]) -> Vec<String> {
+        let file_type = crate::FileType::named(file_type).unwrap();
+        let mut open_comment = None;
+        lines
+            .iter()
+            .map(|line| {
+                let mut row = Row::from(*line);
+                open_comment = row.highlight(file_type.highlighting_options(), None, open_comment);
+                line.chars()
+                    .zip(&row.highlighting)
+                    .map(|(c, hl_type)| match hl_type {
+                        highlighting::Type::Comment | highlighting::Type::MultilineComment => '#',
+                        _ => c,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_highlight_comments() {
+        assert_eq!(comments("python", &["x = 1 # one"]), ["x = 1 #####"]);
+        assert_eq!(
+            comments(
+                "python",
+                &["def f():", "    \"\"\"Doc", "    string.\"\"\"", "x"]
+            ),
+            ["def f():", "    ######", "##############", "x"]
+        );
+        assert_eq!(
+            comments("rust", &["a /* b /* c */", "d */ e", "f"]),
+            ["a ############", "#### e", "f"]
+        );
+        assert_eq!(
+            comments("c", &["a /* b /* c */ d */"]),
+            ["a ############ d */"]
+        );
+        assert_eq!(
+            comments("haskell", &["x -- y", "{- a {- b -} -} z"]),
+            ["x ####", "############### z"]
+        );
+        assert_eq!(
+            comments("html", &["<p><!-- a --></p>"]),
+            ["<p>##########</p>"]
+        );
+        assert_eq!(comments("lisp", &["(f) ; g"]), ["(f) ###"]);
+        assert_eq!(
+            comments("lua", &["--[[ a", "]] b -- c"]),
+            ["######", "## b ####"]
+        );
+    }
+
     #[test]
     fn test_find() {
         let row = Row::from("1testtest");
-        assert_eq!(row.find("t", 0, SearchDirection::Forward), Some(1));
-        assert_eq!(row.find("t", 2, SearchDirection::Forward), Some(4));
-        assert_eq!(row.find("t", 5, SearchDirection::Forward), Some(5));
+        let t = Regex::new("t").unwrap();
+        assert_eq!(row.find(&t, 0, SearchDirection::Forward), Some(1));
+        assert_eq!(row.find(&t, 2, SearchDirection::Forward), Some(4));
+        assert_eq!(row.find(&t, 5, SearchDirection::Forward), Some(5));
+        assert_eq!(row.find(&t, 5, SearchDirection::Backward), Some(4));
+        let row = Row::from("é fn éfn");
+        let word = Regex::new("\\<fn\\>").unwrap();
+        assert_eq!(row.find(&word, 0, SearchDirection::Forward), Some(2));
+        assert_eq!(row.find(&word, 3, SearchDirection::Forward), None);
+        let start = Regex::new("^").unwrap();
+        assert_eq!(row.find(&start, 2, SearchDirection::Backward), Some(0));
+        let tt = Regex::new("tt").unwrap();
+        assert_eq!(
+            Row::from("ttt").find(&tt, 2, SearchDirection::Backward),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let row = Row::from("\ta界b");
+        let reset = format!("{}", termion::color::Fg(color::Reset));
+        let plain = |rendered: String| {
+            rendered.replace(&reset, "").replace(
+                &format!(
+                    "{}",
+                    termion::color::Fg(highlighting::Type::None.to_color())
+                ),
+                "",
+            )
+        };
+        assert_eq!(plain(row.render(0, 20, 4, None)), "    a界b");
+        assert_eq!(plain(row.render(2, 6, 4, None)), "  a ");
+        assert_eq!(plain(row.render(6, 8, 4, None)), " b");
+        assert_eq!(plain(Row::from("a\u{1}").render(0, 8, 4, None)), "a^A");
+        let invert = format!("{}", style::Invert);
+        let no_invert = format!("{}", style::NoInvert);
+        assert_eq!(
+            plain(row.render(0, 20, 4, Some((1, 2)))),
+            format!("    {}a{}界b", invert, no_invert)
+        );
+        assert_eq!(
+            plain(Row::from("").render(0, 20, 4, Some((0, 1)))),
+            format!("{} {}", invert, no_invert)
+        );
+    }
+
+    #[test]
+    fn test_display_column() {
+        let row = Row::from("\ta界b");
+        assert_eq!(row.display_column(0, 8), 0);
+        assert_eq!(row.display_column(1, 8), 8);
+        assert_eq!(row.display_column(2, 8), 9);
+        assert_eq!(row.display_column(3, 8), 11);
+        assert_eq!(row.display_column(1, 4), 4);
+        assert_eq!(row.index_at_column(3, 8), 0);
+        assert_eq!(row.index_at_column(8, 8), 1);
+        assert_eq!(row.index_at_column(10, 8), 2);
+        assert_eq!(row.index_at_column(11, 8), 3);
+        assert_eq!(row.index_at_column(40, 8), 4);
     }
 }
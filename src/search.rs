@@ -0,0 +1,351 @@
+use regex::{Regex, RegexBuilder};
+
+// Search patterns are written like Vim's (with 'magic' set) unless
+// 'rustregex' is set, in which case they go to the `regex` crate as they
+// are. Both understand `\<` and `\>`, and `\c` and `\C` anywhere in them.
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Syntax {
+    #[default]
+    Vim,
+    Rust,
+}
+
+// The options patterns are compiled with.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Settings {
+    pub syntax: Syntax,
+    pub ignore_case: bool,
+    // Only ignore case while the pattern has no upper case letters.
+    pub smart_case: bool,
+}
+
+pub fn compile(pattern: &str, settings: Settings) -> Result<Regex, String> {
+    let (pattern, forced) = case_flags(pattern);
+    let ignore_case = forced.unwrap_or_else(|| {
+        settings.ignore_case && !(settings.smart_case && has_upper_case(&pattern))
+    });
+    let translated = match settings.syntax {
+        Syntax::Vim => translate(&pattern)?,
+        Syntax::Rust => pattern,
+    };
+    RegexBuilder::new(&translated)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|error| format!("Invalid pattern: {}", first_line(&error.to_string())))
+}
+
+// Takes `\c` (ignore case) and `\C` (match case) out of `pattern`. Like in
+// Vim, `\c` wins when there are both.
+fn case_flags(pattern: &str) -> (String, Option<bool>) {
+    let mut result = String::new();
+    let mut ignore_case = None;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('c') => ignore_case = Some(true),
+            Some('C') => ignore_case = ignore_case.or(Some(false)),
+            Some(next) => {
+                result.push(c);
+                result.push(next);
+            }
+            None => result.push(c),
+        }
+    }
+    (result, ignore_case)
+}
+
+// Upper case letters after a backslash, like `\S`, do not count.
+fn has_upper_case(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+// `text` as a pattern matching just itself.
+pub fn escape(text: &str, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Vim => text
+            .chars()
+            .flat_map(|c| match c {
+                '\\' | '.' | '*' | '[' | '^' | '$' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect(),
+        Syntax::Rust => regex::escape(text),
+    }
+}
+
+// The `regex` crate puts the pattern and a caret under the error first.
+fn first_line(error: &str) -> &str {
+    error
+        .lines()
+        .rev()
+        .find(|line| line.starts_with("error:"))
+        .map_or(error, |line| line.trim_start_matches("error:").trim())
+}
+
+// Rewrites a Vim pattern in the `regex` crate's syntax. `\(`, `\)`, `\|`,
+// `\+`, `\?`, `\=` and `\{n,m}` are the operators there, while the bare
+// characters match themselves; `*` and `^` at the start of a branch and
+// `$` elsewhere than its end are literal, and so is a `[` without a `]`.
+pub fn translate(pattern: &str) -> Result<String, String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut result = String::new();
+    let mut index = 0;
+    // Whether a branch starts here, where `*` has nothing to repeat.
+    let mut branch_start = true;
+    while let Some(&c) = chars.get(index) {
+        index = index.saturating_add(1);
+        let was_branch_start = branch_start;
+        branch_start = false;
+        match c {
+            '\\' => {
+                let next = chars.get(index).copied();
+                index = index.saturating_add(1);
+                match next {
+                    Some('(') => {
+                        result.push('(');
+                        branch_start = true;
+                    }
+                    Some('|') => {
+                        result.push('|');
+                        branch_start = true;
+                    }
+                    Some(')') => result.push(')'),
+                    Some('+') => result.push('+'),
+                    Some('?' | '=') => result.push('?'),
+                    Some('{') => index = translate_braces(&chars, index, &mut result)?,
+                    Some('<') => result.push_str("\\<"),
+                    Some('>') => result.push_str("\\>"),
+                    Some(class) => match class_escape(class) {
+                        Some(translated) => result.push_str(translated),
+                        None if class.is_ascii_alphanumeric() => {
+                            return Err(format!("Unsupported in patterns: \\{}", class));
+                        }
+                        None => result.push_str(&regex::escape(class.encode_utf8(&mut [0; 4]))),
+                    },
+                    None => result.push_str("\\\\"),
+                }
+            }
+            '^' if was_branch_start => {
+                result.push('^');
+                branch_start = true;
+            }
+            '$' if at_branch_end(&chars, index) => result.push('$'),
+            '*' if !was_branch_start => result.push('*'),
+            '.' => result.push('.'),
+            '[' => match translate_brackets(&chars, index) {
+                Some((class, end)) => {
+                    result.push_str(&class);
+                    index = end;
+                }
+                None => result.push_str("\\["),
+            },
+            c => result.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    Ok(result)
+}
+
+fn at_branch_end(chars: &[char], index: usize) -> bool {
+    match (chars.get(index), chars.get(index.saturating_add(1))) {
+        (None, _) => true,
+        (Some('\\'), Some(')' | '|')) => true,
+        _ => false,
+    }
+}
+
+// Backslash classes Vim has and the `regex` crate spells differently or
+// shares.
+fn class_escape(c: char) -> Option<&'static str> {
+    Some(match c {
+        's' => "\\s",
+        'S' => "\\S",
+        'd' => "\\d",
+        'D' => "\\D",
+        'w' => "\\w",
+        'W' => "\\W",
+        'a' => "[A-Za-z]",
+        'A' => "[^A-Za-z]",
+        'l' => "[a-z]",
+        'L' => "[^a-z]",
+        'u' => "[A-Z]",
+        'U' => "[^A-Z]",
+        'x' => "[0-9A-Fa-f]",
+        'X' => "[^0-9A-Fa-f]",
+        'o' => "[0-7]",
+        'O' => "[^0-7]",
+        'h' => "[A-Za-z_]",
+        'H' => "[^A-Za-z_]",
+        'e' => "\\x1b",
+        't' => "\\t",
+        'r' => "\\r",
+        'n' => "\\n",
+        _ => return None,
+    })
+}
+
+// `\{n,m}`, `\{n}`, `\{n,}`, `\{,m}` and `\{}`, with a `-` after the `{`
+// for the non-greedy versions. Returns the index after the closing `}`.
+fn translate_braces(chars: &[char], start: usize, result: &mut String) -> Result<usize, String> {
+    let mut index = start;
+    let lazy = chars.get(index) == Some(&'-');
+    if lazy {
+        index = index.saturating_add(1);
+    }
+    let mut bounds = String::new();
+    loop {
+        match chars.get(index) {
+            Some('}') => break,
+            Some('\\') if chars.get(index.saturating_add(1)) == Some(&'}') => {
+                index = index.saturating_add(1);
+                break;
+            }
+            Some(c) if c.is_ascii_digit() || *c == ',' => bounds.push(*c),
+            _ => return Err("Invalid \\{ in pattern".to_string()),
+        }
+        index = index.saturating_add(1);
+    }
+    match bounds.as_str() {
+        "" | "0," | "," => result.push('*'),
+        bounds if bounds.starts_with(',') => result.push_str(&format!("{{0{}}}", bounds)),
+        bounds => result.push_str(&format!("{{{}}}", bounds)),
+    }
+    if lazy {
+        result.push('?');
+    }
+    Ok(index.saturating_add(1))
+}
+
+// A `[...]` collection starting after the `[` at `start`, and the index
+// after its `]`. A `]` right after the `[` or `[^` belongs to the set.
+fn translate_brackets(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut index = start;
+    if chars.get(index) == Some(&'^') {
+        class.push('^');
+        index = index.saturating_add(1);
+    }
+    if chars.get(index) == Some(&']') {
+        class.push_str("\\]");
+        index = index.saturating_add(1);
+    }
+    loop {
+        let c = *chars.get(index)?;
+        index = index.saturating_add(1);
+        match c {
+            ']' => {
+                class.push(']');
+                return Some((class, index));
+            }
+            '\\' => {
+                let next = *chars.get(index)?;
+                index = index.saturating_add(1);
+                match next {
+                    'e' => class.push_str("\\x1b"),
+                    't' | 'n' | 'r' | '\\' | ']' | '^' | '-' => {
+                        class.push('\\');
+                        class.push(next);
+                    }
+                    next => class.push_str(&regex::escape(next.encode_utf8(&mut [0; 4]))),
+                }
+            }
+            // `[:alpha:]` and the like are the same in both.
+            '[' if chars.get(index) == Some(&':') => {
+                let end = chars
+                    .get(index..)?
+                    .windows(2)
+                    .position(|window| window == [':', ']'])?;
+                let end = index.saturating_add(end).saturating_add(2);
+                class.extend(chars.get(index.saturating_sub(1)..end)?);
+                index = end;
+            }
+            '[' | '&' | '~' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> Option<String> {
+        compile(pattern, Settings::default())
+            .unwrap()
+            .find(text)
+            .map(|found| found.as_str().to_string())
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate("\\(a\\|b\\)\\+").unwrap(), "(a|b)+");
+        assert_eq!(translate("f(x) + y?").unwrap(), "f\\(x\\) \\+ y\\?");
+        assert_eq!(translate("*a^b$c$").unwrap(), "\\*a\\^b\\$c$");
+        assert_eq!(translate("a\\{2,}b\\{-,3}").unwrap(), "a{2,}b{0,3}?");
+        assert_eq!(
+            translate("[]a-z[:digit:]&]x[").unwrap(),
+            "[\\]a-z[:digit:]\\&]x\\["
+        );
+        assert!(translate("\\1").is_err());
+        assert!(translate("a\\{x}").is_err());
+        assert_eq!(
+            translate(&escape("a.*[^$]\\", Syntax::Vim)).unwrap(),
+            regex::escape("a.*[^$]\\")
+        );
+    }
+
+    #[test]
+    fn test_compile() {
+        let rust = Settings {
+            syntax: Syntax::Rust,
+            ..Settings::default()
+        };
+        assert_eq!(
+            matches("fn \\w\\+", "pub fn main()"),
+            Some("fn main".to_string())
+        );
+        assert_eq!(matches("^use ", "reuse use"), None);
+        assert_eq!(matches("\\<use\\>", "reuse use"), Some("use".to_string()));
+        assert_eq!(
+            matches("^.*$", "whole line"),
+            Some("whole line".to_string())
+        );
+        assert_eq!(matches("\\u\\l*", "abc Def"), Some("Def".to_string()));
+        assert!(compile("(", rust).is_err());
+        assert!(compile("\\bfn\\b", rust).unwrap().is_match("a fn b"));
+    }
+
+    #[test]
+    fn test_case() {
+        let settings = |ignore_case, smart_case| Settings {
+            syntax: Syntax::Vim,
+            ignore_case,
+            smart_case,
+        };
+        let is_match = |pattern, settings| compile(pattern, settings).unwrap().is_match("Foo");
+        assert!(!is_match("foo", settings(false, false)));
+        assert!(is_match("foo", settings(true, false)));
+        assert!(is_match("foo", settings(true, true)));
+        assert!(!is_match("FOO", settings(true, true)));
+        assert!(is_match("\\Sfoo\\|foo", settings(true, true)));
+        assert!(is_match("foo\\c", settings(false, false)));
+        assert!(!is_match("\\Cfoo", settings(true, false)));
+        assert!(is_match("\\Cf\\coo", settings(false, false)));
+        assert!(is_match("\\\\c\\|Foo", settings(false, false)));
+    }
+}
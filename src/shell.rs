@@ -0,0 +1,155 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+// External commands for `:!`, `:r !` and `:w !`, run through the user's
+// shell.
+
+fn command(line: &str) -> Command {
+    let shell = std::env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "sh".to_string());
+    let mut command = Command::new(shell);
+    command.arg("-c").arg(line);
+    command
+}
+
+fn check(status: ExitStatus, stderr: &[u8]) -> Result<(), String> {
+    if status.success() {
+        return Ok(());
+    }
+    let code = status
+        .code()
+        .map_or_else(|| "a signal".to_string(), |code| code.to_string());
+    let stderr = String::from_utf8_lossy(stderr);
+    match stderr.lines().find(|line| !line.trim().is_empty()) {
+        Some(line) => Err(format!("shell returned {}: {}", code, line.trim())),
+        None => Err(format!("shell returned {}", code)),
+    }
+}
+
+// Runs `line` with `input` on its standard input and returns what it wrote
+// to its standard output.
+pub fn filter(line: &str, input: &str) -> Result<String, String> {
+    let mut child = command(line)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("Cannot run {}: {}", line, error))?;
+    // The input is written from another thread so that a command writing a
+    // lot of output before it has read all of its input cannot block.
+    let stdin = child.stdin.take();
+    let input = input.to_string();
+    let writer = thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            // A command that does not read its input closes the pipe early,
+            // which is not an error.
+            stdin.write_all(input.as_bytes()).ok();
+        }
+    });
+    let output = child
+        .wait_with_output()
+        .map_err(|error| format!("Cannot run {}: {}", line, error))?;
+    writer.join().ok();
+    check(output.status, &output.stderr)?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Runs `line` on the terminal, with `input` on its standard input if
+// given.
+pub fn run(line: &str, input: Option<&str>) -> Result<(), String> {
+    let mut command = command(line);
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|error| format!("Cannot run {}: {}", line, error))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes()).ok();
+    }
+    let status = child
+        .wait()
+        .map_err(|error| format!("Cannot run {}: {}", line, error))?;
+    check(status, &[])
+}
+
+// Runs `line` and returns what it wrote to its standard output and error,
+// in the order it wrote them, whether it failed or not.
+pub fn capture(line: &str) -> Result<String, String> {
+    let cannot_run = |error: io::Error| format!("Cannot run {}: {}", line, error);
+    let (mut reader, writer) = io::pipe().map_err(cannot_run)?;
+    let mut child = {
+        // The command holds on to the writing end, which has to be closed
+        // before the output can be read to its end.
+        let mut command = command(line);
+        command
+            .stdin(Stdio::null())
+            .stdout(writer.try_clone().map_err(cannot_run)?)
+            .stderr(writer);
+        command.spawn().map_err(cannot_run)?
+    };
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).map_err(cannot_run)?;
+    child.wait().map_err(cannot_run)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+// Replaces `%` in a command line with the file name; `\%` is a plain `%`.
+pub fn expand_file_name(line: &str, file_name: Option<&str>) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('%') => result.push('%'),
+                Some(next) => {
+                    result.push(c);
+                    result.push(next);
+                }
+                None => result.push(c),
+            },
+            '%' => result.push_str(
+                file_name.ok_or_else(|| "No file name to substitute for '%'".to_string())?,
+            ),
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(
+            filter("tr a-z A-Z", "abc\ndef\n"),
+            Ok("ABC\nDEF\n".to_string())
+        );
+        assert_eq!(
+            filter("echo oops >&2; exit 3", ""),
+            Err("shell returned 3: oops".to_string())
+        );
+    }
+
+    #[test]
+    fn test_capture() {
+        assert_eq!(
+            capture("echo out; echo err >&2; exit 1"),
+            Ok("out\nerr\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_expand_file_name() {
+        assert_eq!(
+            expand_file_name("wc -l % \\%", Some("a.rs")),
+            Ok("wc -l a.rs %".to_string())
+        );
+        assert!(expand_file_name("cat %", None).is_err());
+    }
+}
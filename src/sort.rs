@@ -0,0 +1,173 @@
+use crate::ex::split_pattern;
+use crate::search::{self, Settings};
+use regex::Regex;
+use std::cmp::Ordering;
+
+// `:sort[!] [n][i][u][r] [/pattern/]`. `n` sorts on the first number in a
+// line, `i` ignores case, `u` keeps only the first of equal lines and `!`
+// reverses the order. With a pattern the lines are sorted on what follows
+// its match, or with `r` on the match itself; lines it does not match keep
+// their order and go first.
+pub struct Sort {
+    numeric: bool,
+    ignore_case: bool,
+    unique: bool,
+    use_match: bool,
+    reverse: bool,
+    pattern: Option<Regex>,
+}
+
+struct Line {
+    key: Option<String>,
+    number: Option<i64>,
+    text: String,
+}
+
+impl Sort {
+    pub fn parse(
+        argument: &str,
+        reverse: bool,
+        last_search: Option<&str>,
+        settings: Settings,
+    ) -> Result<Self, String> {
+        let mut sort = Self {
+            numeric: false,
+            ignore_case: false,
+            unique: false,
+            use_match: false,
+            reverse,
+            pattern: None,
+        };
+        let mut rest = argument.trim();
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                'n' => sort.numeric = true,
+                'i' => sort.ignore_case = true,
+                'u' => sort.unique = true,
+                'r' => sort.use_match = true,
+                c if c.is_whitespace() => (),
+                c if !c.is_alphanumeric() && !matches!(c, '\\' | '"' | '|') => {
+                    let (pattern, after) = split_pattern(rest, c);
+                    let pattern = match (pattern.as_str(), last_search) {
+                        ("", Some(last_search)) => last_search.to_string(),
+                        ("", None) => return Err("No previous regular expression".to_string()),
+                        _ => pattern,
+                    };
+                    sort.pattern = Some(search::compile(&pattern, settings)?);
+                    rest = after;
+                }
+                c => return Err(format!("Invalid argument: {}", c)),
+            }
+        }
+        Ok(sort)
+    }
+    fn line(&self, text: String) -> Line {
+        let key = match &self.pattern {
+            Some(pattern) => pattern.find(&text).map(|found| {
+                if self.use_match {
+                    found.as_str().to_string()
+                } else {
+                    text[found.end()..].to_string()
+                }
+            }),
+            None => Some(text.clone()),
+        };
+        let key = key.map(|key| {
+            if self.ignore_case {
+                key.to_lowercase()
+            } else {
+                key
+            }
+        });
+        let number = if self.numeric {
+            key.as_deref().and_then(first_number)
+        } else {
+            None
+        };
+        Line { key, number, text }
+    }
+    fn compare(&self, a: &Line, b: &Line) -> Ordering {
+        if self.numeric {
+            a.number.cmp(&b.number)
+        } else {
+            a.key.cmp(&b.key)
+        }
+    }
+    pub fn apply(&self, lines: Vec<String>) -> Vec<String> {
+        let (unmatched, mut lines): (Vec<Line>, Vec<Line>) = lines
+            .into_iter()
+            .map(|text| self.line(text))
+            .partition(|line| line.key.is_none());
+        if self.reverse {
+            lines.sort_by(|a, b| self.compare(b, a));
+        } else {
+            lines.sort_by(|a, b| self.compare(a, b));
+        }
+        if self.unique {
+            lines.dedup_by(|b, a| self.compare(a, b) == Ordering::Equal);
+        }
+        let mut sorted: Vec<String> = unmatched.into_iter().map(|line| line.text).collect();
+        sorted.extend(lines.into_iter().map(|line| line.text));
+        sorted
+    }
+}
+
+// The first decimal number in `text`, with its minus sign.
+fn first_number(text: &str) -> Option<i64> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let digits = text[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(text.len(), |len| start.saturating_add(len));
+    let number: i64 = text[start..digits].parse().unwrap_or(i64::MAX);
+    if text[..start].ends_with('-') {
+        Some(number.saturating_neg())
+    } else {
+        Some(number)
+    }
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+    use crate::search::Syntax;
+
+    fn rust() -> Settings {
+        Settings {
+            syntax: Syntax::Rust,
+            ..Settings::default()
+        }
+    }
+
+    fn sort(argument: &str, reverse: bool, lines: &[&str]) -> Vec<String> {
+        let lines = lines.iter().map(|line| line.to_string()).collect();
+        Sort::parse(argument, reverse, None, rust())
+            .unwrap()
+            .apply(lines)
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(sort("", false, &["b", "a", "C"]), ["C", "a", "b"]);
+        assert_eq!(sort("i", false, &["b", "a", "C"]), ["a", "b", "C"]);
+        assert_eq!(sort("", true, &["b", "a", "C"]), ["b", "a", "C"]);
+        assert_eq!(
+            sort("n", false, &["x10", "x9", "none", "x-3"]),
+            ["none", "x-3", "x9", "x10"]
+        );
+        assert_eq!(sort("u", false, &["b", "a", "b"]), ["a", "b"]);
+        assert_eq!(sort("iu", false, &["B", "a", "b"]), ["a", "B"]);
+        assert_eq!(
+            sort("/\\d /", false, &["1 b", "z", "2 a"]),
+            ["z", "2 a", "1 b"]
+        );
+        assert_eq!(sort("r /\\d+/", false, &["b 10", "a 9"]), ["b 10", "a 9"]);
+        assert_eq!(
+            Sort::parse("/\\d\\+ /", false, None, Settings::default())
+                .unwrap()
+                .apply(vec!["12 b".to_string(), "3 a".to_string()]),
+            ["3 a", "12 b"]
+        );
+        assert!(Sort::parse("x", false, None, rust()).is_err());
+    }
+}
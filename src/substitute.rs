@@ -0,0 +1,220 @@
+use crate::ex::split_pattern;
+use crate::search::{self, Settings};
+use regex::{Captures, Regex};
+
+// `:s/pattern/replacement/[&][gciI] [count]`. Leaving out the pattern and
+// replacement (`:s`, `:&&`) repeats the previous substitution; `&` as the
+// first flag keeps its flags.
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Flags {
+    pub global: bool,
+    pub confirm: bool,
+    // `i` or `I`; None leaves it to the default.
+    pub ignore_case: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: Flags,
+    pub count: Option<usize>,
+}
+
+pub fn parse(argument: &str, previous: Option<&Substitute>) -> Result<Substitute, String> {
+    let delimiter = argument.chars().next().filter(|c| {
+        !c.is_alphanumeric() && !c.is_whitespace() && !matches!(c, '\\' | '"' | '|' | '&')
+    });
+    let (pattern, replacement, rest) = match delimiter {
+        Some(delimiter) => {
+            let (pattern, rest) = split_pattern(&argument[delimiter.len_utf8()..], delimiter);
+            let (replacement, rest) = split_pattern(rest, delimiter);
+            // `~` stands for the previous replacement.
+            let previous_replacement = previous.map_or("", |previous| &previous.replacement);
+            (
+                pattern,
+                expand_tilde(&replacement, previous_replacement),
+                rest,
+            )
+        }
+        None => {
+            let previous =
+                previous.ok_or_else(|| "No previous substitute regular expression".to_string())?;
+            (
+                previous.pattern.clone(),
+                previous.replacement.clone(),
+                argument,
+            )
+        }
+    };
+    let (mut flags, rest) = match rest.strip_prefix('&') {
+        Some(rest) => (
+            previous.map_or_else(Flags::default, |previous| previous.flags),
+            rest,
+        ),
+        None => (Flags::default(), rest),
+    };
+    let mut chars = rest.char_indices();
+    let rest = loop {
+        match chars.next() {
+            Some((_, 'g')) => flags.global = !flags.global,
+            Some((_, 'c')) => flags.confirm = true,
+            Some((_, 'i')) => flags.ignore_case = Some(true),
+            Some((_, 'I')) => flags.ignore_case = Some(false),
+            Some((index, _)) => break rest[index..].trim(),
+            None => break "",
+        }
+    };
+    let count = match rest {
+        "" => None,
+        rest => match rest.parse::<usize>() {
+            Ok(count) if count > 0 => Some(count),
+            Ok(_) => return Err("Positive count required".to_string()),
+            Err(_) => return Err(format!("Trailing characters: {}", rest)),
+        },
+    };
+    Ok(Substitute {
+        pattern,
+        replacement,
+        flags,
+        count,
+    })
+}
+
+fn expand_tilde(replacement: &str, previous: &str) -> String {
+    let mut result = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                result.push(c);
+                if let Some(next) = chars.next() {
+                    result.push(next);
+                }
+            }
+            '~' => result.push_str(previous),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+impl Substitute {
+    // The `i` and `I` flags take the place of 'ignorecase' and 'smartcase'.
+    pub fn regex(&self, pattern: &str, settings: Settings) -> Result<Regex, String> {
+        let settings = match self.flags.ignore_case {
+            Some(ignore_case) => Settings {
+                ignore_case,
+                smart_case: false,
+                ..settings
+            },
+            None => settings,
+        };
+        search::compile(pattern, settings)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+// Appends `text`, changing the case of the first character to `next` and
+// of the others to `until_end`.
+fn push_cased(result: &mut String, text: &str, next: &mut Option<Case>, until_end: Option<Case>) {
+    for c in text.chars() {
+        match next.take().or(until_end) {
+            Some(Case::Upper) => result.extend(c.to_uppercase()),
+            Some(Case::Lower) => result.extend(c.to_lowercase()),
+            None => result.push(c),
+        }
+    }
+}
+
+// The replacement text for one match. `&` and `\0` are the whole match,
+// `\1`-`\9` its groups, `\u`/`\l` change the case of the next character,
+// `\U`/`\L` of everything up to `\E`, `\r` breaks the line and `\n` is a
+// NUL, as in Vim.
+pub fn expand(replacement: &str, captures: &Captures) -> String {
+    let mut result = String::new();
+    let mut next = None;
+    let mut until_end = None;
+    let group = |index: usize| captures.get(index).map_or("", |group| group.as_str());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push_cased(&mut result, group(0), &mut next, until_end),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => {
+                    let index = digit.to_digit(10).map_or(0, |index| index as usize);
+                    push_cased(&mut result, group(index), &mut next, until_end);
+                }
+                Some('u') => next = Some(Case::Upper),
+                Some('l') => next = Some(Case::Lower),
+                Some('U') => until_end = Some(Case::Upper),
+                Some('L') => until_end = Some(Case::Lower),
+                Some('E' | 'e') => until_end = None,
+                Some('r') => result.push('\n'),
+                Some('n') => result.push('\0'),
+                Some('t') => result.push('\t'),
+                Some(other) => {
+                    push_cased(
+                        &mut result,
+                        other.encode_utf8(&mut [0; 4]),
+                        &mut next,
+                        until_end,
+                    );
+                }
+                None => result.push('\\'),
+            },
+            c => push_cased(
+                &mut result,
+                c.encode_utf8(&mut [0; 4]),
+                &mut next,
+                until_end,
+            ),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let substitute = parse("/a\\/b/c/gi 3", None).unwrap();
+        assert_eq!(substitute.pattern, "a/b");
+        assert_eq!(substitute.replacement, "c");
+        assert!(substitute.flags.global);
+        assert_eq!(substitute.flags.ignore_case, Some(true));
+        assert_eq!(substitute.count, Some(3));
+        let repeated = parse("&c", Some(&substitute)).unwrap();
+        assert_eq!(repeated.pattern, "a/b");
+        assert!(repeated.flags.global && repeated.flags.confirm);
+        assert_eq!(repeated.count, None);
+        let repeated = parse("", Some(&substitute)).unwrap();
+        assert!(!repeated.flags.global);
+        assert_eq!(
+            parse("#x#~y#", Some(&substitute)).unwrap().replacement,
+            "cy"
+        );
+        assert!(parse("", None).is_err());
+        assert!(parse("/a/b/x", None).is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let regex = Regex::new("(\\w+) (\\w+)").unwrap();
+        let captures = regex.captures("hello world").unwrap();
+        assert_eq!(expand("\\2 \\1", &captures), "world hello");
+        assert_eq!(expand("[&] \\&", &captures), "[hello world] &");
+        assert_eq!(expand("\\u\\1 \\U\\2\\E!", &captures), "Hello WORLD!");
+        assert_eq!(expand("\\L\\uABC", &captures), "Abc");
+        assert_eq!(expand("a\\rb\\\\", &captures), "a\nb\\");
+        assert_eq!(expand("a\\nb", &captures), "a\0b");
+    }
+}
@@ -11,7 +11,7 @@ pub struct Size {
 }
 pub struct Terminal {
     size: Size,
-    _stdout: RawTerminal<std::io::Stdout>,
+    stdout: RawTerminal<std::io::Stdout>,
 }
 
 impl Terminal {
@@ -22,12 +22,19 @@ impl Terminal {
                 width: size.0,
                 height: size.1.saturating_sub(2),
             },
-            _stdout: stdout().into_raw_mode()?,
+            stdout: stdout().into_raw_mode()?,
         })
     }
     pub fn size(&self) -> &Size {
         &self.size
     }
+    // Raw mode is left while a shell command uses the terminal.
+    pub fn suspend_raw_mode(&self) -> Result<(), std::io::Error> {
+        self.stdout.suspend_raw_mode()
+    }
+    pub fn activate_raw_mode(&self) -> Result<(), std::io::Error> {
+        self.stdout.activate_raw_mode()
+    }
     pub fn clear_screen() {
         print!("{}", termion::clear::All);
     }